@@ -0,0 +1,103 @@
+// Starts the server, parks a growing number of idle client connections on it and checks that
+// the thread count stays flat and active clients still get answered quickly.
+//
+//     cargo bench --bench connections
+//
+// Each idle connection needs a file descriptor on both ends, so raise `ulimit -n` to try the
+// larger steps.
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PORT: u32 = 18400;
+const STEPS: [usize; 4] = [100, 1_000, 5_000, 10_000];
+const ROUND_TRIPS: usize = 2_000;
+
+fn start_server() -> Child {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_mykvstore"))
+        .args([PORT.to_string(), "1000".to_string(), "lru".to_string()])
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start server");
+    for _ in 0..50 {
+        if TcpStream::connect(("localhost", PORT as u16)).is_ok() {
+            return server;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    stop_server(&mut server);
+    panic!("server did not start listening");
+}
+
+fn stop_server(server: &mut Child) {
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+// Reads a field such as `Threads` or `VmRSS` out of /proc, where it exists.
+fn proc_status(pid: u32, field: &str) -> String {
+    fs::read_to_string(format!("/proc/{}/status", pid))
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find(|line| line.starts_with(field))
+                .map(|line| line[field.len() + 1..].trim().to_string())
+        })
+        .unwrap_or_else(|| "n/a".to_string())
+}
+
+fn round_trip(conn: &mut TcpStream, command: &str) {
+    let command = format!("{}\n", command);
+    conn.write_all(&[command.len() as u8]).unwrap();
+    conn.write_all(command.as_bytes()).unwrap();
+    let mut len = [0; 1];
+    conn.read_exact(&mut len).unwrap();
+    let mut reply = vec![0; len[0] as usize];
+    conn.read_exact(&mut reply).unwrap();
+}
+
+fn main() {
+    let mut server = start_server();
+    let mut idle = Vec::new();
+    let mut active = TcpStream::connect(("localhost", PORT as u16)).unwrap();
+    active.set_nodelay(true).unwrap();
+    println!("{:>12} {:>10} {:>12} {:>14} {:>14}", "connections", "threads", "server rss", "open (ms)", "avg rtt (us)");
+    for step in STEPS.iter() {
+        let started = Instant::now();
+        while idle.len() < *step {
+            match TcpStream::connect(("localhost", PORT as u16)) {
+                Ok(conn) => idle.push(conn),
+                Err(e) => {
+                    println!("stopping at {} connections: {}", idle.len(), e);
+                    stop_server(&mut server);
+                    return;
+                }
+            }
+        }
+        let opened = started.elapsed();
+
+        let started = Instant::now();
+        for i in 0..ROUND_TRIPS {
+            if i % 2 == 0 {
+                round_trip(&mut active, &format!("PUT key{} value", i % 100));
+            } else {
+                round_trip(&mut active, &format!("GET key{}", i % 100));
+            }
+        }
+        let rtt = started.elapsed() / ROUND_TRIPS as u32;
+
+        println!(
+            "{:>12} {:>10} {:>12} {:>14} {:>14}",
+            idle.len(),
+            proc_status(server.id(), "Threads"),
+            proc_status(server.id(), "VmRSS"),
+            opened.as_millis(),
+            rtt.as_micros()
+        );
+    }
+    stop_server(&mut server);
+}
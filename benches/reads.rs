@@ -0,0 +1,72 @@
+// Measures GET throughput with several threads reading one cache at once. LRU moves an entry to
+// the front of its list on every hit, so readers have to take the lock to themselves, while
+// CLOCK and CLOCK-Pro only set a reference bit and can share a read lock.
+//
+//     cargo bench --bench reads
+use std::hint::black_box;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Instant;
+use mykvstore::stores::Cacheable;
+use mykvstore::stores::clock::ClockCache;
+use mykvstore::stores::clockpro::ClockProCache;
+use mykvstore::stores::lru::LRUCache;
+
+const ENTRIES: u32 = 100_000;
+const READS_PER_THREAD: u32 = 1_000_000;
+const THREADS: [u32; 4] = [1, 2, 4, 8];
+
+fn key(i: u32) -> Vec<u8> {
+    format!("key:{:08}", i).into_bytes()
+}
+
+// Spreads lookups over the whole key space, starting each thread somewhere else.
+fn scattered(thread: u32, i: u32) -> u32 {
+    ((thread as u64 * 104_729 + i as u64 * 7_919) % ENTRIES as u64) as u32
+}
+
+fn filled<C: Cacheable<u64>>(mut cache: C) -> C {
+    for i in 0..ENTRIES {
+        cache.put(key(i), i as u64).unwrap();
+    }
+    cache
+}
+
+// Millions of gets per second served by `threads` threads each calling `read` on the cache.
+fn throughput<C: Send + Sync + 'static>(cache: &Arc<RwLock<C>>, threads: u32, read: fn(&RwLock<C>, &[u8]) -> Option<u64>) -> f64 {
+    let keys: Arc<Vec<Vec<u8>>> = Arc::new((0..ENTRIES).map(key).collect());
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads).map(|t| {
+        let cache = cache.clone();
+        let keys = keys.clone();
+        thread::spawn(move || {
+            for i in 0..READS_PER_THREAD {
+                black_box(read(&cache, &keys[scattered(t, i) as usize]));
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    (threads * READS_PER_THREAD) as f64 / start.elapsed().as_secs_f64() / 1_000_000.0
+}
+
+fn bench<C: Send + Sync + 'static>(name: &str, cache: C, read: fn(&RwLock<C>, &[u8]) -> Option<u64>) {
+    let cache = Arc::new(RwLock::new(cache));
+    print!("{:>9}", name);
+    for threads in THREADS {
+        print!(" {:>12.2}", throughput(&cache, threads, read));
+    }
+    println!();
+}
+
+fn main() {
+    print!("{:>9}", "policy");
+    for threads in THREADS {
+        print!(" {:>12}", format!("{} thread(s)", threads));
+    }
+    println!("\n(millions of gets per second)");
+    bench("lru", filled(LRUCache::new(ENTRIES)), |cache, k| cache.write().unwrap().get(k.to_vec()));
+    bench("clock", filled(ClockCache::new(ENTRIES)), |cache, k| cache.read().unwrap().get_shared(k).flatten());
+    bench("clockpro", filled(ClockProCache::new(ENTRIES)), |cache, k| cache.read().unwrap().get_shared(k).flatten());
+}
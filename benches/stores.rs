@@ -0,0 +1,97 @@
+// Measures what each eviction policy costs on its own, without the server around it: heap
+// bytes held per cached entry and the time taken by put and get.
+//
+//     cargo bench --bench stores
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use mykvstore::stores::Cacheable;
+use mykvstore::stores::lfu::LFUCache;
+use mykvstore::stores::lru::LRUCache;
+
+const ENTRIES: u32 = 100_000;
+
+// Keeps a running total of live heap bytes so a cache's footprint can be read off directly.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn key(i: u32) -> Vec<u8> {
+    format!("key:{:08}", i).into_bytes()
+}
+
+// Spreads lookups over the whole key space rather than walking it in insertion order.
+fn scattered(i: u32) -> u32 {
+    (i as u64 * 7_919 % ENTRIES as u64) as u32
+}
+
+fn ns_per_op(start: Instant, ops: u32) -> u128 {
+    start.elapsed().as_nanos() / ops as u128
+}
+
+fn bench(name: &str, new_cache: &dyn Fn(u32) -> Box<dyn Cacheable<u64>>) {
+    let keys: Vec<Vec<u8>> = (0..ENTRIES).map(key).collect();
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let mut cache = new_cache(ENTRIES);
+    let start = Instant::now();
+    for (i, k) in keys.iter().enumerate() {
+        cache.put(k.clone(), i as u64).unwrap();
+    }
+    let put = ns_per_op(start, ENTRIES);
+    // The cloned keys now belong to the cache, so they count towards its footprint.
+    let per_entry = (ALLOCATED.load(Ordering::Relaxed) - before) / ENTRIES as usize;
+
+    let start = Instant::now();
+    for i in 0..ENTRIES {
+        black_box(cache.get(keys[scattered(i) as usize].clone()));
+    }
+    let get = ns_per_op(start, ENTRIES);
+    drop(cache);
+
+    // Half the capacity, so every other put evicts something.
+    let mut cache = new_cache(ENTRIES / 2);
+    let start = Instant::now();
+    for (i, k) in keys.iter().enumerate() {
+        cache.put(k.clone(), i as u64).unwrap();
+    }
+    let evicting_put = ns_per_op(start, ENTRIES);
+    drop(cache);
+    let leaked = ALLOCATED.load(Ordering::Relaxed) - before;
+
+    println!(
+        "{:>6} {:>16} {:>12} {:>12} {:>18} {:>14}",
+        name, per_entry, put, get, evicting_put, leaked
+    );
+}
+
+fn main() {
+    println!(
+        "{:>6} {:>16} {:>12} {:>12} {:>18} {:>14}",
+        "policy", "bytes per entry", "put (ns)", "get (ns)", "evicting put (ns)", "leaked bytes"
+    );
+    bench("lru", &|size| Box::new(LRUCache::new(size)));
+    bench("lfu", &|size| Box::new(LFUCache::new(size)));
+}
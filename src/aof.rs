@@ -0,0 +1,306 @@
+// An append-only log of every write, so a restart loses nothing that was acknowledged before
+// it rather than everything since the last snapshot.
+//
+// The file is MAGIC and a version byte followed by records. Each record is a big-endian u32
+// length, a CRC-32 of the payload and the payload itself: an op byte and its arguments. PUT
+// carries an entry laid out the same way as in a snapshot, so it holds the key's complete state
+// and replaying it twice does no harm. DEL and PERSIST carry the length prefixed key.
+//
+// A record cut short by a crash is dropped on replay and the log truncated back to the last
+// whole record. Rewriting replaces the log with one PUT per live key.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use crate::protocol::Value;
+use crate::snapshot::{self, Reader, Store};
+use crate::stores::Entry;
+
+const MAGIC: &[u8; 4] = b"KVLA";
+const VERSION: u8 = 2;
+const HEADER_LEN: usize = MAGIC.len() + 1;
+
+const OP_PUT: u8 = 1;
+const OP_DEL: u8 = 2;
+const OP_PERSIST: u8 = 3;
+
+// The log is rewritten once it is this big and has doubled since the last rewrite.
+const MIN_REWRITE_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy)]
+pub enum Fsync {
+    // Before every write is acknowledged.
+    Always,
+    // From a background thread once a second, so at most a second of writes can be lost.
+    EverySecond,
+    // Whenever the operating system gets round to it.
+    Never,
+}
+
+pub enum Record {
+    Put(Entry<Value>),
+    Del(Vec<u8>),
+    Persist(Vec<u8>),
+}
+
+// Also the framing of the lake's records.
+pub fn encode(record: &Record) -> Vec<u8> {
+    let mut payload = Vec::new();
+    match record {
+        Record::Put(entry) => {
+            payload.push(OP_PUT);
+            snapshot::write_entry(&mut payload, entry);
+        }
+        Record::Del(key) => {
+            payload.push(OP_DEL);
+            snapshot::write_bytes(&mut payload, key);
+        }
+        Record::Persist(key) => {
+            payload.push(OP_PERSIST);
+            snapshot::write_bytes(&mut payload, key);
+        }
+    }
+    let mut out = Vec::with_capacity(payload.len() + 8);
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(&snapshot::crc32(&payload).to_be_bytes());
+    out.extend_from_slice(&payload);
+    out
+}
+
+// A PUT whose entry has expired since it was logged comes back as a DEL of its key, so that
+// replaying it still hides any older PUT of the same key.
+pub fn decode(payload: &[u8]) -> Result<Record, String> {
+    let mut reader = Reader { buf: &payload[1..] };
+    let record = match payload[0] {
+        OP_PUT => {
+            let key = Reader { buf: reader.buf }.bytes()?;
+            reader.entry()?.map_or(Record::Del(key), Record::Put)
+        }
+        OP_DEL => Record::Del(reader.bytes()?),
+        OP_PERSIST => Record::Persist(reader.bytes()?),
+        op => return Err(format!("unknown op {}", op)),
+    };
+    if !reader.buf.is_empty() {
+        return Err("trailing data in record".to_string());
+    }
+    Ok(record)
+}
+
+// Returns the records of a whole log, along with how many bytes of it were intact.
+fn decode_log(raw: &[u8]) -> Result<(Vec<Record>, usize), String> {
+    if raw.len() < HEADER_LEN || &raw[..MAGIC.len()] != MAGIC {
+        return Err("not a kv-lake append-only log".to_string());
+    }
+    let version = raw[MAGIC.len()];
+    if version != VERSION {
+        return Err(format!("unsupported append-only log version {}", version));
+    }
+    let mut records = Vec::new();
+    let mut pos = HEADER_LEN;
+    while let Some((payload, next)) = read_frame(raw, pos) {
+        match decode(payload) {
+            Ok(record) => records.push(record),
+            Err(msg) => return Err(format!("bad record at byte {}: {}", pos, msg)),
+        }
+        pos = next;
+    }
+    Ok((records, pos))
+}
+
+// The payload of the record starting at `pos` and where the one after it starts, or None if
+// the record is cut short or damaged.
+pub fn read_frame(raw: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    if raw.len() < pos + 8 {
+        return None;
+    }
+    let len = u32::from_be_bytes([raw[pos], raw[pos + 1], raw[pos + 2], raw[pos + 3]]) as usize;
+    let checksum = u32::from_be_bytes([raw[pos + 4], raw[pos + 5], raw[pos + 6], raw[pos + 7]]);
+    let start = pos + 8;
+    if len == 0 || raw.len() - start < len || snapshot::crc32(&raw[start..start + len]) != checksum {
+        return None;
+    }
+    Some((&raw[start..start + len], start + len))
+}
+
+fn create(path: &PathBuf) -> io::Result<File> {
+    let mut file = File::create(path)?;
+    file.write_all(MAGIC)?;
+    file.write_all(&[VERSION])?;
+    Ok(file)
+}
+
+struct Writer {
+    file: File,
+    size: u64,
+    // Everything logged while a rewrite is running, to be appended to the rewritten log.
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+pub struct AppendLog {
+    path: PathBuf,
+    fsync: Fsync,
+    writer: Mutex<Writer>,
+    rewriting: AtomicBool,
+    // The size of the log straight after the last rewrite.
+    base_size: AtomicU64,
+}
+
+impl AppendLog {
+    // Reads back every record in the log at `path`, cutting off a torn last record, and opens
+    // it for appending. A missing log starts out empty.
+    pub fn open(path: PathBuf, fsync: Fsync) -> Result<(AppendLog, Vec<Record>), String> {
+        let describe = |e: io::Error| format!("{}: {}", path.display(), e);
+        let (file, records, size) = match fs::read(&path) {
+            Ok(raw) => {
+                let (records, intact) = decode_log(&raw).map_err(|msg| format!("{}: {}", path.display(), msg))?;
+                if intact < raw.len() {
+                    println!("dropping {} bytes of incomplete records from {}", raw.len() - intact, path.display());
+                }
+                let file = OpenOptions::new().write(true).open(&path).map_err(describe)?;
+                file.set_len(intact as u64).map_err(describe)?;
+                drop(file);
+                let file = OpenOptions::new().append(true).open(&path).map_err(describe)?;
+                (file, records, intact as u64)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (create(&path).map_err(describe)?, Vec::new(), HEADER_LEN as u64),
+            Err(e) => return Err(describe(e)),
+        };
+        let log = AppendLog {
+            path,
+            fsync,
+            writer: Mutex::new(Writer { file, size, rewrite_buffer: None }),
+            rewriting: AtomicBool::new(false),
+            base_size: AtomicU64::new(size),
+        };
+        Ok((log, records))
+    }
+
+    pub fn fsync(&self) -> Fsync {
+        self.fsync
+    }
+
+    pub fn append(&self, record: &Record) {
+        let encoded = encode(record);
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writer.file.write_all(&encoded) {
+            println!("failed to append to {}: {}", self.path.display(), e);
+            return;
+        }
+        if let Fsync::Always = self.fsync {
+            if let Err(e) = writer.file.sync_data() {
+                println!("failed to sync {}: {}", self.path.display(), e);
+            }
+        }
+        writer.size += encoded.len() as u64;
+        if let Some(buffer) = writer.rewrite_buffer.as_mut() {
+            buffer.extend_from_slice(&encoded);
+        }
+    }
+
+    pub fn sync(&self) {
+        if let Err(e) = self.writer.lock().unwrap().file.sync_data() {
+            println!("failed to sync {}: {}", self.path.display(), e);
+        }
+    }
+
+    pub fn needs_rewrite(&self) -> bool {
+        let size = self.writer.lock().unwrap().size;
+        size >= MIN_REWRITE_SIZE && size >= 2 * self.base_size.load(Ordering::Relaxed)
+    }
+
+    // Writes a fresh log holding one PUT per live key next to the current one, then swaps it
+    // in. Writes that land while the cache is being copied are logged to both.
+    pub fn rewrite(&self, cache: &Store) -> Result<(), String> {
+        let describe = |e: io::Error| format!("failed to rewrite {}: {}", self.path.display(), e);
+        self.writer.lock().unwrap().rewrite_buffer = Some(Vec::new());
+        let entries = cache.entries();
+        let tmp_path = self.path.with_extension("rewrite");
+        let mut file = create(&tmp_path).map_err(describe)?;
+        let mut size = HEADER_LEN as u64;
+        let mut encoded = Vec::new();
+        for entry in entries {
+            encoded.extend_from_slice(&encode(&Record::Put(entry)));
+        }
+        file.write_all(&encoded).map_err(describe)?;
+        size += encoded.len() as u64;
+
+        let mut writer = self.writer.lock().unwrap();
+        let buffered = writer.rewrite_buffer.take().unwrap_or_default();
+        let mut finish = || -> io::Result<File> {
+            file.write_all(&buffered)?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, &self.path)?;
+            OpenOptions::new().append(true).open(&self.path)
+        };
+        writer.file = finish().map_err(describe)?;
+        writer.size = size + buffered.len() as u64;
+        self.base_size.store(writer.size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Rewrites on a thread of its own. Returns false if a rewrite is already running.
+    pub fn rewrite_in_background(self: &Arc<Self>, cache: Arc<Store>) -> bool {
+        if self.rewriting.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        let log = Arc::clone(self);
+        thread::spawn(move || {
+            match log.rewrite(&*cache) {
+                Ok(()) => println!("rewrote {}", log.path.display()),
+                Err(msg) => {
+                    log.writer.lock().unwrap().rewrite_buffer = None;
+                    println!("{}", msg)
+                }
+            }
+            log.rewriting.store(false, Ordering::Release);
+        });
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::{AppendLog, Fsync, Record};
+    use crate::protocol::Value;
+    use crate::stores::Entry;
+
+    fn put(key: &str, value: &str, expires_at: Option<Instant>) -> Record {
+        Record::Put(Entry { key: key.as_bytes().to_vec(), value: Value::new(value.as_bytes().to_vec()), expires_at, frequency: 0 })
+    }
+
+    // A PUT that expired while the server was down used to be skipped on replay, which
+    // brought back the older PUT it had overwritten.
+    #[test]
+    fn expired_puts_replay_as_deletes() {
+        let path = std::env::temp_dir().join(format!("kv-lake-aof-test-{}.kvla", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (log, _) = AppendLog::open(path.clone(), Fsync::Never).unwrap();
+        log.append(&put("k", "v1", None));
+        log.append(&put("k", "v2", Some(Instant::now() + Duration::from_millis(10))));
+        log.append(&put("other", "v", None));
+        drop(log);
+        thread::sleep(Duration::from_millis(50));
+
+        let (_, records) = AppendLog::open(path.clone(), Fsync::Never).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let mut replayed = HashMap::new();
+        for record in records {
+            match record {
+                Record::Put(entry) => {
+                    replayed.insert(entry.key, entry.value.data);
+                }
+                Record::Del(key) => {
+                    replayed.remove(&key);
+                }
+                Record::Persist(_) => {}
+            }
+        }
+        assert_eq!(replayed.get(&b"k"[..]), None);
+        assert_eq!(replayed.get(&b"other"[..]), Some(&b"v".to_vec()));
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use crate::aof::Fsync;
+use crate::stores::lfu::Decay;
+use crate::stores::slru;
+
+// Server settings taken from the command line:
+// `mykvstore [PORT [QUEUE_SIZE [EVICTION_STRATEGY]]] [--option value]...`
+pub struct Config {
+    pub port: u32,
+    pub queue_size: u32,
+    // Caps the cache by the memory its entries take up instead of by QUEUE_SIZE.
+    pub max_memory: Option<u64>,
+    pub eviction_strategy: String,
+    // How often the lfu strategy halves its frequencies.
+    pub lfu_decay: Decay,
+    // How much of the slru strategy's capacity its protected segment takes up, in percent.
+    pub slru_protected_percent: u64,
+    pub resp_port: Option<u32>,
+    pub memcached_port: Option<u32>,
+    pub http_port: Option<u32>,
+    pub metrics_port: Option<u32>,
+    pub workers: usize,
+    pub shards: u32,
+    pub snapshot_path: PathBuf,
+    pub snapshot_interval: Option<Duration>,
+    pub aof_path: Option<PathBuf>,
+    pub aof_fsync: Fsync,
+    pub lake_path: Option<PathBuf>,
+    // The most bytes of records the lake keeps before dropping its oldest entries.
+    pub lake_capacity: u64,
+}
+
+fn get_port_number(args: &mut VecDeque<String>) -> u32 {
+    if let Some(arg) = args.pop_front() {
+        match arg.parse::<u32>() {
+            Ok(p) => p,
+            Err(_msg) => {
+                println!("invalid port number. defaulting to 8000");
+                8000
+            }
+        }
+    } else {
+        println!("defaulting to port: 8000");
+        8000
+    }
+}
+
+fn get_queue_size(args: &mut VecDeque<String>) -> u32 {
+    if let Some(arg) = args.pop_front() {
+        match arg.parse::<u32>() {
+            Ok(qs) => qs,
+            Err(_msg) => {
+                println!("invalid queue size. defaulting to queue size: 5");
+                5
+            }
+        }
+    } else {
+        println!("defaulting to queue size: 5");
+        5
+    }
+}
+
+// The values EVICTION_STRATEGY can take.
+const EVICTION_STRATEGIES: [&str; 9] = ["lru", "lfu", "arc", "tinylfu", "slru", "clock", "clockpro", "2q", "lirs"];
+
+fn get_eviction_strategy(args: &mut VecDeque<String>) -> String {
+    if let Some(arg) = args.pop_front() {
+        if EVICTION_STRATEGIES.contains(&arg.as_str()) {
+            arg
+        } else {
+            println!("unknown eviction strategy {}. defaulting to lru", arg);
+            "lru".to_string()
+        }
+    } else {
+        println!("defaulting to lru eviction strategy");
+        "lru".to_string()
+    }
+}
+
+// `N` halves every N gets and puts, `Ns` every N seconds.
+fn parse_decay(value: &str) -> Option<Decay> {
+    match value.strip_suffix('s') {
+        Some(secs) => match secs.parse::<u64>() {
+            Ok(secs) if secs > 0 => Some(Decay::Interval(Duration::from_secs(secs))),
+            _ => None,
+        },
+        None => match value.parse::<u64>() {
+            Ok(n) if n > 0 => Some(Decay::Operations(n)),
+            _ => None,
+        },
+    }
+}
+
+// Unless --shards says otherwise the cache gets a shard per CPU, as long as each still holds at
+// least this many entries, or bytes under --maxmemory. A small cache split any further ends up
+// with shards so small that keys are evicted while the cache as a whole still has room.
+const MIN_SHARD_ENTRIES: u64 = 1024;
+const MIN_SHARD_BYTES: u64 = 1024 * 1024;
+
+fn default_shards(cpus: usize, queue_size: u32, max_memory: Option<u64>) -> u32 {
+    let most = match max_memory {
+        Some(bytes) => bytes / MIN_SHARD_BYTES,
+        None => queue_size as u64 / MIN_SHARD_ENTRIES,
+    };
+    (cpus as u64).min(most).max(1) as u32
+}
+
+fn get_optional_port(name: &str, value: &str) -> Option<u32> {
+    match value.parse::<u32>() {
+        Ok(p) => Some(p),
+        Err(_msg) => {
+            println!("invalid port number for --{}. ignoring it", name);
+            None
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args(mut args: VecDeque<String>) -> Config {
+        args.pop_front(); // program name
+        let mut positional = VecDeque::new();
+        let mut resp_port = None;
+        let mut memcached_port = None;
+        let mut http_port = None;
+        let mut metrics_port = None;
+        let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let mut workers = cpus;
+        let mut shards = None;
+        let mut snapshot_path = PathBuf::from("kv-lake.snapshot");
+        let mut snapshot_interval = None;
+        let mut aof_path = None;
+        let mut aof_fsync = Fsync::EverySecond;
+        let mut max_memory = None;
+        let mut lake_path = None;
+        let mut lake_capacity = 1024 * 1024 * 1024;
+        let mut lfu_decay = Decay::Never;
+        let mut slru_protected_percent = slru::DEFAULT_PROTECTED_PERCENT;
+        while let Some(arg) = args.pop_front() {
+            if let Some(name) = arg.strip_prefix("--") {
+                let value = args.pop_front().unwrap_or_default();
+                match name {
+                    "resp-port" => resp_port = get_optional_port(name, &value),
+                    "memcached-port" => memcached_port = get_optional_port(name, &value),
+                    "http-port" => http_port = get_optional_port(name, &value),
+                    "metrics-port" => metrics_port = get_optional_port(name, &value),
+                    "workers" => match value.parse::<usize>() {
+                        Ok(n) if n > 0 => workers = n,
+                        _ => println!("invalid worker count. defaulting to {}", workers),
+                    },
+                    "shards" => match value.parse::<u32>() {
+                        Ok(n) if n > 0 => shards = Some(n),
+                        _ => println!("invalid shard count. picking one to suit the cache size"),
+                    },
+                    "snapshot" => snapshot_path = PathBuf::from(value),
+                    "snapshot-interval" => match value.parse::<u64>() {
+                        Ok(secs) if secs > 0 => snapshot_interval = Some(Duration::from_secs(secs)),
+                        _ => println!("invalid snapshot interval. not saving periodically"),
+                    },
+                    "appendonly" => aof_path = Some(PathBuf::from(value)),
+                    "appendfsync" => match value.as_str() {
+                        "always" => aof_fsync = Fsync::Always,
+                        "everysec" => aof_fsync = Fsync::EverySecond,
+                        "no" => aof_fsync = Fsync::Never,
+                        _ => println!("invalid appendfsync policy. defaulting to everysec"),
+                    },
+                    "maxmemory" => match value.parse::<u64>() {
+                        Ok(bytes) if bytes > 0 => max_memory = Some(bytes),
+                        _ => println!("invalid maxmemory. capping the cache by QUEUE_SIZE"),
+                    },
+                    "lake" => lake_path = Some(PathBuf::from(value)),
+                    "lake-capacity" => match value.parse::<u64>() {
+                        Ok(bytes) if bytes > 0 => lake_capacity = bytes,
+                        _ => println!("invalid lake capacity. defaulting to {} bytes", lake_capacity),
+                    },
+                    "lfu-decay" => match parse_decay(&value) {
+                        Some(decay) => lfu_decay = decay,
+                        None => println!("invalid lfu decay. not decaying frequencies"),
+                    },
+                    "slru-protected" => match value.parse::<u64>() {
+                        Ok(percent) if percent <= 100 => slru_protected_percent = percent,
+                        _ => println!("invalid slru protected percentage. defaulting to {}", slru_protected_percent),
+                    },
+                    _ => println!("unknown option --{}. ignoring it", name),
+                }
+            } else {
+                positional.push_back(arg);
+            }
+        }
+        let port = get_port_number(&mut positional);
+        let queue_size = get_queue_size(&mut positional);
+        Config {
+            port,
+            queue_size,
+            max_memory,
+            eviction_strategy: get_eviction_strategy(&mut positional),
+            lfu_decay,
+            slru_protected_percent,
+            resp_port,
+            memcached_port,
+            http_port,
+            metrics_port,
+            workers,
+            shards: shards.unwrap_or_else(|| default_shards(cpus, queue_size, max_memory)),
+            snapshot_path,
+            snapshot_interval,
+            aof_path,
+            aof_fsync,
+            lake_path,
+            lake_capacity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::default_shards;
+
+    #[test]
+    fn small_caches_default_to_fewer_shards_than_cpus() {
+        assert_eq!(default_shards(8, 5, None), 1);
+        assert_eq!(default_shards(8, 3000, None), 2);
+        assert_eq!(default_shards(8, 1_000_000, None), 8);
+        assert_eq!(default_shards(8, 5, Some(4 * 1024 * 1024)), 4);
+        assert_eq!(default_shards(8, 5, Some(1000)), 1);
+    }
+}
@@ -0,0 +1,367 @@
+// The on-disk tier behind the cache. Entries evicted from memory are spilled here rather than
+// dropped, and a command on a key that is no longer in memory brings it back from here first.
+//
+// The lake is a log-structured file: MAGIC, a version byte, then records framed the same way
+// as the append-only log's. A PUT holds a spilled entry and a DEL marks the key as gone from
+// the lake again, either because it went back into memory or because it was deleted. Only the
+// latest record for a key counts, so the index of where each key's record lives is rebuilt by
+// reading the file from the front at startup.
+//
+// Once the live records take up more than the lake's capacity the oldest are dropped. The
+// space of dropped and overwritten records is reclaimed by compaction, which copies the live
+// records into a fresh file.
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use crate::aof::{self, Record};
+use crate::protocol::Value;
+use crate::snapshot::Reader;
+use crate::stores::Entry;
+
+const MAGIC: &[u8; 4] = b"KVLK";
+// Entries are laid out as in snapshots.
+const VERSION: u8 = 2;
+const HEADER_LEN: usize = MAGIC.len() + 1;
+
+// The lake is compacted once the space taken by dead records is at least this big and as big
+// as the live records.
+const MIN_COMPACTION_GARBAGE: u64 = 4 * 1024 * 1024;
+
+// Where a key's latest record is in the file, framing included.
+#[derive(Clone, Copy)]
+struct Location {
+    offset: u64,
+    len: u64,
+}
+
+struct Inner {
+    file: File,
+    size: u64,
+    index: HashMap<Vec<u8>, Location>,
+    // The same keys by the offset of their record, so the oldest can be dropped first.
+    by_offset: BTreeMap<u64, Vec<u8>>,
+    // Bytes taken up by the records in the index.
+    live: u64,
+}
+
+impl Inner {
+    fn forget(&mut self, key: &[u8]) -> bool {
+        match self.index.remove(key) {
+            Some(location) => {
+                self.by_offset.remove(&location.offset);
+                self.live -= location.len;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn add(&mut self, key: Vec<u8>, location: Location, capacity: u64) {
+        self.forget(&key);
+        self.live += location.len;
+        self.by_offset.insert(location.offset, key.clone());
+        self.index.insert(key, location);
+        while self.live > capacity {
+            let oldest = match self.by_offset.iter().next() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            self.forget(&oldest);
+        }
+    }
+
+    fn append(&mut self, record: &Record) -> io::Result<Location> {
+        let encoded = aof::encode(record);
+        self.file.write_all(&encoded)?;
+        let location = Location { offset: self.size, len: encoded.len() as u64 };
+        self.size += location.len;
+        Ok(location)
+    }
+
+    fn read(&mut self, location: Location) -> io::Result<Vec<u8>> {
+        read_at(&mut self.file, location)
+    }
+}
+
+// A compaction part way through: the live records as they were when it started, copied into
+// a file that is yet to be swapped in.
+struct Compaction {
+    tmp_path: PathBuf,
+    out: BufWriter<File>,
+    // The lake as it was, to read what is appended to it meanwhile from.
+    source: File,
+    // Each key copied, with the offset its record was at and where the copy is.
+    moved: Vec<(Vec<u8>, u64, Location)>,
+    // How much of the lake was there to copy.
+    copied_up_to: u64,
+    size: u64,
+}
+
+fn read_at(file: &mut File, location: Location) -> io::Result<Vec<u8>> {
+    let mut frame = vec![0; location.len as usize];
+    file.seek(SeekFrom::Start(location.offset))?;
+    file.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+// The key a record is about. PUT and DEL payloads both start with it.
+fn record_key(payload: &[u8]) -> Result<Vec<u8>, String> {
+    Reader { buf: &payload[1..] }.bytes()
+}
+
+fn create(path: &PathBuf) -> io::Result<File> {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+    file.write_all(MAGIC)?;
+    file.write_all(&[VERSION])?;
+    Ok(file)
+}
+
+pub struct Lake {
+    path: PathBuf,
+    capacity: u64,
+    inner: Mutex<Inner>,
+    compacting: AtomicBool,
+}
+
+impl Lake {
+    // Opens the lake at `path`, creating it if it is missing, and indexes what is in it. A
+    // torn last record is cut off.
+    pub fn open(path: PathBuf, capacity: u64) -> Result<Lake, String> {
+        let describe = |e: io::Error| format!("{}: {}", path.display(), e);
+        let raw = match fs::read(&path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                create(&path).map_err(describe)?;
+                let mut raw = MAGIC.to_vec();
+                raw.push(VERSION);
+                raw
+            }
+            Err(e) => return Err(describe(e)),
+        };
+        if raw.len() < HEADER_LEN || &raw[..MAGIC.len()] != MAGIC {
+            return Err(format!("{}: not a kv-lake lake", path.display()));
+        }
+        if raw[MAGIC.len()] != VERSION {
+            return Err(format!("{}: unsupported lake version {}", path.display(), raw[MAGIC.len()]));
+        }
+        let file = OpenOptions::new().read(true).append(true).open(&path).map_err(describe)?;
+        let mut inner = Inner { file, size: 0, index: HashMap::new(), by_offset: BTreeMap::new(), live: 0 };
+        let mut pos = HEADER_LEN;
+        while let Some((payload, next)) = aof::read_frame(&raw, pos) {
+            let bad_record = |msg| format!("{}: bad record at byte {}: {}", path.display(), pos, msg);
+            let key = record_key(payload).map_err(bad_record)?;
+            // Entries that have expired since they were spilled are as good as deleted.
+            match aof::decode(payload).map_err(bad_record)? {
+                Record::Put(_) => inner.add(key, Location { offset: pos as u64, len: (next - pos) as u64 }, capacity),
+                _ => {
+                    inner.forget(&key);
+                }
+            }
+            pos = next;
+        }
+        if pos < raw.len() {
+            println!("dropping {} bytes of incomplete records from {}", raw.len() - pos, path.display());
+            inner.file.set_len(pos as u64).map_err(describe)?;
+        }
+        inner.size = pos as u64;
+        Ok(Lake { path, capacity, inner: Mutex::new(inner), compacting: AtomicBool::new(false) })
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().index.len()
+    }
+
+    // Writes an entry evicted from memory to the lake. Entries that have already expired, or
+    // that are bigger than the whole lake, are dropped.
+    pub fn spill(&self, entry: Entry<Value>) {
+        if entry.expires_at.is_some_and(|t| t <= Instant::now()) {
+            return;
+        }
+        let key = entry.key.clone();
+        let mut inner = self.inner.lock().unwrap();
+        match inner.append(&Record::Put(entry)) {
+            Ok(location) => inner.add(key, location, self.capacity),
+            Err(e) => println!("failed to spill to {}: {}", self.path.display(), e),
+        }
+    }
+
+    // Takes a key out of the lake, so it can go back into memory.
+    pub fn take(&self, key: &[u8]) -> Option<Entry<Value>> {
+        let mut inner = self.inner.lock().unwrap();
+        let location = *inner.index.get(key)?;
+        let frame = inner.read(location);
+        self.remove_locked(&mut inner, key);
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                println!("failed to read {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+        match aof::read_frame(&frame, 0).map(|(payload, _)| aof::decode(payload)) {
+            Some(Ok(Record::Put(entry))) => Some(entry),
+            Some(Ok(_)) => None,
+            _ => {
+                println!("damaged record at byte {} of {}", location.offset, self.path.display());
+                None
+            }
+        }
+    }
+
+    // Drops a key from the lake, if it is there.
+    pub fn remove(&self, key: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        self.remove_locked(&mut inner, key);
+    }
+
+    fn remove_locked(&self, inner: &mut Inner, key: &[u8]) {
+        if inner.forget(key) {
+            if let Err(e) = inner.append(&Record::Del(key.to_vec())) {
+                println!("failed to append to {}: {}", self.path.display(), e);
+            }
+        }
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let garbage = inner.size - HEADER_LEN as u64 - inner.live;
+        garbage >= MIN_COMPACTION_GARBAGE && garbage >= inner.live
+    }
+
+    // Copies the live records into a fresh file next to the lake and swaps it in, oldest first
+    // so they are dropped in the same order as before.
+    pub fn compact(&self) -> Result<(), String> {
+        let compaction = self.copy_live()?;
+        self.swap_in(compaction)
+    }
+
+    // The bulk of a compaction, done from a snapshot of the index without holding the lock, so
+    // spills and takes carry on meanwhile.
+    fn copy_live(&self) -> Result<Compaction, String> {
+        let describe = |e: io::Error| format!("failed to compact {}: {}", self.path.display(), e);
+        let (mut source, live, copied_up_to) = {
+            let inner = self.inner.lock().unwrap();
+            let source = File::open(&self.path).map_err(describe)?;
+            let live: Vec<(Vec<u8>, Location)> = inner.by_offset.values().map(|key| (key.clone(), inner.index[key])).collect();
+            (source, live, inner.size)
+        };
+        let tmp_path = self.path.with_extension("compact");
+        let mut out = BufWriter::new(create(&tmp_path).map_err(describe)?);
+        let mut moved = Vec::with_capacity(live.len());
+        let mut size = HEADER_LEN as u64;
+        for (key, location) in live {
+            let frame = read_at(&mut source, location).map_err(describe)?;
+            out.write_all(&frame).map_err(describe)?;
+            moved.push((key, location.offset, Location { offset: size, len: location.len }));
+            size += location.len;
+        }
+        out.flush().map_err(describe)?;
+        out.get_ref().sync_all().map_err(describe)?;
+        Ok(Compaction { tmp_path, out, source, moved, copied_up_to, size })
+    }
+
+    // Takes the lock to copy over the records appended since copy_live looked, and swaps the
+    // new file in. They go on the end as they are, after any copy they replace or delete, so
+    // they still win when the lake is read back.
+    fn swap_in(&self, compaction: Compaction) -> Result<(), String> {
+        let describe = |e: io::Error| format!("failed to compact {}: {}", self.path.display(), e);
+        let Compaction { tmp_path, mut out, mut source, moved, copied_up_to, size } = compaction;
+        let mut inner = self.inner.lock().unwrap();
+        let appended = Location { offset: copied_up_to, len: inner.size - copied_up_to };
+        let tail = read_at(&mut source, appended).map_err(describe)?;
+        let mut index = HashMap::with_capacity(inner.index.len());
+        let mut by_offset = BTreeMap::new();
+        for (key, was, moved) in moved {
+            // Keys deleted, dropped or spilled again since are left out.
+            if inner.index.get(&key).map(|location| location.offset) == Some(was) {
+                by_offset.insert(moved.offset, key.clone());
+                index.insert(key, moved);
+            }
+        }
+        for (&offset, key) in inner.by_offset.range(copied_up_to..) {
+            let moved = Location { offset: offset - copied_up_to + size, len: inner.index[key].len };
+            by_offset.insert(moved.offset, key.clone());
+            index.insert(key.clone(), moved);
+        }
+        let mut finish = || -> io::Result<File> {
+            out.write_all(&tail)?;
+            out.flush()?;
+            out.get_ref().sync_all()?;
+            fs::rename(&tmp_path, &self.path)?;
+            OpenOptions::new().read(true).append(true).open(&self.path)
+        };
+        inner.file = finish().map_err(describe)?;
+        inner.size = size + tail.len() as u64;
+        inner.index = index;
+        inner.by_offset = by_offset;
+        Ok(())
+    }
+
+    // Compacts on a thread of its own. Returns false if a compaction is already running.
+    pub fn compact_in_background(self: &Arc<Self>) -> bool {
+        if self.compacting.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        let lake = Arc::clone(self);
+        thread::spawn(move || {
+            match lake.compact() {
+                Ok(()) => println!("compacted {}", lake.path.display()),
+                Err(msg) => println!("{}", msg),
+            }
+            lake.compacting.store(false, Ordering::Release);
+        });
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lake;
+    use crate::protocol::Value;
+    use crate::stores::Entry;
+
+    fn entry(key: &str, value: &str) -> Entry<Value> {
+        Entry { key: key.as_bytes().to_vec(), value: Value::new(value.as_bytes().to_vec()), expires_at: None, frequency: 0 }
+    }
+
+    fn taken(lake: &Lake, key: &str) -> Option<String> {
+        lake.take(key.as_bytes()).map(|entry| String::from_utf8(entry.value.data).unwrap())
+    }
+
+    // Compaction copies without the lock held, so spills, takes and deletes can land part way
+    // through. They have to survive the swap, and still win over the copies when read back.
+    #[test]
+    fn writes_during_compaction_survive_it() {
+        let path = std::env::temp_dir().join(format!("kv-lake-lake-test-{}.kvlk", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let lake = Lake::open(path.clone(), 1 << 20).unwrap();
+        for key in &["a", "b", "c", "d", "e"] {
+            lake.spill(entry(key, key));
+        }
+        lake.remove(b"b");
+        lake.spill(entry("c", "c2"));
+
+        let compaction = lake.copy_live().unwrap();
+        lake.spill(entry("f", "f"));
+        lake.remove(b"d");
+        lake.spill(entry("a", "a2"));
+        assert_eq!(taken(&lake, "e"), Some("e".to_string()));
+        lake.swap_in(compaction).unwrap();
+
+        lake.spill(entry("g", "g"));
+        assert_eq!(lake.len(), 4);
+        assert_eq!(taken(&lake, "c"), Some("c2".to_string()));
+        drop(lake);
+
+        let lake = Lake::open(path.clone(), 1 << 20).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let found: Vec<_> = ["a", "b", "c", "d", "e", "f", "g"].iter().map(|key| taken(&lake, key)).collect();
+        let expected = vec![Some("a2"), None, None, None, None, Some("f"), Some("g")];
+        assert_eq!(found, expected.into_iter().map(|value| value.map(String::from)).collect::<Vec<_>>());
+    }
+}
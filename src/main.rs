@@ -1,183 +1,460 @@
 pub mod stores;
+mod protocol;
+mod config;
+mod server;
+mod metrics;
+mod snapshot;
+mod aof;
+mod lake;
 use stores::lru::LRUCache;
 use stores::lfu::LFUCache;
+use stores::arc::ArcCache;
+use stores::tinylfu::TinyLfuCache;
+use stores::slru::SLRUCache;
+use stores::clock::ClockCache;
+use stores::clockpro::ClockProCache;
+use stores::twoq::TwoQueueCache;
+use stores::lirs::LIRSCache;
+use stores::sharded::ShardedCache;
+use protocol::{Command, ErrorCode, Response, Value};
+use stores::Condition;
+use config::Config;
+use snapshot::Snapshots;
+use aof::{AppendLog, Record};
+use lake::Lake;
 use std::io;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread;
 use std::collections::VecDeque;
 use std::env;
+use std::time::{Duration, Instant};
 
-enum Command<T> {
-    PUT(String, T),
-    GET(String),
-    DEL(String),
-    QUIT,
+// How often the background sweeper evicts keys whose ttl has run out.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+type Cache = Arc<ShardedCache<Value>>;
+
+// What commands run against: the cache and whatever keeps it on disk.
+struct Context {
+    cache: Cache,
+    snapshots: Arc<Snapshots>,
+    log: Option<Arc<AppendLog>>,
+    lake: Option<Arc<Lake>>,
+}
+
+#[cfg(test)]
+impl Context {
+    // A single LRU shard of `capacity` entries with nothing kept on disk, for the protocol tests
+    // to run commands against.
+    fn in_memory(capacity: u64) -> Context {
+        let snapshot_path = env::temp_dir().join(format!("kv-lake-test-{}.kvls", std::process::id()));
+        Context {
+            cache: Arc::new(ShardedCache::new(capacity, 1, |share| Box::new(LRUCache::new(share as u32)))),
+            snapshots: Arc::new(Snapshots::new(snapshot_path)),
+            log: None,
+            lake: None,
+        }
+    }
+}
+
+static NEXT_CAS: AtomicU64 = AtomicU64::new(1);
+
+fn next_cas() -> u64 {
+    NEXT_CAS.fetch_add(1, Ordering::Relaxed)
+}
+
+// Writes the key if `condition` holds, returning whether it did, and records the write in the
+// append-only log, if there is one. Fails without writing anything if the value is too large
+// for the shard that owns the key, which only gets its share of the cache to itself.
+fn store(m_cache: &mut dyn stores::Cacheable<Value>, log: Option<&AppendLog>, key: Vec<u8>, value: Value, ttl: Option<Duration>, condition: Condition) -> Result<bool, Response> {
+    let expires_at = ttl.map(stores::deadline).transpose().map_err(|msg| Response::error(ErrorCode::InvalidArgument, &msg))?;
+    let logged = log.map(|_| stores::Entry { key: key.clone(), value: value.clone(), expires_at, frequency: 0 });
+    let stored = m_cache.put_if(key, value, ttl, condition).map_err(|_| Response::error(ErrorCode::TooLarge, "value is larger than a shard's share of the cache"))?;
+    if let (true, Some(log), Some(entry)) = (stored, log, logged) {
+        log.append(&Record::Put(entry));
+    }
+    Ok(stored)
+}
+
+// Brings a key that was spilled to the lake back into memory, ahead of a command on it.
+fn promote(m_cache: &mut dyn stores::Cacheable<Value>, lake: &Lake, key: &[u8]) {
+    if let stores::Ttl::Missing = m_cache.ttl(key.to_vec()) {
+        if let Some(entry) = lake.take(key) {
+            m_cache.restore(entry);
+        }
+    }
+}
+
+// Moves whatever the cache has evicted out to the lake.
+fn spill(m_cache: &mut dyn stores::Cacheable<Value>, lake: &Lake) {
+    for entry in m_cache.take_evicted() {
+        lake.spill(entry);
+    }
+}
+
+fn remaining_ttl(m_cache: &mut dyn stores::Cacheable<Value>, key: Vec<u8>) -> Option<Duration> {
+    match m_cache.ttl(key) {
+        stores::Ttl::Expiring(remaining) => Some(remaining),
+        _ => None,
+    }
+}
+
+fn incr_decr(m_cache: &mut dyn stores::Cacheable<Value>, log: Option<&AppendLog>, key: Vec<u8>, delta: u64, incr: bool) -> Response {
+    let mut value = match m_cache.get(key.clone()) {
+        Some(value) => value,
+        None => return Response::NotFound,
+    };
+    let current = match std::str::from_utf8(&value.data).ok().and_then(|s| s.parse::<u64>().ok()) {
+        Some(n) => n,
+        None => return Response::error(ErrorCode::InvalidArgument, "cannot increment or decrement non-numeric value"),
+    };
+    // Same as memcached: incrementing wraps around and decrementing stops at zero.
+    let updated = if incr { current.wrapping_add(delta) } else { current.saturating_sub(delta) };
+    value.data = updated.to_string().into_bytes();
+    value.cas = next_cas();
+    let ttl = remaining_ttl(m_cache, key.clone());
+    match store(m_cache, log, key, value.clone(), ttl, Condition::Always) {
+        Ok(_) => Response::Value(value),
+        Err(response) => response,
+    }
 }
 
-fn get_command(raw_input: &str) -> Result<Command<String>, String> {
-    let mut raw_input = raw_input.to_string();
-    raw_input.pop(); // removing trailing newline char
-    let mut input_vec: VecDeque<&str> = raw_input.split(" ").collect::<VecDeque<&str>>();
-    let c = input_vec.pop_front().unwrap();
-    if c == "PUT" {
-        Ok(Command::PUT(
-            input_vec.pop_front().unwrap().to_string(),
-            input_vec.iter().fold(String::new(), |mut acc, x| {
-                acc.push_str(" ");
-                acc.push_str(x);
-                acc
-            })
-        ))
-    } else if c == "GET" {
-        Ok(Command::GET(input_vec.pop_front().unwrap().to_string()))
-    } else if c == "quit" {
-        Ok(Command::QUIT)
-    } else if c == "DEL" {
-        Ok(Command::DEL(input_vec.pop_front().unwrap().to_string()))
-    } else {
-        Err("Received unknown command".to_string())
+// Serves a GET under the shard's read lock when its store can, so GETs on one shard don't wait
+// on each other. A miss with the lake enabled still needs the write lock, to bring the key back.
+fn get_shared(cache: &Cache, lake: Option<&Lake>, key: &[u8]) -> Option<Response> {
+    match cache.shard(key).read().unwrap().get_shared(key)? {
+        Some(output) => Some(Response::Value(output)),
+        None if lake.is_none() => Some(Response::NotFound),
+        None => None,
     }
 }
 
-fn handle_connection(conn: &mut TcpStream, cache: Arc<RwLock<dyn stores::Cacheable<String>>>) -> io::Result<String> {
-    loop {
-        let mut input_length = [0; 1];
-        conn.read(&mut input_length)?;
-        if input_length[0] == 0 {
-            println!("shutting down. bye!");
-            conn.shutdown(Shutdown::Both).expect("failed to shutdown");
-            return Ok("".to_string());
-        }
-        let mut input_buffer: Vec<u8> = vec![0; input_length[0] as usize];
-        conn.read(&mut input_buffer)?;
-        let string_buffer = std::str::from_utf8(&input_buffer).unwrap();
-        println!("Received Command: {}", string_buffer);
-        match get_command(string_buffer) {
-            Ok(command) => match command {
-                Command::PUT(key, value) => {
-                    let mut m_cache = cache.write().unwrap();
-                    m_cache.put(key.to_string(), String::clone(&value));
-                    let output = format!("Added {}: {}", key, value);
-                    conn.write(&[output.len() as u8]).expect("len socket write failed");
-                    conn.write(output.as_bytes()).expect("data socket write failed");
-                    println!("hashmap-----------");
-                    m_cache.print_map();
-                    println!("linked list-----------");
-                    m_cache.print_list();
+fn execute(command: Command, context: &Context) -> Response {
+    let cache = &context.cache;
+    let log = context.log.as_deref();
+    let lake = context.lake.as_deref();
+    if let Command::GET(key) = &command {
+        if let Some(response) = get_shared(cache, lake, key) {
+            return response;
+        }
+    }
+    // Only the shard holding the key is locked, so commands on other keys carry on meanwhile.
+    let mut m_cache = match command {
+        Command::STATS => return Response::Stats(stores::Cacheable::stats(&**cache)),
+        Command::SAVE => {
+            return match context.snapshots.save(&**cache) {
+                Ok(_) => Response::Ok,
+                Err(msg) => Response::error(ErrorCode::ServerError, &msg),
+            }
+        }
+        Command::BGSAVE => {
+            return if context.snapshots.save_in_background(Arc::clone(cache) as _) {
+                Response::Ok
+            } else {
+                Response::error(ErrorCode::Conflict, "background save already in progress")
+            }
+        }
+        Command::BGREWRITEAOF => {
+            return match &context.log {
+                Some(log) if log.rewrite_in_background(Arc::clone(cache) as _) => Response::Ok,
+                Some(_) => Response::error(ErrorCode::Conflict, "append-only log rewrite already in progress"),
+                None => Response::error(ErrorCode::InvalidArgument, "the append-only log is not enabled"),
+            }
+        }
+        Command::QUIT => return Response::Ok,
+        _ => cache.shard(command.key().unwrap()).write().unwrap(),
+    };
+    // A key is only ever in one of memory and the lake. A conditional PUT needs to know whether
+    // the key is in either.
+    if let Some(lake) = lake {
+        match &command {
+            Command::PUT(key, _, _, Condition::Always) => lake.remove(key),
+            _ => promote(&mut **m_cache, lake, command.key().unwrap()),
+        }
+    }
+    let response = match command {
+        Command::PUT(key, mut value, ttl, condition) => {
+            value.cas = next_cas();
+            let printed = format!("{}: {}", String::from_utf8_lossy(&key), value);
+            match store(&mut **m_cache, log, key, value, ttl, condition) {
+                Ok(true) => {
+                    println!("Added {}", printed);
+                    Response::Ok
                 }
-                Command::GET(key) => {
-                    let mut m_cache = cache.write().unwrap();
-                    if let Some(output) = m_cache.get(key) {
-                        println!("output = {}", output);
-                        conn.write(&[output.len() as u8]).expect("len socket write failed");
-                        conn.write(output.as_bytes()).expect("data socket write failed");
-                    } else {
-                        println!("Not found in cache");
-                    }
-                    println!("hashmap-----------");
-                    m_cache.print_map();
-                    println!("linked list-----------");
-                    m_cache.print_list();
+                Ok(false) => {
+                    println!("Not stored {}", printed);
+                    Response::NotStored
                 }
-                Command::DEL(key) => {
-                    let mut m_cache = cache.write().unwrap();
-                    let mut output = String::new();
-                    if let Err(msg) = m_cache.delete(key) {
-                        output = msg;
-                    }
-                    println!("output = {}", output);
-                    conn.write(&[output.len() as u8]).expect("len socket write failed");
-                    conn.write(output.as_bytes()).expect("data socket write failed");
-                    println!("hashmap-----------");
-                    m_cache.print_map();
-                    println!("linked list-----------");
-                    m_cache.print_list();
+                Err(response) => response,
+            }
+        }
+        Command::GET(key) => {
+            if let Some(output) = m_cache.get(key) {
+                println!("output = {}", output);
+                Response::Value(output)
+            } else {
+                println!("Not found in cache");
+                Response::NotFound
+            }
+        }
+        Command::DEL(key) => match m_cache.delete(key.clone()) {
+            Ok(()) => {
+                if let Some(log) = log {
+                    log.append(&Record::Del(key));
                 }
-                Command::QUIT => {
-                    println!("shutting down. bye!");
-                    conn.shutdown(Shutdown::Both).expect("failed to shutdown");
-                    return Ok("".to_string());
+                Response::Ok
+            }
+            Err(_) => Response::NotFound,
+        },
+        Command::TTL(key) => match m_cache.ttl(key) {
+            stores::Ttl::Missing => Response::Integer(-2),
+            stores::Ttl::Persistent => Response::Integer(-1),
+            stores::Ttl::Expiring(remaining) => Response::Integer(remaining.as_secs() as i64),
+        },
+        Command::PERSIST(key) => {
+            let persisted = m_cache.persist(key.clone());
+            if let (true, Some(log)) = (persisted, log) {
+                log.append(&Record::Persist(key));
+            }
+            Response::Integer(persisted as i64)
+        }
+        Command::INCR(key, delta) => incr_decr(&mut **m_cache, log, key, delta, true),
+        Command::DECR(key, delta) => incr_decr(&mut **m_cache, log, key, delta, false),
+        Command::CAS(key, mut value, ttl, expected) => match m_cache.get(key.clone()) {
+            None => Response::NotFound,
+            Some(current) if current.cas != expected => Response::error(ErrorCode::Conflict, "cas mismatch"),
+            Some(_) => {
+                value.cas = next_cas();
+                match store(&mut **m_cache, log, key, value, ttl, Condition::Always) {
+                    Ok(_) => Response::Ok,
+                    Err(response) => response,
                 }
-            },
-            Err(_e) => {
-                let output = "unknown command";
-                println!("{}", output);
-                conn.write(&[output.len() as u8]).expect("len socket write failed");
-                conn.write(output.as_bytes()).expect("data socket write failed");
-            },
-        };
+            }
+        },
+        Command::STATS | Command::SAVE | Command::BGSAVE | Command::BGREWRITEAOF | Command::QUIT => unreachable!("only commands with a key lock a shard"),
+    };
+    if let Some(lake) = lake {
+        spill(&mut **m_cache, lake);
+    }
+    println!("hashmap-----------");
+    m_cache.print_map();
+    println!("linked list-----------");
+    m_cache.print_list();
+    drop(m_cache);
+    if let Some(log) = &context.log {
+        if log.needs_rewrite() {
+            log.rewrite_in_background(Arc::clone(cache) as _);
+        }
+    }
+    if let Some(lake) = &context.lake {
+        if lake.needs_compaction() {
+            lake.compact_in_background();
+        }
     }
+    response
 }
 
-fn get_port_number(args: &mut VecDeque<String>) -> u32 {
-    args.pop_front();
-    if args.is_empty() {
-        println!("defaulting to port: 8000");
-        8000
-    } else {
-        let port = match args[0].to_string().parse::<u32>() {
-            Ok(p) => p,
-            Err(_msg) => {
-                println!("invalid port number. defaulting to 8000");
-                8000
-            }
-        };
-        port
+type Shard = Box<dyn stores::Cacheable<Value> + Send + Sync>;
+
+// One shard of the cache under the configured eviction strategy, holding `share` entries, or
+// `share` bytes when the cache is capped by memory.
+fn new_shard(config: &Config, share: u64) -> Shard {
+    let by_bytes = config.max_memory.is_some();
+    match (config.eviction_strategy.as_str(), by_bytes) {
+        ("lfu", _) => {
+            let mut cache = if by_bytes { LFUCache::with_max_bytes(share) } else { LFUCache::new(share as u32) };
+            cache.set_decay(config.lfu_decay);
+            Box::new(cache)
+        }
+        ("arc", true) => Box::new(ArcCache::with_max_bytes(share)),
+        ("arc", false) => Box::new(ArcCache::new(share as u32)),
+        ("tinylfu", true) => Box::new(TinyLfuCache::with_max_bytes(share)),
+        ("tinylfu", false) => Box::new(TinyLfuCache::new(share as u32)),
+        ("slru", _) => {
+            let mut cache = if by_bytes { SLRUCache::with_max_bytes(share) } else { SLRUCache::new(share as u32) };
+            cache.set_protected_percent(config.slru_protected_percent);
+            Box::new(cache)
+        }
+        ("clock", true) => Box::new(ClockCache::with_max_bytes(share)),
+        ("clock", false) => Box::new(ClockCache::new(share as u32)),
+        ("clockpro", true) => Box::new(ClockProCache::with_max_bytes(share)),
+        ("clockpro", false) => Box::new(ClockProCache::new(share as u32)),
+        ("2q", true) => Box::new(TwoQueueCache::with_max_bytes(share)),
+        ("2q", false) => Box::new(TwoQueueCache::new(share as u32)),
+        ("lirs", true) => Box::new(LIRSCache::with_max_bytes(share)),
+        ("lirs", false) => Box::new(LIRSCache::new(share as u32)),
+        (_, true) => Box::new(LRUCache::with_max_bytes(share)),
+        (_, false) => Box::new(LRUCache::new(share as u32)),
     }
 }
 
-fn get_queue_size(args: &mut VecDeque<String>) -> u32 {
-    args.pop_front();
-    if args.is_empty() {
-        println!("defaulting to queue size: 5");
-        5
-    } else {
-        let queue_size = match args[0].to_string().parse::<u32>() {
-            Ok(qs) => qs,
-            Err(_msg) => {
-                println!("invalid queue size. defaulting to queue size: 5");
-                5
+// Replaces whatever the key held, keeping the entry's place in the eviction order.
+fn restore(cache: &Cache, lake: Option<&Lake>, entry: stores::Entry<Value>) {
+    // Keep handing out cas values the restored entries have never had.
+    NEXT_CAS.fetch_max(entry.value.cas + 1, Ordering::Relaxed);
+    let mut shard = cache.shard(&entry.key).write().unwrap();
+    if let Some(lake) = lake {
+        lake.remove(&entry.key);
+    }
+    shard.restore(entry);
+    if let Some(lake) = lake {
+        spill(&mut **shard, lake);
+    }
+}
+
+// Replays the append-only log into the cache. With the log enabled it holds every write, so
+// the snapshot is only used when there is no log.
+fn open_log(config: &Config, cache: &Cache, lake: Option<&Lake>) -> Option<Arc<AppendLog>> {
+    let path = config.aof_path.clone()?;
+    match AppendLog::open(path.clone(), config.aof_fsync) {
+        Ok((log, records)) => {
+            let count = records.len();
+            for record in records {
+                match record {
+                    // The PUT holds the key's whole state, so whatever was there goes.
+                    Record::Put(entry) => restore(cache, lake, entry),
+                    Record::Del(key) => {
+                        if let Some(lake) = lake {
+                            lake.remove(&key);
+                        }
+                        let _ = cache.shard(&key).write().unwrap().delete(key);
+                    }
+                    Record::Persist(key) => {
+                        let mut shard = cache.shard(&key).write().unwrap();
+                        if let Some(lake) = lake {
+                            promote(&mut **shard, lake, &key);
+                        }
+                        shard.persist(key);
+                    }
+                }
             }
-        };
-        queue_size
+            println!("replayed {} writes from {}", count, path.display());
+            Some(Arc::new(log))
+        }
+        Err(msg) => {
+            // Appending to a log we could not read would bury whatever is wrong with it.
+            println!("not opening the append-only log. {}", msg);
+            std::process::exit(1);
+        }
     }
 }
 
 fn main() -> io::Result<()> {
-    let mut args: VecDeque<String> = env::args().collect::<VecDeque<String>>();
-    let mut port = 8000;
-    let mut queue_size = 5;
-    let mut eviction_strat = "lru";
-    if !args.is_empty() {
-        port = get_port_number(&mut args);
-        queue_size = get_queue_size(&mut args);
-        eviction_strat = if args.is_empty() {
-            println!("defaulting to lru eviction strategy");
-            "lru"
-        } else {
-            args[0].as_str()
-        };
-    }
-
-    let cache_ptr: Arc<RwLock<dyn stores::Cacheable<String> + std::marker::Send + std::marker::Sync>> = if &eviction_strat == &"lfu" {
-        println!("Using LFU eviction strategy");
-        Arc::new(RwLock::new(LFUCache::new(queue_size)))
-    } else {
-        println!("Using LRU eviction strategy");
-        Arc::new(RwLock::new(LRUCache::new(queue_size)))
-    };
-    println!("queue size: {}", queue_size);
-
-    let conn = TcpListener::bind(format!("localhost:{}", port))?;
-    println!("Listening on port {}", port);
-    for stream in conn.incoming() {
-        let mut sock = stream.unwrap().try_clone().unwrap();
-        let cache_ref = Arc::clone(&cache_ptr);
-        thread::spawn(move || {
-            handle_connection(&mut sock, cache_ref).expect("Handle connection failed.");
+    let config = Config::from_args(env::args().collect::<VecDeque<String>>());
+    let queue_size = config.queue_size;
+
+    let lake = config.lake_path.clone().map(|path| match Lake::open(path.clone(), config.lake_capacity) {
+        Ok(lake) => {
+            println!("{} keys in the lake at {}", lake.len(), path.display());
+            Arc::new(lake)
+        }
+        Err(msg) => {
+            // Spilling into a lake we could not read would bury whatever is wrong with it.
+            println!("not opening the lake. {}", msg);
+            std::process::exit(1);
+        }
+    });
+
+    let strategy = config.eviction_strategy.as_str();
+    println!("Using {} eviction strategy", strategy.to_uppercase());
+    let capacity = config.max_memory.unwrap_or(queue_size as u64);
+    let mut sharded_cache = ShardedCache::new(capacity, config.shards, |share| new_shard(&config, share));
+    if lake.is_some() {
+        stores::Cacheable::keep_evicted(&mut sharded_cache);
+    }
+    let cache_ptr: Cache = Arc::new(sharded_cache);
+    match config.max_memory {
+        Some(bytes) => println!("memory budget: {} bytes across {} shards", bytes, cache_ptr.shard_count()),
+        None => println!("queue size: {} across {} shards", queue_size, cache_ptr.shard_count()),
+    }
+
+    // Warm the cache back up before any listener starts taking traffic.
+    let snapshots = Arc::new(Snapshots::new(config.snapshot_path.clone()));
+    let log = open_log(&config, &cache_ptr, lake.as_deref());
+    if log.is_none() {
+        match snapshots.load() {
+            Ok(entries) => {
+                let count = entries.len();
+                for entry in entries {
+                    restore(&cache_ptr, lake.as_deref(), entry);
+                }
+                if count > 0 {
+                    println!("restored {} keys from {}", count, config.snapshot_path.display());
+                }
+            }
+            Err(msg) => println!("not restoring snapshot. {}", msg),
+        }
+    }
+    if let Some(log) = &log {
+        if let aof::Fsync::EverySecond = log.fsync() {
+            let log = Arc::clone(log);
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_secs(1));
+                log.sync();
+            });
+        }
+    }
+    if let Some(interval) = config.snapshot_interval {
+        let snapshot_cache = Arc::clone(&cache_ptr);
+        let periodic = Arc::clone(&snapshots);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            periodic.log_save(&*snapshot_cache);
         });
     }
-    Ok(())
+
+    let sweeper_cache = Arc::clone(&cache_ptr);
+    thread::spawn(move || loop {
+        thread::sleep(EXPIRY_SWEEP_INTERVAL);
+        let removed: u32 = sweeper_cache.shards().iter().map(|shard| shard.write().unwrap().remove_expired()).sum();
+        if removed > 0 {
+            println!("expired {} keys", removed);
+        }
+    });
+
+    let mut listeners = vec![server::Listener {
+        name: "kv-lake",
+        port: config.port,
+        new_session: Box::new(|| Box::new(protocol::native::NativeSession::new())),
+    }];
+    if let Some(resp_port) = config.resp_port {
+        listeners.push(server::Listener {
+            name: "RESP",
+            port: resp_port,
+            new_session: Box::new(|| Box::new(protocol::resp::RespSession::new())),
+        });
+    }
+    if let Some(memcached_port) = config.memcached_port {
+        listeners.push(server::Listener {
+            name: "memcached",
+            port: memcached_port,
+            new_session: Box::new(|| Box::new(protocol::memcached::MemcachedSession)),
+        });
+    }
+    let info = protocol::http::ServerInfo {
+        eviction_strategy: config.eviction_strategy.clone(),
+        started: Instant::now(),
+    };
+    // Prometheus is usually pointed at a port of its own, but both serve the same routes.
+    for (name, port) in [("HTTP", config.http_port), ("metrics", config.metrics_port)] {
+        if let Some(port) = port {
+            let info = info.clone();
+            listeners.push(server::Listener {
+                name,
+                port,
+                new_session: Box::new(move || Box::new(protocol::http::HttpSession::new(info.clone()))),
+            });
+        }
+    }
+
+    let context = Context { cache: cache_ptr, snapshots, log, lake };
+    let executor: server::Executor = Arc::new(move |command| {
+        let name = command.name();
+        let start = Instant::now();
+        let response = execute(command, &context);
+        metrics::METRICS.observe(name, start.elapsed());
+        response
+    });
+    server::run(listeners, config.workers, executor)
 }
@@ -0,0 +1,217 @@
+// Process wide counters rendered in the Prometheus text format for `GET /metrics`. Everything
+// is a relaxed atomic so recording a sample never takes a lock on the request path.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use crate::stores::Stats;
+
+// The commands that get a latency histogram, named as Command::name reports them.
+const COMMANDS: [&str; 9] = ["PUT", "GET", "DEL", "TTL", "PERSIST", "INCR", "DECR", "CAS", "STATS"];
+// Upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 12] = [0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.1, 1.0];
+
+pub static METRICS: Metrics = Metrics::new();
+
+struct Histogram {
+    // Samples that fell in each bucket alone. They are summed into Prometheus' cumulative
+    // buckets when rendered.
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|le| seconds <= *le) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct Metrics {
+    commands: [Histogram; COMMANDS.len()],
+    open_connections: AtomicU64,
+    accepted_connections: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn stat_help(name: &str) -> &'static str {
+    match name {
+        "hits" => "Lookups that found their key.",
+        "misses" => "Lookups that did not find their key.",
+        "inserts" => "Keys added to the cache.",
+        "updates" => "Writes that replaced the value of an existing key.",
+        "evictions" => "Keys evicted to make room for new ones.",
+        "expirations" => "Keys removed because their ttl ran out.",
+        "deletes" => "Keys removed by clients.",
+        _ => "",
+    }
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            commands: [const { Histogram::new() }; COMMANDS.len()],
+            open_connections: AtomicU64::new(0),
+            accepted_connections: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, command: &str, elapsed: Duration) {
+        if let Some(i) = COMMANDS.iter().position(|c| *c == command) {
+            self.commands[i].observe(elapsed);
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        self.accepted_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    // Renders everything in the Prometheus text exposition format. The cache counters are
+    // labelled with the eviction policy so dashboards can compare runs of different policies.
+    pub fn render(&self, policy: &str, stats: &Stats) -> String {
+        let mut out = String::new();
+        for (name, value) in stats.fields().iter() {
+            let metric = match *name {
+                "size" => {
+                    write_header(&mut out, "kvlake_cache_keys", "gauge", "Keys currently in the cache.");
+                    "kvlake_cache_keys".to_string()
+                }
+                "capacity" => {
+                    write_header(&mut out, "kvlake_cache_capacity_keys", "gauge", "Keys the cache holds before it evicts, or 0 when it is capped by bytes.");
+                    "kvlake_cache_capacity_keys".to_string()
+                }
+                "bytes" => {
+                    write_header(&mut out, "kvlake_cache_bytes", "gauge", "Memory taken up by the cache's entries, when it is capped by bytes.");
+                    "kvlake_cache_bytes".to_string()
+                }
+                "max_bytes" => {
+                    write_header(&mut out, "kvlake_cache_max_bytes", "gauge", "Memory the cache may take up before it evicts, when it is capped by bytes.");
+                    "kvlake_cache_max_bytes".to_string()
+                }
+                _ => {
+                    let metric = format!("kvlake_cache_{}_total", name);
+                    write_header(&mut out, &metric, "counter", stat_help(name));
+                    metric
+                }
+            };
+            let _ = writeln!(out, "{}{{policy=\"{}\"}} {}", metric, policy, value);
+        }
+
+        write_header(&mut out, "kvlake_command_duration_seconds", "histogram", "Time taken to execute commands against the cache.");
+        for (command, histogram) in COMMANDS.iter().zip(self.commands.iter()) {
+            let mut cumulative = 0;
+            for (le, bucket) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(out, "kvlake_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}", command, le, cumulative);
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(out, "kvlake_command_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}", command, count);
+            let _ = writeln!(out, "kvlake_command_duration_seconds_sum{{command=\"{}\"}} {}", command, sum);
+            let _ = writeln!(out, "kvlake_command_duration_seconds_count{{command=\"{}\"}} {}", command, count);
+        }
+
+        let connections: [(&str, &str, &str, &AtomicU64); 4] = [
+            ("kvlake_connections_open", "gauge", "Client connections currently open.", &self.open_connections),
+            ("kvlake_connections_accepted_total", "counter", "Client connections accepted.", &self.accepted_connections),
+            ("kvlake_network_received_bytes_total", "counter", "Bytes read from clients.", &self.bytes_received),
+            ("kvlake_network_sent_bytes_total", "counter", "Bytes written to clients.", &self.bytes_sent),
+        ];
+        for (name, kind, help, value) in connections.iter() {
+            write_header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::Metrics;
+    use crate::stores::Stats;
+
+    fn lines_starting(out: &str, prefix: &str) -> Vec<String> {
+        out.lines().filter(|line| line.starts_with(prefix)).map(|line| line.to_string()).collect()
+    }
+
+    // Each cache metric has its HELP and TYPE lines first and carries the policy as a label.
+    #[test]
+    fn cache_stats_are_labelled_with_the_policy() {
+        let stats = Stats { hits: 3, misses: 1, size: 2, capacity: 8, ..Stats::default() };
+        let out = Metrics::new().render("lru", &stats);
+        assert!(out.contains("# HELP kvlake_cache_hits_total Lookups that found their key.\n# TYPE kvlake_cache_hits_total counter\nkvlake_cache_hits_total{policy=\"lru\"} 3\n"));
+        assert!(out.contains("# TYPE kvlake_cache_keys gauge\nkvlake_cache_keys{policy=\"lru\"} 2\n"));
+        assert!(out.contains("kvlake_cache_misses_total{policy=\"lru\"} 1\n"));
+        assert!(out.contains("kvlake_cache_capacity_keys{policy=\"lru\"} 8\n"));
+        assert!(out.contains("# TYPE kvlake_connections_open gauge\nkvlake_connections_open 0\n"));
+
+        // Every metric named outside of the comments has a HELP and a TYPE line.
+        for line in out.lines().filter(|line| !line.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            let family = ["_bucket", "_sum", "_count"].iter().fold(name, |name, suffix| name.strip_suffix(suffix).unwrap_or(name));
+            assert!(out.contains(&format!("# HELP {} ", family)), "no HELP for {}", name);
+            assert!(out.contains(&format!("# TYPE {} ", family)), "no TYPE for {}", name);
+        }
+    }
+
+    // Each bucket counts every sample up to its bound, so they only ever go up, and a sample
+    // slower than the last bound only shows up in +Inf.
+    #[test]
+    fn histogram_buckets_are_cumulative_and_end_in_inf() {
+        let metrics = Metrics::new();
+        for micros in [5, 30, 2_000, 2_000_000] {
+            metrics.observe("GET", Duration::from_micros(micros));
+        }
+        let out = metrics.render("lru", &Stats::default());
+        assert!(out.contains("# TYPE kvlake_command_duration_seconds histogram\n"));
+        let buckets = lines_starting(&out, "kvlake_command_duration_seconds_bucket{command=\"GET\"");
+        assert_eq!(
+            buckets,
+            [
+                ("0.00001", 1), ("0.000025", 1), ("0.00005", 2), ("0.0001", 2), ("0.00025", 2), ("0.0005", 2),
+                ("0.001", 2), ("0.0025", 3), ("0.005", 3), ("0.01", 3), ("0.1", 3), ("1", 3), ("+Inf", 4),
+            ]
+            .iter()
+            .map(|(le, count)| format!("kvlake_command_duration_seconds_bucket{{command=\"GET\",le=\"{}\"}} {}", le, count))
+            .collect::<Vec<_>>()
+        );
+        assert!(out.contains("kvlake_command_duration_seconds_sum{command=\"GET\"} 2.002035\n"));
+        assert!(out.contains("kvlake_command_duration_seconds_count{command=\"GET\"} 4\n"));
+        // Commands with no samples still list every bucket, all at 0.
+        assert!(out.contains("kvlake_command_duration_seconds_bucket{command=\"PUT\",le=\"+Inf\"} 0\n"));
+    }
+}
@@ -0,0 +1,230 @@
+// Version 2 framing. Every request is a 4 byte big-endian length followed by that many bytes:
+// an opcode and then its arguments, each itself prefixed with a 4 byte big-endian length.
+// Responses are a 4 byte big-endian length, a status byte and the payload: the value for
+// STATUS_VALUE, a big-endian i64 for STATUS_INTEGER, an error code byte followed by the
+// message for STATUS_ERROR, a list of counters for STATUS_STATS and nothing otherwise.
+use std::time::Duration;
+use crate::stores::Condition;
+use super::{valid_expiry, Command, ErrorCode, Response, Value};
+
+// Guards against a bogus length making us allocate gigabytes before reading a single byte.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+pub const OP_GET: u8 = 1;
+pub const OP_PUT: u8 = 2;
+pub const OP_DEL: u8 = 3;
+pub const OP_TTL: u8 = 4;
+pub const OP_PERSIST: u8 = 5;
+pub const OP_QUIT: u8 = 6;
+pub const OP_STATS: u8 = 7;
+pub const OP_SAVE: u8 = 8;
+pub const OP_BGSAVE: u8 = 9;
+pub const OP_BGREWRITEAOF: u8 = 10;
+// PUT, but only if the key is missing or only if it is there.
+pub const OP_ADD: u8 = 11;
+pub const OP_REPLACE: u8 = 12;
+
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_ERROR: u8 = 1;
+pub const STATUS_VALUE: u8 = 2;
+pub const STATUS_NOT_FOUND: u8 = 3;
+pub const STATUS_INTEGER: u8 = 4;
+pub const STATUS_STATS: u8 = 5;
+pub const STATUS_NOT_STORED: u8 = 6;
+
+type Parsed = Option<(Result<Command, Response>, usize)>;
+
+// Pulls one request off the front of `buf` along with the number of bytes it took up, or None
+// if it hasn't fully arrived. Errors mean the stream can't be resynchronised.
+pub fn parse_frame(buf: &[u8]) -> Result<Parsed, String> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    if len == 0 || len > MAX_FRAME_LEN {
+        return Err(format!("invalid frame length {}", len));
+    }
+    let end = 4 + len as usize;
+    if buf.len() < end {
+        return Ok(None);
+    }
+    Ok(Some((parse_request(&buf[4..end]), end)))
+}
+
+fn write_frame(out: &mut Vec<u8>, status: u8, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+    out.push(status);
+    out.extend_from_slice(payload);
+}
+
+pub fn write_response(out: &mut Vec<u8>, response: &Response) {
+    match response {
+        Response::Ok => write_frame(out, STATUS_OK, &[]),
+        Response::Value(value) => write_frame(out, STATUS_VALUE, &value.data),
+        Response::Integer(i) => write_frame(out, STATUS_INTEGER, &i.to_be_bytes()),
+        Response::NotFound => write_frame(out, STATUS_NOT_FOUND, &[]),
+        Response::NotStored => write_frame(out, STATUS_NOT_STORED, &[]),
+        Response::Error(code, message) => {
+            let mut payload = vec![*code as u8];
+            payload.extend_from_slice(message.as_bytes());
+            write_frame(out, STATUS_ERROR, &payload)
+        }
+        // Each counter is its length prefixed name followed by a big-endian u64.
+        Response::Stats(stats) => {
+            let mut payload = Vec::new();
+            for (name, value) in stats.fields().iter() {
+                payload.extend_from_slice(&(name.len() as u32).to_be_bytes());
+                payload.extend_from_slice(name.as_bytes());
+                payload.extend_from_slice(&value.to_be_bytes());
+            }
+            write_frame(out, STATUS_STATS, &payload)
+        }
+    }
+}
+
+fn next_arg(args: &mut &[u8]) -> Result<Option<Vec<u8>>, Response> {
+    if args.is_empty() {
+        return Ok(None);
+    }
+    if args.len() < 4 {
+        return Err(Response::error(ErrorCode::InvalidArgument, "truncated argument length"));
+    }
+    let len = u32::from_be_bytes([args[0], args[1], args[2], args[3]]) as usize;
+    if args.len() - 4 < len {
+        return Err(Response::error(ErrorCode::InvalidArgument, "truncated argument"));
+    }
+    let arg = args[4..4 + len].to_vec();
+    *args = &args[4 + len..];
+    Ok(Some(arg))
+}
+
+fn required_arg(args: &mut &[u8]) -> Result<Vec<u8>, Response> {
+    next_arg(args)?.ok_or_else(|| Response::error(ErrorCode::InvalidArgument, "missing argument"))
+}
+
+// The optional PUT expiry is a big-endian u64 of milliseconds.
+fn get_expiry(raw: Option<Vec<u8>>) -> Result<Option<Duration>, Response> {
+    match raw {
+        Some(raw) => {
+            if raw.len() != 8 {
+                return Err(Response::error(ErrorCode::InvalidArgument, "invalid expire time"));
+            }
+            let mut millis = [0; 8];
+            millis.copy_from_slice(&raw);
+            match u64::from_be_bytes(millis) {
+                0 => Err(Response::error(ErrorCode::InvalidArgument, "invalid expire time")),
+                m => valid_expiry(Duration::from_millis(m))
+                    .map(Some)
+                    .ok_or_else(|| Response::error(ErrorCode::InvalidArgument, "invalid expire time")),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+fn parse_request(body: &[u8]) -> Result<Command, Response> {
+    let opcode = body[0];
+    let mut args = &body[1..];
+    let command = match opcode {
+        OP_GET => Command::GET(required_arg(&mut args)?),
+        OP_PUT | OP_ADD | OP_REPLACE => {
+            let key = required_arg(&mut args)?;
+            let value = required_arg(&mut args)?;
+            let ttl = get_expiry(next_arg(&mut args)?)?;
+            let condition = match opcode {
+                OP_ADD => Condition::IfAbsent,
+                OP_REPLACE => Condition::IfPresent,
+                _ => Condition::Always,
+            };
+            Command::PUT(key, Value::new(value), ttl, condition)
+        }
+        OP_DEL => Command::DEL(required_arg(&mut args)?),
+        OP_TTL => Command::TTL(required_arg(&mut args)?),
+        OP_PERSIST => Command::PERSIST(required_arg(&mut args)?),
+        OP_QUIT => Command::QUIT,
+        OP_STATS => Command::STATS,
+        OP_SAVE => Command::SAVE,
+        OP_BGSAVE => Command::BGSAVE,
+        OP_BGREWRITEAOF => Command::BGREWRITEAOF,
+        _ => return Err(Response::error(ErrorCode::UnknownCommand, "unknown command")),
+    };
+    if !args.is_empty() {
+        return Err(Response::error(ErrorCode::InvalidArgument, "too many arguments"));
+    }
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{parse_frame, parse_request, MAX_FRAME_LEN, OP_GET, OP_PUT};
+    use crate::protocol::{Command, Response};
+    use crate::stores::Condition;
+
+    // A request body: the opcode and each argument with its length.
+    fn body(opcode: u8, args: &[&[u8]]) -> Vec<u8> {
+        let mut body = vec![opcode];
+        for arg in args {
+            body.extend_from_slice(&(arg.len() as u32).to_be_bytes());
+            body.extend_from_slice(arg);
+        }
+        body
+    }
+
+    // The code and message a request is turned down with.
+    fn rejection(body: &[u8]) -> String {
+        match parse_request(body) {
+            Err(Response::Error(code, message)) => format!("{} {}", code as u8, message),
+            Err(_) => panic!("rejected without an error"),
+            Ok(command) => panic!("parsed as {}", command.name()),
+        }
+    }
+
+    // Neither can be skipped over safely, so the connection is dropped.
+    #[test]
+    fn empty_and_oversized_frames_are_rejected() {
+        assert_eq!(parse_frame(&[0, 0, 0, 0, OP_GET]).err(), Some("invalid frame length 0".to_string()));
+        let too_long = (MAX_FRAME_LEN + 1).to_be_bytes();
+        assert_eq!(parse_frame(&too_long).err(), Some(format!("invalid frame length {}", MAX_FRAME_LEN + 1)));
+        assert!(matches!(parse_frame(&MAX_FRAME_LEN.to_be_bytes()), Ok(None)));
+    }
+
+    #[test]
+    fn frames_wait_for_the_rest_of_their_body() {
+        let mut framed = 6u32.to_be_bytes().to_vec();
+        framed.extend_from_slice(&body(OP_GET, &[b"k"]));
+        assert!(matches!(parse_frame(&framed[..framed.len() - 1]), Ok(None)));
+        assert!(matches!(parse_frame(&framed), Ok(Some((Ok(Command::GET(_)), 10)))));
+    }
+
+    #[test]
+    fn truncated_arguments_are_invalid() {
+        assert_eq!(rejection(&[OP_GET, 0, 0, 0]), "2 truncated argument length");
+        assert_eq!(rejection(&[OP_GET, 0, 0, 0, 2, b'k']), "2 truncated argument");
+    }
+
+    #[test]
+    fn missing_and_extra_arguments_are_invalid() {
+        assert_eq!(rejection(&body(OP_PUT, &[b"k"])), "2 missing argument");
+        assert_eq!(rejection(&body(OP_GET, &[b"k", b"extra"])), "2 too many arguments");
+        assert_eq!(rejection(&body(OP_PUT, &[b"k", b"v", &1000u64.to_be_bytes(), b"extra"])), "2 too many arguments");
+        assert_eq!(rejection(&body(99, &[])), "1 unknown command");
+    }
+
+    // The third PUT argument, if there is one, is how many milliseconds the key lives for.
+    #[test]
+    fn put_takes_an_optional_expiry_in_milliseconds() {
+        match parse_request(&body(OP_PUT, &[b"k", b"v", &1500u64.to_be_bytes()])) {
+            Ok(Command::PUT(key, value, ttl, condition)) => {
+                assert_eq!(key, b"k");
+                assert_eq!(value.data, b"v");
+                assert_eq!(ttl, Some(Duration::from_millis(1500)));
+                assert!(condition == Condition::Always);
+            }
+            _ => panic!("not a PUT"),
+        }
+        assert!(matches!(parse_request(&body(OP_PUT, &[b"k", b"v"])), Ok(Command::PUT(_, _, None, _))));
+        assert_eq!(rejection(&body(OP_PUT, &[b"k", b"v", &0u64.to_be_bytes()])), "2 invalid expire time");
+        assert_eq!(rejection(&body(OP_PUT, &[b"k", b"v", &1500u32.to_be_bytes()])), "2 invalid expire time");
+    }
+}
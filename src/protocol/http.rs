@@ -0,0 +1,291 @@
+// A small HTTP/1.1 front end so curl, browsers, health checkers and load balancers can reach
+// the cache directly:
+//   GET    /keys/{key}             the value, or 404
+//   PUT    /keys/{key}[?ex=|?px=]  stores the request body, optionally expiring it. With
+//                                  `If-None-Match: *` only if the key is missing and with
+//                                  `If-Match: *` only if it is there, or 412
+//   DELETE /keys/{key}             204, or 404
+//   GET    /stats                  a JSON summary of the server and its cache counters
+//   GET    /metrics                the same counters and more in the Prometheus text format
+use std::time::{Duration, Instant};
+use crate::metrics::METRICS;
+use crate::stores::{Condition, Stats};
+use super::{valid_expiry, Command, ErrorCode, Response, Session, Value};
+
+const MAX_HEAD_LEN: usize = 8 * 1024;
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+pub struct Request {
+    method: String,
+    path: String,
+    query: String,
+    keep_alive: bool,
+    condition: Condition,
+    body: Vec<u8>,
+}
+
+// What /stats reports about the server as a whole.
+#[derive(Clone)]
+pub struct ServerInfo {
+    pub eviction_strategy: String,
+    pub started: Instant,
+}
+
+fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn percent_decode(raw: &str) -> Result<Vec<u8>, String> {
+    let raw = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(raw.len());
+    let mut i = 0;
+    while i < raw.len() {
+        if raw[i] == b'%' {
+            match (raw.get(i + 1).and_then(|b| hex_value(*b)), raw.get(i + 2).and_then(|b| hex_value(*b))) {
+                (Some(hi), Some(lo)) => decoded.push(hi << 4 | lo),
+                _ => return Err("invalid percent encoding".to_string()),
+            }
+            i += 3;
+        } else {
+            decoded.push(raw[i]);
+            i += 1;
+        }
+    }
+    Ok(decoded)
+}
+
+// Query parameters other than `ex` and `px` are left alone, whatever their values.
+fn get_expiry(query: &str) -> Result<Option<Duration>, String> {
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let unit = match name {
+            "ex" => Duration::from_secs,
+            "px" => Duration::from_millis,
+            _ => continue,
+        };
+        return match value.parse::<u64>() {
+            Ok(amount) if amount > 0 => valid_expiry(unit(amount)).map(Some).ok_or_else(|| "invalid expire time".to_string()),
+            _ => Err("invalid expire time".to_string()),
+        };
+    }
+    Ok(None)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        _ => "",
+    }
+}
+
+fn write_response(out: &mut Vec<u8>, status: u16, headers: &[(&str, &str)], body: &[u8], keep_alive: bool) {
+    out.extend_from_slice(format!("HTTP/1.1 {} {}\r\n", status, reason(status)).as_bytes());
+    for (name, value) in headers {
+        out.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    if !keep_alive {
+        out.extend_from_slice(b"Connection: close\r\n");
+    }
+    // A 204 must not carry a Content-Length.
+    if status != 204 {
+        out.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+    }
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(body);
+}
+
+fn write_text(out: &mut Vec<u8>, status: u16, message: &str, keep_alive: bool) {
+    let body = format!("{}\n", message);
+    write_response(out, status, &[("Content-Type", "text/plain")], body.as_bytes(), keep_alive);
+}
+
+pub struct HttpSession {
+    info: ServerInfo,
+}
+
+impl HttpSession {
+    pub fn new(info: ServerInfo) -> HttpSession {
+        HttpSession { info }
+    }
+
+    fn write_stats(&self, out: &mut Vec<u8>, stats: &Stats, keep_alive: bool) {
+        let mut body = format!(
+            "{{\"eviction_strategy\":\"{}\",\"uptime_seconds\":{}",
+            self.info.eviction_strategy,
+            self.info.started.elapsed().as_secs()
+        );
+        for (name, value) in stats.fields().iter() {
+            body.push_str(&format!(",\"{}\":{}", name, value));
+        }
+        body.push_str("}\n");
+        write_response(out, 200, &[("Content-Type", "application/json")], body.as_bytes(), keep_alive);
+    }
+
+    fn write_cache_response(&self, out: &mut Vec<u8>, response: Response, keep_alive: bool) {
+        match response {
+            Response::Ok => write_response(out, 204, &[], &[], keep_alive),
+            Response::Value(value) => {
+                write_response(out, 200, &[("Content-Type", "application/octet-stream")], &value.data, keep_alive)
+            }
+            Response::Integer(i) => write_text(out, 200, &i.to_string(), keep_alive),
+            Response::NotFound => write_text(out, 404, "key not found", keep_alive),
+            Response::NotStored => write_text(out, 412, "precondition failed", keep_alive),
+            Response::Error(ErrorCode::TooLarge, message) => write_text(out, 413, &message, keep_alive),
+            Response::Error(_, message) => write_text(out, 400, &message, keep_alive),
+            Response::Stats(stats) => self.write_stats(out, &stats, keep_alive),
+        }
+    }
+}
+
+impl Session for HttpSession {
+    type Request = Request;
+
+    fn parse_request(&self, buf: &[u8]) -> Result<Option<(Request, usize)>, String> {
+        let head_end = match find_head_end(buf) {
+            Some(end) => end,
+            None if buf.len() > MAX_HEAD_LEN => return Err("request head too large".to_string()),
+            None => return Ok(None),
+        };
+        let head = std::str::from_utf8(&buf[..head_end]).map_err(|_| "request head is not valid utf-8".to_string())?;
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or("").split(' ');
+        let (method, target, version) = match (request_line.next(), request_line.next(), request_line.next()) {
+            (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/1.") => (m, t, v),
+            _ => return Err("malformed request line".to_string()),
+        };
+        let mut keep_alive = version != "HTTP/1.0";
+        let mut content_length = 0;
+        let mut condition = Condition::Always;
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or_else(|| "malformed header".to_string())?;
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => {
+                    content_length = value.parse::<usize>().map_err(|_| "invalid content-length".to_string())?;
+                    if content_length > MAX_BODY_LEN {
+                        return Err("request body too large".to_string());
+                    }
+                }
+                "transfer-encoding" => return Err("transfer-encoding is not supported".to_string()),
+                // Only the `*` form is understood, since values carry no entity tags.
+                "if-none-match" if value == "*" => condition = Condition::IfAbsent,
+                "if-match" if value == "*" => condition = Condition::IfPresent,
+                "connection" => keep_alive = match value.to_ascii_lowercase().as_str() {
+                    "close" => false,
+                    "keep-alive" => true,
+                    _ => keep_alive,
+                },
+                _ => {}
+            }
+        }
+        let body_start = head_end + 4;
+        if buf.len() < body_start + content_length {
+            return Ok(None);
+        }
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let request = Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            keep_alive,
+            condition,
+            body: buf[body_start..body_start + content_length].to_vec(),
+        };
+        Ok(Some((request, body_start + content_length)))
+    }
+
+    fn write_protocol_error(&self, message: &str, out: &mut Vec<u8>) {
+        write_text(out, 400, message, false);
+    }
+
+    fn handle(&mut self, request: Request, execute: &mut dyn FnMut(Command) -> Response, out: &mut Vec<u8>) -> bool {
+        let keep_alive = request.keep_alive;
+        if request.path == "/stats" || request.path == "/metrics" {
+            if request.method != "GET" {
+                write_response(out, 405, &[("Allow", "GET")], &[], keep_alive);
+                return keep_alive;
+            }
+            let response = execute(Command::STATS);
+            match response {
+                Response::Stats(stats) if request.path == "/metrics" => {
+                    let body = METRICS.render(&self.info.eviction_strategy, &stats);
+                    write_response(out, 200, &[("Content-Type", "text/plain; version=0.0.4")], body.as_bytes(), keep_alive);
+                }
+                response => self.write_cache_response(out, response, keep_alive),
+            }
+            return keep_alive;
+        }
+        let key = match request.path.strip_prefix("/keys/") {
+            Some(raw) if !raw.is_empty() => match percent_decode(raw) {
+                Ok(key) => key,
+                Err(message) => {
+                    write_text(out, 400, &message, keep_alive);
+                    return keep_alive;
+                }
+            },
+            _ => {
+                write_text(out, 404, "no such resource", keep_alive);
+                return keep_alive;
+            }
+        };
+        let command = match request.method.as_str() {
+            "GET" => Command::GET(key),
+            "DELETE" => Command::DEL(key),
+            "PUT" => match get_expiry(&request.query) {
+                Ok(ttl) => Command::PUT(key, Value::new(request.body), ttl, request.condition),
+                Err(message) => {
+                    write_text(out, 400, &message, keep_alive);
+                    return keep_alive;
+                }
+            },
+            _ => {
+                write_response(out, 405, &[("Allow", "GET, PUT, DELETE")], &[], keep_alive);
+                return keep_alive;
+            }
+        };
+        let response = execute(command);
+        self.write_cache_response(out, response, keep_alive);
+        keep_alive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::get_expiry;
+
+    #[test]
+    fn expiry_comes_from_ex_or_px() {
+        assert_eq!(get_expiry(""), Ok(None));
+        assert_eq!(get_expiry("ex=10"), Ok(Some(Duration::from_secs(10))));
+        assert_eq!(get_expiry("px=250"), Ok(Some(Duration::from_millis(250))));
+    }
+
+    #[test]
+    fn other_parameters_are_ignored() {
+        assert_eq!(get_expiry("v=abc&ex=10"), Ok(Some(Duration::from_secs(10))));
+        assert_eq!(get_expiry("cachebuster&token=-1"), Ok(None));
+    }
+
+    #[test]
+    fn invalid_expiries_are_rejected() {
+        for query in ["ex=0", "ex=soon", "px=", "ex", "ex=18446744073709551615"] {
+            assert_eq!(get_expiry(query), Err("invalid expire time".to_string()));
+        }
+    }
+}
@@ -0,0 +1,367 @@
+// The memcached ASCII protocol, so services written against memcached can use the cache as a
+// drop in replacement. Supports get/gets, set, add, replace, cas, delete, incr/decr, stats,
+// version and quit.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::stores::Condition;
+use super::{Command, ErrorCode, Response, Session, Value};
+
+const MAX_LINE_LEN: usize = 8 * 1024;
+const MAX_KEY_LEN: usize = 250;
+const MAX_ITEM_SIZE: usize = 64 * 1024 * 1024;
+// Expiry times larger than this are unix timestamps rather than a number of seconds.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+const STORAGE_COMMANDS: [&[u8]; 6] = [b"set", b"add", b"replace", b"append", b"prepend", b"cas"];
+
+pub struct Request {
+    args: Vec<Vec<u8>>,
+    // The data block that follows a storage command's line.
+    data: Option<Vec<u8>>,
+}
+
+enum Expiry {
+    Never,
+    In(Duration),
+    Expired,
+}
+
+fn get_expiry(exptime: i64) -> Expiry {
+    if exptime == 0 {
+        return Expiry::Never;
+    }
+    if exptime < 0 {
+        return Expiry::Expired;
+    }
+    if exptime <= MAX_RELATIVE_EXPTIME {
+        return Expiry::In(Duration::from_secs(exptime as u64));
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    if exptime as u64 <= now {
+        Expiry::Expired
+    } else {
+        Expiry::In(Duration::from_secs(exptime as u64 - now))
+    }
+}
+
+fn parse_number<N: std::str::FromStr>(raw: &[u8]) -> Option<N> {
+    std::str::from_utf8(raw).ok().and_then(|s| s.parse::<N>().ok())
+}
+
+fn write_line(out: &mut Vec<u8>, line: &str) {
+    out.extend_from_slice(line.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+fn write_value(out: &mut Vec<u8>, key: &[u8], value: &Value, with_cas: bool) {
+    out.extend_from_slice(b"VALUE ");
+    out.extend_from_slice(key);
+    if with_cas {
+        out.extend_from_slice(format!(" {} {} {}\r\n", value.flags, value.data.len(), value.cas).as_bytes());
+    } else {
+        out.extend_from_slice(format!(" {} {}\r\n", value.flags, value.data.len()).as_bytes());
+    }
+    out.extend_from_slice(&value.data);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn write_error(out: &mut Vec<u8>, response: &Response) {
+    match response {
+        Response::Error(ErrorCode::UnknownCommand, _) => write_line(out, "ERROR"),
+        Response::Error(ErrorCode::TooLarge, _) => write_line(out, "SERVER_ERROR object too large for cache"),
+        Response::Error(_, message) => write_line(out, &format!("CLIENT_ERROR {}", message)),
+        _ => write_line(out, "SERVER_ERROR unexpected response"),
+    }
+}
+
+fn bad_format() -> Response {
+    Response::error(ErrorCode::InvalidArgument, "bad command line format")
+}
+
+pub struct MemcachedSession;
+
+impl MemcachedSession {
+    // `<command> <key> <flags> <exptime> <bytes> [<cas unique>] [noreply]` plus the data block.
+    fn store(&self, args: &[Vec<u8>], data: Vec<u8>, execute: &mut dyn FnMut(Command) -> Response, out: &mut Vec<u8>) {
+        let is_cas = args[0] == b"cas";
+        let expected_len = if is_cas { 6 } else { 5 };
+        if args.len() < expected_len || args[1].len() > MAX_KEY_LEN {
+            return write_error(out, &bad_format());
+        }
+        let (flags, exptime) = match (parse_number::<u32>(&args[2]), parse_number::<i64>(&args[3])) {
+            (Some(flags), Some(exptime)) => (flags, exptime),
+            _ => return write_error(out, &bad_format()),
+        };
+        let key = args[1].clone();
+        let mut value = Value::new(data);
+        value.flags = flags;
+        let expiry = get_expiry(exptime);
+        let ttl = match expiry {
+            Expiry::In(ttl) => Some(ttl),
+            _ => None,
+        };
+        let response = if is_cas {
+            match parse_number::<u64>(&args[5]) {
+                Some(unique) => execute(Command::CAS(key.clone(), value, ttl, unique)),
+                None => return write_error(out, &bad_format()),
+            }
+        } else if args[0] == b"set" {
+            execute(Command::PUT(key.clone(), value, ttl, Condition::Always))
+        } else if args[0] == b"add" {
+            execute(Command::PUT(key.clone(), value, ttl, Condition::IfAbsent))
+        } else if args[0] == b"replace" {
+            execute(Command::PUT(key.clone(), value, ttl, Condition::IfPresent))
+        } else {
+            let command = String::from_utf8_lossy(&args[0]);
+            return write_line(out, &format!("CLIENT_ERROR {} is not supported", command));
+        };
+        match response {
+            Response::Ok => {
+                // memcached accepts an expiry in the past and the item simply never shows up.
+                if let Expiry::Expired = expiry {
+                    execute(Command::DEL(key));
+                }
+                write_line(out, "STORED")
+            }
+            Response::NotFound => write_line(out, "NOT_FOUND"),
+            Response::NotStored => write_line(out, "NOT_STORED"),
+            Response::Error(ErrorCode::Conflict, _) => write_line(out, "EXISTS"),
+            other => write_error(out, &other),
+        }
+    }
+
+    fn retrieve(&self, args: &[Vec<u8>], execute: &mut dyn FnMut(Command) -> Response, out: &mut Vec<u8>) {
+        if args.len() < 2 {
+            return write_line(out, "ERROR");
+        }
+        let with_cas = args[0] == b"gets";
+        for key in &args[1..] {
+            if let Response::Value(value) = execute(Command::GET(key.clone())) {
+                write_value(out, key, &value, with_cas);
+            }
+        }
+        write_line(out, "END");
+    }
+
+    fn delete(&self, args: &[Vec<u8>], execute: &mut dyn FnMut(Command) -> Response, out: &mut Vec<u8>) {
+        if args.len() != 2 {
+            return write_error(out, &bad_format());
+        }
+        match execute(Command::DEL(args[1].clone())) {
+            Response::Ok => write_line(out, "DELETED"),
+            Response::NotFound => write_line(out, "NOT_FOUND"),
+            other => write_error(out, &other),
+        }
+    }
+
+    fn stats(&self, execute: &mut dyn FnMut(Command) -> Response, out: &mut Vec<u8>) {
+        match execute(Command::STATS) {
+            Response::Stats(stats) => {
+                for (name, value) in stats.fields().iter() {
+                    write_line(out, &format!("STAT {} {}", name, value));
+                }
+                write_line(out, "END");
+            }
+            other => write_error(out, &other),
+        }
+    }
+
+    fn incr_decr(&self, args: &[Vec<u8>], execute: &mut dyn FnMut(Command) -> Response, out: &mut Vec<u8>) {
+        if args.len() != 3 {
+            return write_line(out, "ERROR");
+        }
+        let delta = match parse_number::<u64>(&args[2]) {
+            Some(delta) => delta,
+            None => return write_line(out, "CLIENT_ERROR invalid numeric delta argument"),
+        };
+        let key = args[1].clone();
+        let command = if args[0] == b"incr" { Command::INCR(key, delta) } else { Command::DECR(key, delta) };
+        match execute(command) {
+            Response::Value(value) => {
+                out.extend_from_slice(&value.data);
+                out.extend_from_slice(b"\r\n");
+            }
+            Response::NotFound => write_line(out, "NOT_FOUND"),
+            other => write_error(out, &other),
+        }
+    }
+}
+
+impl Session for MemcachedSession {
+    type Request = Request;
+
+    fn parse_request(&self, buf: &[u8]) -> Result<Option<(Request, usize)>, String> {
+        let line_end = match buf.iter().position(|b| *b == b'\n') {
+            Some(end) => end,
+            None if buf.len() > MAX_LINE_LEN => return Err("line too long".to_string()),
+            None => return Ok(None),
+        };
+        let line = &buf[..line_end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let args: Vec<Vec<u8>> = line
+            .split(|b| *b == b' ')
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        let is_storage = args.first().map(|c| STORAGE_COMMANDS.contains(&c.as_slice())).unwrap_or(false);
+        let data_len = if is_storage { args.get(4).and_then(|b| parse_number::<usize>(b)) } else { None };
+        let data_start = line_end + 1;
+        match data_len {
+            Some(len) => {
+                if len > MAX_ITEM_SIZE {
+                    return Err("object too large for cache".to_string());
+                }
+                if buf.len() < data_start + len + 2 {
+                    return Ok(None);
+                }
+                if &buf[data_start + len..data_start + len + 2] != b"\r\n" {
+                    return Err("bad data chunk".to_string());
+                }
+                let data = buf[data_start..data_start + len].to_vec();
+                Ok(Some((Request { args, data: Some(data) }, data_start + len + 2)))
+            }
+            None => Ok(Some((Request { args, data: None }, data_start))),
+        }
+    }
+
+    fn write_protocol_error(&self, message: &str, out: &mut Vec<u8>) {
+        write_line(out, &format!("CLIENT_ERROR {}", message));
+    }
+
+    fn handle(&mut self, request: Request, execute: &mut dyn FnMut(Command) -> Response, out: &mut Vec<u8>) -> bool {
+        let mut args = request.args;
+        if args.is_empty() {
+            write_line(out, "ERROR");
+            return true;
+        }
+        let noreply = args.len() > 1 && args[args.len() - 1] == b"noreply";
+        if noreply {
+            args.pop();
+        }
+        let mut reply = Vec::new();
+        match args[0].as_slice() {
+            b"get" | b"gets" => self.retrieve(&args, execute, &mut reply),
+            b"delete" => self.delete(&args, execute, &mut reply),
+            b"incr" | b"decr" => self.incr_decr(&args, execute, &mut reply),
+            b"stats" => self.stats(execute, &mut reply),
+            b"version" => write_line(&mut reply, &format!("VERSION {}", env!("CARGO_PKG_VERSION"))),
+            b"quit" => return false,
+            _ => match request.data {
+                Some(data) => self.store(&args, data, execute, &mut reply),
+                None if STORAGE_COMMANDS.contains(&args[0].as_slice()) => write_error(&mut reply, &bad_format()),
+                None => write_line(&mut reply, "ERROR"),
+            },
+        }
+        if !noreply {
+            out.extend_from_slice(&reply);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use super::MemcachedSession;
+    use crate::protocol::{process, Command, Response};
+    use crate::{execute, Context};
+
+    // Sends `input` through a session to a cache and returns everything written back.
+    fn round_trip(context: &Context, input: &str) -> String {
+        let mut input = input.as_bytes().to_vec();
+        let mut out = Vec::new();
+        assert!(process(&mut MemcachedSession, &mut input, &mut |command| execute(command, context), &mut out));
+        assert!(input.is_empty());
+        String::from_utf8(out).unwrap()
+    }
+
+    fn ttl(context: &Context, key: &str) -> i64 {
+        match execute(Command::TTL(key.as_bytes().to_vec()), context) {
+            Response::Integer(ttl) => ttl,
+            _ => panic!("TTL did not reply with an integer"),
+        }
+    }
+
+    // The cas unique `gets` reports for a key.
+    fn cas_unique(context: &Context, key: &str) -> u64 {
+        let reply = round_trip(context, &format!("gets {}\r\n", key));
+        reply.split("\r\n").next().unwrap().rsplit(' ').next().unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn add_and_replace_depend_on_the_key_being_there() {
+        let context = Context::in_memory(16);
+        assert_eq!(
+            round_trip(&context, "replace k 0 0 1\r\na\r\nadd k 0 0 1\r\nb\r\nadd k 0 0 1\r\nc\r\nset k 0 0 1\r\nd\r\nreplace k 0 0 1\r\ne\r\nget k\r\n"),
+            "NOT_STORED\r\nSTORED\r\nNOT_STORED\r\nSTORED\r\nSTORED\r\nVALUE k 0 1\r\ne\r\nEND\r\n"
+        );
+    }
+
+    #[test]
+    fn cas_only_stores_over_the_value_it_was_read_from() {
+        let context = Context::in_memory(16);
+        assert_eq!(round_trip(&context, "cas k 0 0 1 1\r\na\r\nset k 0 0 1\r\nb\r\n"), "NOT_FOUND\r\nSTORED\r\n");
+        let unique = cas_unique(&context, "k");
+        assert_eq!(round_trip(&context, &format!("cas k 0 0 1 {}\r\nc\r\n", unique + 1)), "EXISTS\r\n");
+        assert_eq!(round_trip(&context, &format!("cas k 0 0 1 {}\r\nd\r\n", unique)), "STORED\r\n");
+        // Every write gives the key a new cas unique, so the same one can't be used twice.
+        assert_eq!(round_trip(&context, &format!("cas k 0 0 1 {}\r\ne\r\n", unique)), "EXISTS\r\n");
+        assert!(cas_unique(&context, "k") > unique);
+        assert_eq!(round_trip(&context, "get k\r\n"), "VALUE k 0 1\r\nd\r\nEND\r\n");
+    }
+
+    // Incrementing wraps around at 2^64 and decrementing stops at 0, as memcached does.
+    #[test]
+    fn incr_and_decr_work_on_numbers_only() {
+        let context = Context::in_memory(16);
+        assert_eq!(
+            round_trip(&context, "set n 0 0 2\r\n10\r\nincr n 5\r\ndecr n 20\r\nset max 0 0 20\r\n18446744073709551615\r\nincr max 2\r\n"),
+            "STORED\r\n15\r\n0\r\nSTORED\r\n1\r\n"
+        );
+        assert_eq!(
+            round_trip(&context, "set s 0 0 3\r\nabc\r\nincr s 1\r\ndecr s 1\r\nincr missing 1\r\nincr n x\r\nincr n\r\n"),
+            "STORED\r\n\
+             CLIENT_ERROR cannot increment or decrement non-numeric value\r\n\
+             CLIENT_ERROR cannot increment or decrement non-numeric value\r\n\
+             NOT_FOUND\r\n\
+             CLIENT_ERROR invalid numeric delta argument\r\n\
+             ERROR\r\n"
+        );
+    }
+
+    // The command still runs, only its reply is left out.
+    #[test]
+    fn noreply_suppresses_the_reply() {
+        let context = Context::in_memory(16);
+        assert_eq!(
+            round_trip(&context, "set k 0 0 1 noreply\r\nv\r\nset n 0 0 1 noreply\r\n1\r\nincr n 1 noreply\r\ndelete k noreply\r\nget k n\r\n"),
+            "VALUE n 0 1\r\n2\r\nEND\r\n"
+        );
+    }
+
+    #[test]
+    fn flags_come_back_as_they_were_set() {
+        let context = Context::in_memory(16);
+        round_trip(&context, "set a 42 0 1\r\nx\r\nset b 4294967295 0 1\r\ny\r\n");
+        let reply = round_trip(&context, "get a b\r\n");
+        assert_eq!(reply, "VALUE a 42 1\r\nx\r\nVALUE b 4294967295 1\r\ny\r\nEND\r\n");
+        assert_eq!(round_trip(&context, "set c 4294967296 0 1\r\nz\r\n"), "CLIENT_ERROR bad command line format\r\n");
+    }
+
+    // Up to 30 days an exptime is a number of seconds from now, and past that a unix time.
+    // One already gone by stores the item, which is then never seen.
+    #[test]
+    fn exptime_is_relative_up_to_30_days_and_absolute_after() {
+        let context = Context::in_memory(16);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let input = format!(
+            "set relative 0 100 1\r\nv\r\nset absolute 0 {} 1\r\nv\r\nset month 0 2592000 1\r\nv\r\nset past 0 {} 1\r\nv\r\nset negative 0 -1 1\r\nv\r\n",
+            now + 200,
+            now - 10
+        );
+        assert_eq!(round_trip(&context, &input), "STORED\r\n".repeat(5));
+        assert!((99..=100).contains(&ttl(&context, "relative")));
+        assert!((198..=200).contains(&ttl(&context, "absolute")));
+        assert!((2_591_999..=2_592_000).contains(&ttl(&context, "month")));
+        assert_eq!(round_trip(&context, "get past negative\r\n"), "END\r\n");
+    }
+}
@@ -0,0 +1,177 @@
+use std::fmt;
+use std::time::Duration;
+use crate::stores::{self, Condition, HeapSize, Stats};
+
+pub mod native;
+pub mod text;
+pub mod binary;
+pub mod resp;
+pub mod memcached;
+pub mod http;
+
+// A client that wants something newer than the legacy framing opens with MAGIC followed by
+// the highest version it speaks. Anything else is treated as a legacy kv-lake-cli client.
+pub const MAGIC: &[u8; 3] = b"KVL";
+pub const LEGACY_VERSION: u8 = 1;
+pub const BINARY_VERSION: u8 = 2;
+
+// `flags` is opaque client data that memcached clients store alongside the value. `cas` is
+// handed out by the server on every write so memcached's `cas` can detect lost updates.
+#[derive(Clone)]
+pub struct Value {
+    pub data: Vec<u8>,
+    pub flags: u32,
+    pub cas: u64,
+}
+
+impl Value {
+    pub fn new(data: Vec<u8>) -> Value {
+        Value { data, flags: 0, cas: 0 }
+    }
+}
+
+impl HeapSize for Value {
+    fn heap_size(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.data))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum ErrorCode {
+    UnknownCommand = 1,
+    InvalidArgument = 2,
+    Conflict = 3,
+    ServerError = 4,
+    // The value is bigger than its shard's share of the cache, so storing it would evict
+    // everything else.
+    TooLarge = 5,
+}
+
+// What every command answers with, whichever wire format it came in on.
+pub enum Response {
+    Ok,
+    Value(Value),
+    Integer(i64),
+    NotFound,
+    // A conditional write that did not go ahead.
+    NotStored,
+    Error(ErrorCode, String),
+    Stats(Stats),
+}
+
+impl Response {
+    pub fn error(code: ErrorCode, message: &str) -> Response {
+        Response::Error(code, message.to_string())
+    }
+}
+
+// An expiry a client asked for, unless it is too far off for the cache to keep track of.
+pub fn valid_expiry(ttl: Duration) -> Option<Duration> {
+    stores::deadline(ttl).ok().map(|_| ttl)
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub enum Command {
+    PUT(Vec<u8>, Value, Option<Duration>, Condition),
+    GET(Vec<u8>),
+    DEL(Vec<u8>),
+    TTL(Vec<u8>),
+    PERSIST(Vec<u8>),
+    INCR(Vec<u8>, u64),
+    DECR(Vec<u8>, u64),
+    // Only stores the value if the key's current cas still matches the given one.
+    CAS(Vec<u8>, Value, Option<Duration>, u64),
+    STATS,
+    // Writes a snapshot of the cache to disk before replying, or on a thread of its own.
+    SAVE,
+    BGSAVE,
+    // Compacts the append-only log in the background.
+    BGREWRITEAOF,
+    QUIT,
+}
+
+impl Command {
+    // The key the command works on, if it touches the cache at all.
+    pub fn key(&self) -> Option<&[u8]> {
+        match self {
+            Command::PUT(key, ..) | Command::GET(key) | Command::DEL(key) | Command::TTL(key) | Command::PERSIST(key) => Some(key),
+            Command::INCR(key, _) | Command::DECR(key, _) | Command::CAS(key, ..) => Some(key),
+            Command::STATS | Command::SAVE | Command::BGSAVE | Command::BGREWRITEAOF | Command::QUIT => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::PUT(..) => "PUT",
+            Command::GET(_) => "GET",
+            Command::DEL(_) => "DEL",
+            Command::TTL(_) => "TTL",
+            Command::PERSIST(_) => "PERSIST",
+            Command::INCR(..) => "INCR",
+            Command::DECR(..) => "DECR",
+            Command::CAS(..) => "CAS",
+            Command::STATS => "STATS",
+            Command::SAVE => "SAVE",
+            Command::BGSAVE => "BGSAVE",
+            Command::BGREWRITEAOF => "BGREWRITEAOF",
+            Command::QUIT => "QUIT",
+        }
+    }
+}
+
+// A protocol whose requests are parsed straight out of whatever input has arrived so far,
+// so one connection loop can drive any of them.
+pub trait Session {
+    type Request;
+
+    // Pulls one request off the front of `buf` along with the number of bytes it took up, or
+    // None if more input is needed. Errors mean the stream can't be resynchronised.
+    fn parse_request(&self, buf: &[u8]) -> Result<Option<(Self::Request, usize)>, String>;
+
+    // Handles one request, appending the reply to `out`. Commands that touch the cache are
+    // handed to `execute`. Returns false once the client has asked to close the connection.
+    fn handle(&mut self, request: Self::Request, execute: &mut dyn FnMut(Command) -> Response, out: &mut Vec<u8>) -> bool;
+
+    fn write_protocol_error(&self, message: &str, out: &mut Vec<u8>);
+}
+
+// Handles every complete request sitting at the front of `input`, removing them from it and
+// appending the replies to `out`. Returns false once the connection should be closed.
+pub fn process<S: Session>(session: &mut S, input: &mut Vec<u8>, execute: &mut dyn FnMut(Command) -> Response, out: &mut Vec<u8>) -> bool {
+    let mut consumed = 0;
+    let keep_open = loop {
+        match session.parse_request(&input[consumed..]) {
+            Ok(Some((request, used))) => {
+                consumed += used;
+                if !session.handle(request, execute, out) {
+                    break false;
+                }
+            }
+            Ok(None) => break true,
+            Err(msg) => {
+                println!("protocol error: {}", msg);
+                session.write_protocol_error(&msg, out);
+                break false;
+            }
+        }
+    };
+    input.drain(..consumed);
+    keep_open
+}
+
+// Lets a connection hold on to whichever protocol it was accepted for.
+pub trait Handler: Send {
+    fn process(&mut self, input: &mut Vec<u8>, execute: &mut dyn FnMut(Command) -> Response, out: &mut Vec<u8>) -> bool;
+}
+
+impl<S: Session + Send> Handler for S {
+    fn process(&mut self, input: &mut Vec<u8>, execute: &mut dyn FnMut(Command) -> Response, out: &mut Vec<u8>) -> bool {
+        process(self, input, execute, out)
+    }
+}
@@ -0,0 +1,335 @@
+// The server's own protocol on the main port. Clients speak the legacy kv-lake-cli framing
+// unless they open with MAGIC to negotiate the binary framing.
+use super::{binary, text, Command, ErrorCode, Response, Session, BINARY_VERSION, LEGACY_VERSION, MAGIC};
+
+#[derive(Clone, Copy)]
+enum Framing {
+    Negotiating,
+    Text,
+    Binary,
+}
+
+pub enum Request {
+    Hello(u8),
+    // None is the zero length frame legacy clients send when they are done.
+    Text(Option<Vec<u8>>),
+    Binary(Result<Command, Response>),
+}
+
+pub struct NativeSession {
+    framing: Framing,
+}
+
+impl Default for NativeSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NativeSession {
+    pub fn new() -> NativeSession {
+        NativeSession { framing: Framing::Negotiating }
+    }
+}
+
+fn parse_text(buf: &[u8]) -> Result<Option<(Request, usize)>, String> {
+    Ok(text::parse_frame(buf).map(|(frame, used)| (Request::Text(frame), used)))
+}
+
+impl Session for NativeSession {
+    type Request = Request;
+
+    fn parse_request(&self, buf: &[u8]) -> Result<Option<(Request, usize)>, String> {
+        match self.framing {
+            Framing::Text => parse_text(buf),
+            Framing::Binary => Ok(binary::parse_frame(buf)?.map(|(request, used)| (Request::Binary(request), used))),
+            Framing::Negotiating => {
+                // A legacy frame can start with the same byte (a 75 byte command), but its payload
+                // will be a command name and never the rest of MAGIC.
+                let prefix = buf.len().min(MAGIC.len());
+                if buf[..prefix] != MAGIC[..prefix] {
+                    return parse_text(buf);
+                }
+                if buf.len() <= MAGIC.len() {
+                    return Ok(None);
+                }
+                Ok(Some((Request::Hello(buf[MAGIC.len()]), MAGIC.len() + 1)))
+            }
+        }
+    }
+
+    fn write_protocol_error(&self, message: &str, out: &mut Vec<u8>) {
+        match self.framing {
+            Framing::Binary => binary::write_response(out, &Response::error(ErrorCode::InvalidArgument, message)),
+            _ => text::write_response(out, &Response::error(ErrorCode::InvalidArgument, message)),
+        }
+    }
+
+    fn handle(&mut self, request: Request, execute: &mut dyn FnMut(Command) -> Response, out: &mut Vec<u8>) -> bool {
+        match request {
+            Request::Hello(requested) => {
+                let version = requested.clamp(LEGACY_VERSION, BINARY_VERSION);
+                out.extend_from_slice(MAGIC);
+                out.push(version);
+                if version == BINARY_VERSION {
+                    println!("Client negotiated binary protocol v{}", version);
+                    self.framing = Framing::Binary;
+                } else {
+                    self.framing = Framing::Text;
+                }
+            }
+            Request::Text(None) => return false,
+            Request::Text(Some(input_buffer)) => {
+                self.framing = Framing::Text;
+                let string_buffer = String::from_utf8_lossy(&input_buffer);
+                println!("Received Command: {}", string_buffer);
+                let response = match text::get_command(&string_buffer) {
+                    Ok(Command::QUIT) => return false,
+                    Ok(command) => execute(command),
+                    Err(response) => response,
+                };
+                text::write_response(out, &response);
+            }
+            Request::Binary(request) => {
+                let response = match request {
+                    Ok(Command::QUIT) => return false,
+                    Ok(command) => execute(command),
+                    Err(response) => response,
+                };
+                binary::write_response(out, &response);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NativeSession;
+    use crate::protocol::binary::{OP_ADD, OP_DEL, OP_GET, OP_PUT, OP_STATS, OP_TTL};
+    use crate::protocol::binary::{STATUS_ERROR, STATUS_INTEGER, STATUS_NOT_FOUND, STATUS_NOT_STORED, STATUS_OK, STATUS_STATS, STATUS_VALUE};
+    use crate::protocol::{process, text, Command, ErrorCode, Response, Value};
+    use crate::stores::lru::LRUCache;
+    use crate::stores::{Cacheable, Ttl};
+
+    // A command as kv-lake-cli sends it: its length, then the line typed in.
+    fn frame(command: &str) -> Vec<u8> {
+        let line = format!("{}\n", command);
+        let mut framed = vec![line.len() as u8];
+        framed.extend_from_slice(line.as_bytes());
+        framed
+    }
+
+    fn replies(mut out: &[u8]) -> Vec<String> {
+        let mut replies = Vec::new();
+        while let Some((Some(reply), used)) = text::parse_frame(out) {
+            replies.push(String::from_utf8(reply).unwrap());
+            out = &out[used..];
+        }
+        assert!(out.is_empty());
+        replies
+    }
+
+    // Runs a command against an LRU cache the way the server does.
+    fn execute(cache: &mut LRUCache<Value>, command: Command) -> Response {
+        match command {
+            Command::PUT(key, value, ttl, condition) => match cache.put_if(key, value, ttl, condition) {
+                Ok(true) => Response::Ok,
+                Ok(false) => Response::NotStored,
+                Err(msg) => Response::error(ErrorCode::TooLarge, &msg),
+            },
+            Command::GET(key) => cache.get(key).map(Response::Value).unwrap_or(Response::NotFound),
+            Command::DEL(key) => cache.delete(key).map(|_| Response::Ok).unwrap_or(Response::NotFound),
+            Command::TTL(key) => match cache.ttl(key) {
+                Ttl::Missing => Response::Integer(-2),
+                Ttl::Persistent => Response::Integer(-1),
+                Ttl::Expiring(remaining) => Response::Integer(remaining.as_secs() as i64),
+            },
+            Command::STATS => Response::Stats(cache.stats()),
+            command => panic!("unexpected {}", command.name()),
+        }
+    }
+
+    // Sends `commands` through a text session backed by an LRU cache and reads the replies.
+    fn round_trip(commands: &[&str]) -> Vec<String> {
+        let mut cache = LRUCache::new(8);
+        let mut input: Vec<u8> = commands.iter().flat_map(|command| frame(command)).collect();
+        let mut out = Vec::new();
+        assert!(process(&mut NativeSession::new(), &mut input, &mut |command| execute(&mut cache, command), &mut out));
+        assert!(input.is_empty());
+        replies(&out)
+    }
+
+    // A binary request: its length, the opcode and each argument with a length of its own.
+    fn binary_frame(opcode: u8, args: &[&[u8]]) -> Vec<u8> {
+        let mut body = vec![opcode];
+        for arg in args {
+            body.extend_from_slice(&(arg.len() as u32).to_be_bytes());
+            body.extend_from_slice(arg);
+        }
+        let mut framed = (body.len() as u32).to_be_bytes().to_vec();
+        framed.extend_from_slice(&body);
+        framed
+    }
+
+    // Splits binary responses into their status byte and payload.
+    fn binary_replies(mut out: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut replies = Vec::new();
+        while !out.is_empty() {
+            let len = u32::from_be_bytes([out[0], out[1], out[2], out[3]]) as usize;
+            replies.push((out[4], out[5..4 + len].to_vec()));
+            out = &out[4 + len..];
+        }
+        replies
+    }
+
+    // Negotiates the binary framing, then sends `requests` through it to an LRU cache.
+    fn binary_round_trip(requests: &[Vec<u8>]) -> Vec<(u8, Vec<u8>)> {
+        let mut cache = LRUCache::new(8);
+        let mut input = b"KVL\x02".to_vec();
+        input.extend(requests.iter().flatten());
+        let mut out = Vec::new();
+        assert!(process(&mut NativeSession::new(), &mut input, &mut |command| execute(&mut cache, command), &mut out));
+        assert!(input.is_empty());
+        assert_eq!(&out[..4], b"KVL\x02");
+        binary_replies(&out[4..])
+    }
+
+    // Everything after the key, the space before it included, is the value.
+    #[test]
+    fn put_then_get_returns_the_value() {
+        assert_eq!(round_trip(&["PUT greeting hello world", "GET greeting"]), ["OK", " hello world"]);
+    }
+
+    #[test]
+    fn missing_keys_are_not_found() {
+        assert_eq!(
+            round_trip(&["GET nothing", "PUT k v", "DEL k", "DEL k", "GET k"]),
+            ["NOT_FOUND", "OK", "OK", "NOT_FOUND", "NOT_FOUND"]
+        );
+    }
+
+    #[test]
+    fn conditional_puts_that_do_not_go_ahead_are_not_stored() {
+        assert_eq!(round_trip(&["PUT k v XX", "PUT k v NX", "PUT k w NX", "GET k"]), ["NOT_STORED", "OK", "NOT_STORED", " v"]);
+    }
+
+    // Errors carry their code ahead of the message.
+    #[test]
+    fn errors_are_spelled_out_with_their_code() {
+        assert_eq!(
+            round_trip(&["FETCH k", "GET", "PUT k v EX 0", "PUT k v PX soon"]),
+            ["ERROR 1 unknown command", "ERROR 2 missing key", "ERROR 2 invalid expire time", "ERROR 2 invalid expire time"]
+        );
+    }
+
+    #[test]
+    fn an_empty_frame_closes_the_connection() {
+        let mut input = frame("PUT k v");
+        input.push(0);
+        input.extend_from_slice(&frame("GET k"));
+        let mut out = Vec::new();
+        let mut execute = |_: Command| Response::Ok;
+        assert!(!process(&mut NativeSession::new(), &mut input, &mut execute, &mut out));
+        assert_eq!(replies(&out), ["OK"]);
+    }
+
+    #[test]
+    fn binary_requests_get_framed_responses() {
+        let replies = binary_round_trip(&[
+            binary_frame(OP_PUT, &[b"k", b"v\0\n"]),
+            binary_frame(OP_GET, &[b"k"]),
+            binary_frame(OP_ADD, &[b"k", b"w"]),
+            binary_frame(OP_TTL, &[b"k"]),
+            binary_frame(OP_DEL, &[b"k"]),
+            binary_frame(OP_GET, &[b"k"]),
+            binary_frame(OP_TTL, &[b"k"]),
+        ]);
+        assert_eq!(
+            replies,
+            [
+                (STATUS_OK, vec![]),
+                (STATUS_VALUE, b"v\0\n".to_vec()),
+                (STATUS_NOT_STORED, vec![]),
+                (STATUS_INTEGER, (-1i64).to_be_bytes().to_vec()),
+                (STATUS_OK, vec![]),
+                (STATUS_NOT_FOUND, vec![]),
+                (STATUS_INTEGER, (-2i64).to_be_bytes().to_vec()),
+            ]
+        );
+    }
+
+    // The error code byte comes ahead of the message.
+    #[test]
+    fn binary_errors_carry_their_code() {
+        let replies = binary_round_trip(&[binary_frame(99, &[]), binary_frame(OP_GET, &[])]);
+        assert_eq!(
+            replies,
+            [
+                (STATUS_ERROR, [&[ErrorCode::UnknownCommand as u8][..], b"unknown command"].concat()),
+                (STATUS_ERROR, [&[ErrorCode::InvalidArgument as u8][..], b"missing argument"].concat()),
+            ]
+        );
+    }
+
+    // Each counter is its length prefixed name and a big-endian u64, in the order STATS lists them.
+    #[test]
+    fn binary_stats_list_every_counter() {
+        let replies = binary_round_trip(&[binary_frame(OP_PUT, &[b"k", b"v"]), binary_frame(OP_GET, &[b"k"]), binary_frame(OP_STATS, &[])]);
+        let (status, mut payload) = (replies[2].0, &replies[2].1[..]);
+        assert_eq!(status, STATUS_STATS);
+        let mut counters = Vec::new();
+        while !payload.is_empty() {
+            let len = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+            let name = String::from_utf8(payload[4..4 + len].to_vec()).unwrap();
+            let mut value = [0; 8];
+            value.copy_from_slice(&payload[4 + len..12 + len]);
+            counters.push((name, u64::from_be_bytes(value)));
+            payload = &payload[12 + len..];
+        }
+        let names: Vec<&str> = counters.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["hits", "misses", "inserts", "updates", "evictions", "expirations", "deletes", "size", "capacity", "bytes", "max_bytes"]);
+        assert_eq!(counters[0].1, 1);
+        assert_eq!(counters[2].1, 1);
+        assert_eq!(counters[8].1, 8);
+    }
+
+    // Whatever version a client asks for, it is told the one it gets: the legacy framing for
+    // anything below 2 and the binary framing for anything from 2 up.
+    #[test]
+    fn hello_picks_the_closest_version_there_is() {
+        for (requested, picked) in [(0, 1), (1, 1), (2, 2), (9, 2)] {
+            let mut input = b"KVL".to_vec();
+            input.push(requested);
+            let mut out = Vec::new();
+            let mut execute = |_: Command| Response::Ok;
+            assert!(process(&mut NativeSession::new(), &mut input, &mut execute, &mut out));
+            assert_eq!(out, [&b"KVL"[..], &[picked]].concat());
+        }
+    }
+
+    #[test]
+    fn the_legacy_framing_carries_on_after_hello_1() {
+        let mut cache = LRUCache::new(8);
+        let mut input = b"KVL\x01".to_vec();
+        input.extend_from_slice(&frame("PUT k v"));
+        input.extend_from_slice(&frame("GET k"));
+        let mut out = Vec::new();
+        assert!(process(&mut NativeSession::new(), &mut input, &mut |command| execute(&mut cache, command), &mut out));
+        assert_eq!(&out[..4], b"KVL\x01");
+        assert_eq!(replies(&out[4..]), ["OK", " v"]);
+    }
+
+    // Only a hello as the very first bytes negotiates. Later on, the same bytes are the start
+    // of a 75 byte legacy frame still waiting for the rest of it.
+    #[test]
+    fn hello_is_only_understood_first() {
+        let mut input = frame("PUT k v");
+        input.extend_from_slice(b"KVL\x02");
+        let mut out = Vec::new();
+        let mut execute = |_: Command| Response::Ok;
+        assert!(process(&mut NativeSession::new(), &mut input, &mut execute, &mut out));
+        assert_eq!(replies(&out), ["OK"]);
+        assert_eq!(input, b"KVL\x02");
+    }
+}
@@ -0,0 +1,408 @@
+// The Redis serialization protocol, so redis-cli and stock Redis clients can talk to the cache.
+// Requests are arrays of bulk strings (or inline commands typed into telnet). Replies are RESP2
+// until the client switches to RESP3 with HELLO 3.
+use std::time::Duration;
+use crate::stores::{Condition, Stats};
+use super::{valid_expiry, Command, Response, Session, Value};
+
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+const MAX_ARRAY_LEN: usize = 1024 * 1024;
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+type Parsed = Option<(Vec<Vec<u8>>, usize)>;
+
+fn find_crlf(buf: &[u8], from: usize) -> Option<usize> {
+    buf[from..].windows(2).position(|w| w == b"\r\n").map(|p| from + p)
+}
+
+fn parse_int(raw: &[u8]) -> Result<i64, String> {
+    std::str::from_utf8(raw)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(|| "invalid length".to_string())
+}
+
+// Reads the `<prefix><number>\r\n` header starting at `pos`. Returns the number and where the
+// next token starts, or None if the header has not fully arrived yet.
+fn parse_header(buf: &[u8], pos: usize, prefix: u8, max: usize) -> Result<Option<(i64, usize)>, String> {
+    if pos >= buf.len() {
+        return Ok(None);
+    }
+    if buf[pos] != prefix {
+        return Err(format!("expected '{}', got '{}'", prefix as char, buf[pos] as char));
+    }
+    match find_crlf(buf, pos + 1) {
+        Some(end) => {
+            let n = parse_int(&buf[pos + 1..end])?;
+            if n > max as i64 {
+                return Err("length too large".to_string());
+            }
+            Ok(Some((n, end + 2)))
+        }
+        None if buf.len() - pos > 32 => Err("length header too long".to_string()),
+        None => Ok(None),
+    }
+}
+
+fn parse_inline(buf: &[u8]) -> Result<Parsed, String> {
+    match buf.iter().position(|b| *b == b'\n') {
+        Some(end) => {
+            let line = &buf[..end];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let args = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec())
+                .collect();
+            Ok(Some((args, end + 1)))
+        }
+        None if buf.len() > MAX_INLINE_LEN => Err("too big inline request".to_string()),
+        None => Ok(None),
+    }
+}
+
+fn parse_request(buf: &[u8]) -> Result<Parsed, String> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] != b'*' {
+        return parse_inline(buf);
+    }
+    let (count, mut pos) = match parse_header(buf, 0, b'*', MAX_ARRAY_LEN)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let mut args = Vec::with_capacity(count.max(0) as usize);
+    for _ in 0..count {
+        let (len, start) = match parse_header(buf, pos, b'$', MAX_BULK_LEN)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if len < 0 {
+            return Err("invalid bulk length".to_string());
+        }
+        let end = start + len as usize;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err("bulk string not terminated by CRLF".to_string());
+        }
+        args.push(buf[start..end].to_vec());
+        pos = end + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+fn write_simple(out: &mut Vec<u8>, s: &str) {
+    out.push(b'+');
+    out.extend_from_slice(s.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+fn write_error(out: &mut Vec<u8>, message: &str) {
+    out.push(b'-');
+    out.extend_from_slice(message.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+fn write_integer(out: &mut Vec<u8>, i: i64) {
+    out.extend_from_slice(format!(":{}\r\n", i).as_bytes());
+}
+
+fn write_bulk(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+}
+
+// Laid out like the Stats section of Redis' INFO reply.
+fn write_stats(out: &mut Vec<u8>, stats: &Stats) {
+    let mut info = String::from("# Stats\r\n");
+    for (name, value) in stats.fields().iter() {
+        info.push_str(&format!("{}:{}\r\n", name, value));
+    }
+    write_bulk(out, info.as_bytes());
+}
+
+fn write_array_header(out: &mut Vec<u8>, len: usize) {
+    out.extend_from_slice(format!("*{}\r\n", len).as_bytes());
+}
+
+pub struct RespSession {
+    protocol: u8,
+}
+
+impl Default for RespSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RespSession {
+    pub fn new() -> RespSession {
+        RespSession { protocol: 2 }
+    }
+
+    fn write_null(&self, out: &mut Vec<u8>) {
+        if self.protocol == 3 {
+            out.extend_from_slice(b"_\r\n");
+        } else {
+            out.extend_from_slice(b"$-1\r\n");
+        }
+    }
+
+    fn write_response(&self, out: &mut Vec<u8>, response: &Response) {
+        match response {
+            Response::Ok => write_simple(out, "OK"),
+            Response::Value(value) => write_bulk(out, &value.data),
+            Response::Integer(i) => write_integer(out, *i),
+            Response::NotFound | Response::NotStored => self.write_null(out),
+            Response::Error(_, message) => write_error(out, &format!("ERR {}", message)),
+            Response::Stats(stats) => write_stats(out, stats),
+        }
+    }
+
+    fn write_hello(&self, out: &mut Vec<u8>) {
+        let fields: [(&str, &str); 5] = [
+            ("server", "kv-lake"),
+            ("version", env!("CARGO_PKG_VERSION")),
+            ("mode", "standalone"),
+            ("role", "master"),
+            ("proto", ""),
+        ];
+        if self.protocol == 3 {
+            out.extend_from_slice(format!("%{}\r\n", fields.len()).as_bytes());
+        } else {
+            write_array_header(out, fields.len() * 2);
+        }
+        for (name, value) in fields.iter() {
+            write_bulk(out, name.as_bytes());
+            if *name == "proto" {
+                write_integer(out, self.protocol as i64);
+            } else {
+                write_bulk(out, value.as_bytes());
+            }
+        }
+    }
+}
+
+impl Session for RespSession {
+    type Request = Vec<Vec<u8>>;
+
+    fn parse_request(&self, buf: &[u8]) -> Result<Parsed, String> {
+        parse_request(buf)
+    }
+
+    fn write_protocol_error(&self, message: &str, out: &mut Vec<u8>) {
+        write_error(out, &format!("ERR Protocol error: {}", message));
+    }
+
+    fn handle(&mut self, args: Vec<Vec<u8>>, execute: &mut dyn FnMut(Command) -> Response, out: &mut Vec<u8>) -> bool {
+        if args.is_empty() {
+            return true;
+        }
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let mut args = args.into_iter().skip(1).collect::<Vec<Vec<u8>>>();
+        let wrong_arity = format!("ERR wrong number of arguments for '{}' command", name.to_lowercase());
+        match name.as_str() {
+            "PING" => match args.len() {
+                0 => write_simple(out, "PONG"),
+                1 => write_bulk(out, &args[0]),
+                _ => write_error(out, &wrong_arity),
+            },
+            "ECHO" if args.len() == 1 => write_bulk(out, &args[0]),
+            "QUIT" => {
+                write_simple(out, "OK");
+                return false;
+            }
+            "HELLO" => {
+                if let Some(version) = args.first() {
+                    match std::str::from_utf8(version).ok().and_then(|v| v.parse::<u8>().ok()) {
+                        Some(v) if v == 2 || v == 3 => self.protocol = v,
+                        _ => {
+                            write_error(out, "NOPROTO unsupported protocol version");
+                            return true;
+                        }
+                    }
+                }
+                self.write_hello(out);
+            }
+            // redis-cli asks for command docs on startup; an empty reply is fine.
+            "COMMAND" => write_array_header(out, 0),
+            "CLIENT" => write_simple(out, "OK"),
+            "SELECT" if args.len() == 1 => {
+                if args[0] == b"0" {
+                    write_simple(out, "OK")
+                } else {
+                    write_error(out, "ERR DB index is out of range")
+                }
+            }
+            // Every section asked for gets the cache counters, which is all there is to report.
+            "INFO" => {
+                let response = execute(Command::STATS);
+                self.write_response(out, &response);
+            }
+            "SAVE" => {
+                let response = execute(Command::SAVE);
+                self.write_response(out, &response);
+            }
+            "BGSAVE" => match execute(Command::BGSAVE) {
+                Response::Ok => write_simple(out, "Background saving started"),
+                response => self.write_response(out, &response),
+            },
+            "BGREWRITEAOF" => match execute(Command::BGREWRITEAOF) {
+                Response::Ok => write_simple(out, "Background append only file rewriting started"),
+                response => self.write_response(out, &response),
+            },
+            "GET" if args.len() == 1 => {
+                let response = execute(Command::GET(args.remove(0)));
+                self.write_response(out, &response);
+            }
+            "SET" if args.len() >= 2 => match get_options(&args[2..]) {
+                Ok((ttl, condition)) => {
+                    let value = args.remove(1);
+                    let key = args.remove(0);
+                    let response = execute(Command::PUT(key, Value::new(value), ttl, condition));
+                    self.write_response(out, &response);
+                }
+                Err(message) => write_error(out, &message),
+            },
+            "DEL" if !args.is_empty() => {
+                let mut deleted = 0;
+                for key in args {
+                    if let Response::Ok = execute(Command::DEL(key)) {
+                        deleted += 1;
+                    }
+                }
+                write_integer(out, deleted);
+            }
+            "TTL" if args.len() == 1 => {
+                let response = execute(Command::TTL(args.remove(0)));
+                self.write_response(out, &response);
+            }
+            "PERSIST" if args.len() == 1 => {
+                let response = execute(Command::PERSIST(args.remove(0)));
+                self.write_response(out, &response);
+            }
+            "ECHO" | "SELECT" | "GET" | "SET" | "DEL" | "TTL" | "PERSIST" => write_error(out, &wrong_arity),
+            _ => write_error(out, &format!("ERR unknown command '{}'", name.to_lowercase())),
+        }
+        true
+    }
+}
+
+// SET accepts `EX seconds` or `PX milliseconds`, and `NX` or `XX`, in any order after the value.
+fn get_options(options: &[Vec<u8>]) -> Result<(Option<Duration>, Condition), String> {
+    let syntax_error = || "ERR syntax error".to_string();
+    let mut ttl = None;
+    let mut condition = Condition::Always;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" | b"XX" if condition != Condition::Always => return Err(syntax_error()),
+            b"NX" => condition = Condition::IfAbsent,
+            b"XX" => condition = Condition::IfPresent,
+            unit @ (b"EX" | b"PX") if ttl.is_none() => {
+                let amount = options
+                    .next()
+                    .and_then(|a| std::str::from_utf8(a).ok())
+                    .and_then(|a| a.parse::<u64>().ok())
+                    .filter(|a| *a > 0)
+                    .map(|a| if unit == b"EX" { Duration::from_secs(a) } else { Duration::from_millis(a) })
+                    .and_then(valid_expiry)
+                    .ok_or_else(|| "ERR invalid expire time in 'set' command".to_string())?;
+                ttl = Some(amount);
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+    Ok((ttl, condition))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_request, RespSession};
+    use crate::protocol::process;
+    use crate::{execute, Context};
+
+    fn args(raw: &[&str]) -> Vec<Vec<u8>> {
+        raw.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    // Sends `input` through the session to a cache and returns everything written back.
+    fn round_trip(session: &mut RespSession, context: &Context, input: &str) -> String {
+        let mut input = input.as_bytes().to_vec();
+        let mut out = Vec::new();
+        assert!(process(session, &mut input, &mut |command| execute(command, context), &mut out));
+        assert!(input.is_empty());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn bulk_requests_wait_until_they_are_whole() {
+        let request = "*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nv\r\nv \r\n*1\r\n";
+        let parsed = parse_request(request.as_bytes()).unwrap().unwrap();
+        assert_eq!(parsed, (args(&["SET", "k", "v\r\nv "]), 31));
+        for end in 0..31 {
+            assert_eq!(parse_request(&request.as_bytes()[..end]).unwrap(), None);
+        }
+        assert_eq!(parse_request(b"*1\r\n$1\r\nkX\r\n"), Err("bulk string not terminated by CRLF".to_string()));
+        assert_eq!(parse_request(b"*1\r\n$-1\r\n"), Err("invalid bulk length".to_string()));
+        assert_eq!(parse_request(b"*1\r\n:1\r\n"), Err("expected '$', got ':'".to_string()));
+    }
+
+    // As typed into telnet: split on whitespace, with or without the carriage return.
+    #[test]
+    fn inline_requests_are_split_on_whitespace() {
+        assert_eq!(parse_request(b"SET  k\tv\r\nGET k\n").unwrap(), Some((args(&["SET", "k", "v"]), 10)));
+        assert_eq!(parse_request(b"GET k\n").unwrap(), Some((args(&["GET", "k"]), 6)));
+        assert_eq!(parse_request(b"GET k").unwrap(), None);
+    }
+
+    #[test]
+    fn set_takes_ex_px_nx_and_xx_in_any_order() {
+        let context = Context::in_memory(16);
+        let mut session = RespSession::new();
+        let replies = round_trip(
+            &mut session,
+            &context,
+            "SET k v XX\r\nSET k v NX EX 100\r\nTTL k\r\nset k w px 5000 xx\r\nTTL k\r\nGET k\r\nSET k v NX\r\n",
+        );
+        // TTL rounds down, and a moment has passed since each SET.
+        assert_eq!(replies, "$-1\r\n+OK\r\n:99\r\n+OK\r\n:4\r\n$1\r\nw\r\n$-1\r\n");
+    }
+
+    #[test]
+    fn bad_set_options_are_errors() {
+        let context = Context::in_memory(16);
+        let mut session = RespSession::new();
+        let replies = round_trip(&mut session, &context, "SET k v EX 0\r\nSET k v PX soon\r\nSET k v EX\r\nSET k v NX XX\r\nSET k v EX 1 PX 1\r\nSET k\r\n");
+        assert_eq!(
+            replies,
+            "-ERR invalid expire time in 'set' command\r\n\
+             -ERR invalid expire time in 'set' command\r\n\
+             -ERR invalid expire time in 'set' command\r\n\
+             -ERR syntax error\r\n\
+             -ERR syntax error\r\n\
+             -ERR wrong number of arguments for 'set' command\r\n"
+        );
+    }
+
+    // RESP2 has no null of its own, so a missing key is a null bulk string. After HELLO 3 it
+    // is RESP3's null, and HELLO's reply is a map rather than a flat array.
+    #[test]
+    fn hello_3_switches_to_resp3_nulls_and_maps() {
+        let context = Context::in_memory(16);
+        let mut session = RespSession::new();
+        assert_eq!(round_trip(&mut session, &context, "GET missing\r\n"), "$-1\r\n");
+        let hello = round_trip(&mut session, &context, "HELLO 3\r\n");
+        assert!(hello.starts_with("%5\r\n$6\r\nserver\r\n$7\r\nkv-lake\r\n"));
+        assert!(hello.ends_with("$5\r\nproto\r\n:3\r\n"));
+        assert_eq!(round_trip(&mut session, &context, "GET missing\r\nSET k v XX\r\n"), "_\r\n_\r\n");
+        let hello = round_trip(&mut session, &context, "HELLO 2\r\n");
+        assert!(hello.starts_with("*10\r\n"));
+        assert!(hello.ends_with("$5\r\nproto\r\n:2\r\n"));
+        assert_eq!(round_trip(&mut session, &context, "GET missing\r\nHELLO 4\r\n"), "$-1\r\n-NOPROTO unsupported protocol version\r\n");
+    }
+}
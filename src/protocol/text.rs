@@ -0,0 +1,182 @@
+// The original kv-lake-cli framing: a single length byte followed by a space separated command.
+use std::collections::VecDeque;
+use std::time::Duration;
+use crate::stores::Condition;
+use super::{valid_expiry, Command, ErrorCode, Response, Value};
+
+const MAX_FRAME_LEN: usize = u8::MAX as usize;
+
+// Pulls one frame off the front of `buf` along with the number of bytes it took up, or None
+// if it hasn't fully arrived. A zero length frame is the client saying goodbye.
+pub fn parse_frame(buf: &[u8]) -> Option<(Option<Vec<u8>>, usize)> {
+    let input_length = *buf.first()? as usize;
+    if input_length == 0 {
+        return Some((None, 1));
+    }
+    if buf.len() < 1 + input_length {
+        return None;
+    }
+    Some((Some(buf[1..1 + input_length].to_vec()), 1 + input_length))
+}
+
+// The length prefix is a single byte, so anything longer is cut off rather than letting the
+// length wrap around and desync the stream. Clients that need more should negotiate binary.
+fn write_frame(out: &mut Vec<u8>, output: &[u8]) {
+    let output = &output[..output.len().min(MAX_FRAME_LEN)];
+    out.push(output.len() as u8);
+    out.extend_from_slice(output);
+}
+
+// There is no status byte in this framing, so the response kind is spelled out in the text.
+pub fn write_response(out: &mut Vec<u8>, response: &Response) {
+    match response {
+        Response::Ok => write_frame(out, b"OK"),
+        Response::Value(value) => write_frame(out, &value.data),
+        Response::Integer(i) => write_frame(out, i.to_string().as_bytes()),
+        Response::NotFound => write_frame(out, b"NOT_FOUND"),
+        Response::NotStored => write_frame(out, b"NOT_STORED"),
+        Response::Error(code, message) => {
+            write_frame(out, format!("ERROR {} {}", *code as u8, message).as_bytes())
+        }
+        Response::Stats(stats) => {
+            let lines: Vec<String> = stats.fields().iter().map(|(name, value)| format!("{} {}", name, value)).collect();
+            write_frame(out, lines.join("\n").as_bytes())
+        }
+    }
+}
+
+// Strips the `EX seconds` or `PX millis` and `NX` or `XX` options off the end of a PUT's
+// arguments, in any order. Whatever comes before them is the value, which always keeps at least
+// one word, so a value can still be `NX` or end in `EX 10` as long as nothing follows it.
+fn get_options(input_vec: &mut VecDeque<&str>) -> Result<(Option<Duration>, Condition), Response> {
+    let syntax_error = || Response::error(ErrorCode::InvalidArgument, "syntax error");
+    let mut ttl = None;
+    let mut condition = Condition::Always;
+    loop {
+        let len = input_vec.len();
+        match input_vec.back() {
+            Some(&"NX") | Some(&"XX") if len > 1 => {
+                if condition != Condition::Always {
+                    return Err(syntax_error());
+                }
+                condition = if input_vec.pop_back() == Some("NX") { Condition::IfAbsent } else { Condition::IfPresent };
+            }
+            Some(amount) if len > 2 && (input_vec[len - 2] == "EX" || input_vec[len - 2] == "PX") => {
+                if ttl.is_some() {
+                    return Err(syntax_error());
+                }
+                let amount = match amount.parse::<u64>() {
+                    Ok(a) if a > 0 => a,
+                    _ => return Err(Response::error(ErrorCode::InvalidArgument, "invalid expire time")),
+                };
+                let expiry = if input_vec[len - 2] == "EX" { Duration::from_secs(amount) } else { Duration::from_millis(amount) };
+                ttl = Some(valid_expiry(expiry).ok_or_else(|| Response::error(ErrorCode::InvalidArgument, "invalid expire time"))?);
+                input_vec.pop_back();
+                input_vec.pop_back();
+            }
+            _ => return Ok((ttl, condition)),
+        }
+    }
+}
+
+fn next_key(input_vec: &mut VecDeque<&str>) -> Result<Vec<u8>, Response> {
+    match input_vec.pop_front() {
+        Some(key) if !key.is_empty() => Ok(key.as_bytes().to_vec()),
+        _ => Err(Response::error(ErrorCode::InvalidArgument, "missing key")),
+    }
+}
+
+pub fn get_command(raw_input: &str) -> Result<Command, Response> {
+    let mut raw_input = raw_input.to_string();
+    raw_input.pop(); // removing trailing newline char
+    let mut input_vec: VecDeque<&str> = raw_input.split(' ').collect::<VecDeque<&str>>();
+    let c = input_vec.pop_front().unwrap();
+    if c == "PUT" {
+        let key = next_key(&mut input_vec)?;
+        let (ttl, condition) = get_options(&mut input_vec)?;
+        Ok(Command::PUT(
+            key,
+            Value::new(input_vec.iter().fold(String::new(), |mut acc, x| {
+                acc.push(' ');
+                acc.push_str(x);
+                acc
+            }).into_bytes()),
+            ttl,
+            condition
+        ))
+    } else if c == "GET" {
+        Ok(Command::GET(next_key(&mut input_vec)?))
+    } else if c == "quit" {
+        Ok(Command::QUIT)
+    } else if c == "DEL" {
+        Ok(Command::DEL(next_key(&mut input_vec)?))
+    } else if c == "TTL" {
+        Ok(Command::TTL(next_key(&mut input_vec)?))
+    } else if c == "PERSIST" {
+        Ok(Command::PERSIST(next_key(&mut input_vec)?))
+    } else if c == "STATS" {
+        Ok(Command::STATS)
+    } else if c == "SAVE" {
+        Ok(Command::SAVE)
+    } else if c == "BGSAVE" {
+        Ok(Command::BGSAVE)
+    } else if c == "BGREWRITEAOF" {
+        Ok(Command::BGREWRITEAOF)
+    } else {
+        Err(Response::error(ErrorCode::UnknownCommand, "unknown command"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::get_command;
+    use crate::protocol::{Command, Response};
+    use crate::stores::Condition;
+
+    // The value, expiry and condition a PUT line parses into.
+    fn put(line: &str) -> (String, Option<Duration>, Condition) {
+        match get_command(&format!("{}\n", line)) {
+            Ok(Command::PUT(_, value, ttl, condition)) => (String::from_utf8(value.data).unwrap(), ttl, condition),
+            _ => panic!("{} is not a PUT", line),
+        }
+    }
+
+    fn error(line: &str) -> String {
+        match get_command(&format!("{}\n", line)) {
+            Err(Response::Error(_, message)) => message,
+            _ => panic!("{} did not fail", line),
+        }
+    }
+
+    #[test]
+    fn options_come_in_any_order() {
+        for line in ["PUT k v EX 10 NX", "PUT k v NX EX 10"] {
+            let (value, ttl, condition) = put(line);
+            assert_eq!(value, " v");
+            assert_eq!(ttl, Some(Duration::from_secs(10)));
+            assert!(condition == Condition::IfAbsent);
+        }
+        let (value, ttl, condition) = put("PUT k two words XX PX 250");
+        assert_eq!(value, " two words");
+        assert_eq!(ttl, Some(Duration::from_millis(250)));
+        assert!(condition == Condition::IfPresent);
+    }
+
+    #[test]
+    fn options_can_only_be_given_once() {
+        for line in ["PUT k v NX NX", "PUT k v NX XX", "PUT k v EX 10 PX 10", "PUT k v EX 10 NX EX 5"] {
+            assert_eq!(error(line), "syntax error");
+        }
+    }
+
+    // The value keeps at least one word, so it can look like an option when nothing follows it.
+    #[test]
+    fn a_value_on_its_own_is_never_an_option() {
+        assert_eq!(put("PUT k NX").0, " NX");
+        assert_eq!(put("PUT k EX 10").0, " EX 10");
+        let (value, _, condition) = put("PUT k XX NX");
+        assert_eq!(value, " XX");
+        assert!(condition == Condition::IfAbsent);
+    }
+}
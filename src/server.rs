@@ -0,0 +1,214 @@
+// Event driven networking. One acceptor thread hands new connections out round robin to a
+// fixed pool of workers, each running its own mio event loop over non-blocking sockets, so
+// idle clients cost a socket and two buffers rather than a thread each.
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use crate::metrics::METRICS;
+use crate::protocol::{Command, Handler, Response};
+
+pub type Executor = Arc<dyn Fn(Command) -> Response + Send + Sync>;
+pub type SessionFactory = Box<dyn Fn() -> Box<dyn Handler>>;
+
+// A port to accept connections on and the protocol to speak on them.
+pub struct Listener {
+    pub name: &'static str,
+    pub port: u32,
+    pub new_session: SessionFactory,
+}
+
+const WAKER: Token = Token(usize::MAX);
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+struct Connection {
+    stream: TcpStream,
+    handler: Box<dyn Handler>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    closing: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, handler: Box<dyn Handler>) -> Connection {
+        METRICS.connection_opened();
+        Connection { stream, handler, input: Vec::new(), output: Vec::new(), closing: false }
+    }
+
+    // Reads everything the socket has for us. Partial requests stay buffered until the rest
+    // of them arrives.
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.closing = true;
+                    return Ok(());
+                }
+                Ok(n) => {
+                    METRICS.received(n);
+                    self.input.extend_from_slice(&chunk[..n]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    METRICS.sent(n);
+                    self.output.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Handles a readiness event. Returns true once the connection is finished with.
+    fn ready(&mut self, execute: &Executor) -> bool {
+        if !self.closing {
+            if self.fill().is_err() {
+                return true;
+            }
+            if !self.handler.process(&mut self.input, &mut |command| execute(command), &mut self.output) {
+                self.closing = true;
+            }
+        }
+        if self.flush().is_err() {
+            return true;
+        }
+        self.closing && self.output.is_empty()
+    }
+
+    fn interest(&self) -> Interest {
+        if self.output.is_empty() {
+            Interest::READABLE
+        } else {
+            Interest::READABLE | Interest::WRITABLE
+        }
+    }
+}
+
+struct Worker {
+    sender: Sender<(TcpStream, Box<dyn Handler>)>,
+    waker: Arc<Waker>,
+}
+
+fn spawn_worker(execute: Executor) -> io::Result<Worker> {
+    let poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        run_worker(poll, receiver, execute).expect("Worker event loop failed.");
+    });
+    Ok(Worker { sender, waker })
+}
+
+fn run_worker(mut poll: Poll, receiver: Receiver<(TcpStream, Box<dyn Handler>)>, execute: Executor) -> io::Result<()> {
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut next_token = 0;
+    let mut events = Events::with_capacity(1024);
+    loop {
+        if let Err(e) = poll.poll(&mut events, None) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        for event in events.iter() {
+            if event.token() == WAKER {
+                while let Ok((mut stream, handler)) = receiver.try_recv() {
+                    let token = Token(next_token);
+                    next_token += 1;
+                    poll.registry().register(&mut stream, token, Interest::READABLE)?;
+                    connections.insert(token, Connection::new(stream, handler));
+                }
+                continue;
+            }
+            let token = event.token();
+            let done = match connections.get_mut(&token) {
+                Some(conn) => conn.ready(&execute),
+                None => continue,
+            };
+            if done {
+                if let Some(mut conn) = connections.remove(&token) {
+                    println!("shutting down. bye!");
+                    METRICS.connection_closed();
+                    poll.registry().deregister(&mut conn.stream)?;
+                    let _ = conn.stream.shutdown(Shutdown::Both);
+                }
+            } else if let Some(conn) = connections.get_mut(&token) {
+                let interest = conn.interest();
+                poll.registry().reregister(&mut conn.stream, token, interest)?;
+            }
+        }
+    }
+}
+
+fn bind(port: u32) -> io::Result<TcpListener> {
+    let listener = std::net::TcpListener::bind(format!("localhost:{}", port))?;
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener))
+}
+
+// Binds every listener and serves connections on them until the process exits.
+pub fn run(listeners: Vec<Listener>, workers: usize, execute: Executor) -> io::Result<()> {
+    let mut poll = Poll::new()?;
+    let mut bound = Vec::new();
+    for (i, listener) in listeners.into_iter().enumerate() {
+        let mut socket = bind(listener.port)?;
+        poll.registry().register(&mut socket, Token(i), Interest::READABLE)?;
+        println!("Listening for {} on port {}", listener.name, listener.port);
+        bound.push((socket, listener.new_session));
+    }
+    let pool = (0..workers.max(1))
+        .map(|_| spawn_worker(Arc::clone(&execute)))
+        .collect::<io::Result<Vec<Worker>>>()?;
+    println!("Serving connections with {} workers", pool.len());
+
+    let mut next_worker = 0;
+    let mut events = Events::with_capacity(128);
+    loop {
+        if let Err(e) = poll.poll(&mut events, None) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        for event in events.iter() {
+            let (socket, new_session) = &bound[event.token().0];
+            loop {
+                match socket.accept() {
+                    Ok((stream, _)) => {
+                        // Replies are written in one go, so there is nothing for Nagle to batch.
+                        let _ = stream.set_nodelay(true);
+                        let worker = &pool[next_worker % pool.len()];
+                        next_worker += 1;
+                        if worker.sender.send((stream, new_session())).is_ok() {
+                            worker.waker.wake()?;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        println!("accept failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
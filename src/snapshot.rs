@@ -0,0 +1,262 @@
+// Point in time copies of the cache on disk, so a restart comes back warm instead of sending
+// every request through to the database behind us.
+//
+// A snapshot file is MAGIC, a version byte and an entry count (u64), then each entry, then a
+// CRC-32 of everything before it. Every integer is big-endian. An entry is its key and value
+// data, each prefixed with a u32 length, the value's flags (u32) and cas (u64), and the unix
+// time in milliseconds the entry expires at (u64, 0 for never), then how often it has been used
+// (u32, 0 where the policy doesn't count). Expiry is stored as a wall clock time so a key that
+// should have expired while the server was down stays gone. Entries are written in the order
+// Cacheable::entries gives them, so restoring them in turn rebuilds the eviction order.
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::protocol::Value;
+use crate::stores::{Cacheable, Entry};
+
+const MAGIC: &[u8; 4] = b"KVLS";
+const VERSION: u8 = 2;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+
+// Any cache of server values, sharded or not.
+pub type Store = dyn Cacheable<Value> + Send + Sync;
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, b| CRC32_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+pub fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+// Also the body of the append-only log's PUT records.
+pub fn write_entry(out: &mut Vec<u8>, entry: &Entry<Value>) {
+    write_bytes(out, &entry.key);
+    write_bytes(out, &entry.value.data);
+    out.extend_from_slice(&entry.value.flags.to_be_bytes());
+    out.extend_from_slice(&entry.value.cas.to_be_bytes());
+    let expires_at = match entry.expires_at {
+        Some(t) => unix_millis(SystemTime::now() + t.saturating_duration_since(Instant::now())).max(1),
+        None => 0,
+    };
+    out.extend_from_slice(&expires_at.to_be_bytes());
+    out.extend_from_slice(&entry.frequency.to_be_bytes());
+}
+
+fn encode(entries: &[Entry<Value>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    out.extend_from_slice(&(entries.len() as u64).to_be_bytes());
+    for entry in entries {
+        write_entry(&mut out, entry);
+    }
+    let checksum = crc32(&out);
+    out.extend_from_slice(&checksum.to_be_bytes());
+    out
+}
+
+// Reads integers and length prefixed fields off the front of a snapshot body.
+pub struct Reader<'a> {
+    pub buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() < len {
+            return Err("truncated data".to_string());
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    // Reads what write_entry wrote. Entries that have expired since come back as None.
+    pub fn entry(&mut self) -> Result<Option<Entry<Value>>, String> {
+        let key = self.bytes()?;
+        let mut value = Value::new(self.bytes()?);
+        value.flags = self.u32()?;
+        value.cas = self.u64()?;
+        let expires_at = self.u64()?;
+        let frequency = self.u32()?;
+        let expires_at = match expires_at {
+            0 => None,
+            t => {
+                let now = unix_millis(SystemTime::now());
+                if t <= now {
+                    return Ok(None);
+                }
+                Some(Instant::now() + Duration::from_millis(t - now))
+            }
+        };
+        Ok(Some(Entry { key, value, expires_at, frequency }))
+    }
+}
+
+// Entries whose expiry passed while they were on disk are left out.
+fn decode(raw: &[u8]) -> Result<Vec<Entry<Value>>, String> {
+    if raw.len() < HEADER_LEN + 4 || &raw[..MAGIC.len()] != MAGIC {
+        return Err("not a kv-lake snapshot".to_string());
+    }
+    let version = raw[MAGIC.len()];
+    if version != VERSION {
+        return Err(format!("unsupported snapshot version {}", version));
+    }
+    let (body, trailer) = raw.split_at(raw.len() - 4);
+    if crc32(body).to_be_bytes() != trailer {
+        return Err("snapshot checksum mismatch".to_string());
+    }
+    let mut reader = Reader { buf: &body[MAGIC.len() + 1..] };
+    let count = reader.u64()?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        if let Some(entry) = reader.entry()? {
+            entries.push(entry);
+        }
+    }
+    if !reader.buf.is_empty() {
+        return Err("trailing data after the last snapshot entry".to_string());
+    }
+    Ok(entries)
+}
+
+pub struct Snapshots {
+    path: PathBuf,
+    saving: AtomicBool,
+    // Held for the whole of a save, as SAVE, BGSAVE and the periodic save all write through
+    // the same temporary file.
+    writing: Mutex<()>,
+}
+
+impl Snapshots {
+    pub fn new(path: PathBuf) -> Snapshots {
+        Snapshots { path, saving: AtomicBool::new(false), writing: Mutex::new(()) }
+    }
+
+    // Writes the cache out next to the snapshot and renames it into place, so a crash half way
+    // through leaves the previous snapshot intact. Saves that overlap take turns, the later one
+    // reading the cache once the earlier one is done. Returns the number of entries written.
+    pub fn save(&self, cache: &Store) -> Result<usize, String> {
+        let _writing = self.writing.lock().unwrap();
+        let entries = cache.entries();
+        let encoded = encode(&entries);
+        let tmp_path = self.path.with_extension("tmp");
+        let write = || -> std::io::Result<()> {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(&encoded)?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, &self.path)
+        };
+        write().map_err(|e| format!("failed to write {}: {}", self.path.display(), e))?;
+        Ok(entries.len())
+    }
+
+    // Saves on a thread of its own. Returns false if a background save is already running.
+    pub fn save_in_background(self: &Arc<Self>, cache: Arc<Store>) -> bool {
+        if self.saving.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        let snapshots = Arc::clone(self);
+        thread::spawn(move || {
+            snapshots.log_save(&*cache);
+            snapshots.saving.store(false, Ordering::Release);
+        });
+        true
+    }
+
+    pub fn log_save(&self, cache: &Store) {
+        match self.save(cache) {
+            Ok(count) => println!("saved {} keys to {}", count, self.path.display()),
+            Err(msg) => println!("{}", msg),
+        }
+    }
+
+    // The entries of the last snapshot, or nothing if there isn't one yet.
+    pub fn load(&self) -> Result<Vec<Entry<Value>>, String> {
+        match fs::read(&self.path) {
+            Ok(raw) => decode(&raw).map_err(|msg| format!("{}: {}", self.path.display(), msg)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(format!("failed to read {}: {}", self.path.display(), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use super::Snapshots;
+    use crate::protocol::Value;
+    use crate::stores::lru::LRUCache;
+    use crate::stores::Cacheable;
+
+    // Saves that overlap used to write the same temporary file at once, so one could rename
+    // it away from under another or move a file still being written into place.
+    #[test]
+    fn overlapping_saves_take_turns() {
+        let path = std::env::temp_dir().join(format!("kv-lake-snapshot-test-{}.kvls", std::process::id()));
+        let snapshots = Arc::new(Snapshots::new(path.clone()));
+        let mut cache = LRUCache::new(1000);
+        for i in 0..1000 {
+            cache.put(format!("key:{}", i).into_bytes(), Value::new(vec![b'v'; 100])).unwrap();
+        }
+        let cache = Arc::new(cache);
+        let savers: Vec<_> = (0..8).map(|_| {
+            let snapshots = Arc::clone(&snapshots);
+            let cache = Arc::clone(&cache);
+            thread::spawn(move || (0..20).map(|_| snapshots.save(&*cache)).collect::<Vec<_>>())
+        }).collect();
+        for saver in savers {
+            for saved in saver.join().unwrap() {
+                assert_eq!(saved, Ok(1000));
+            }
+        }
+        let loaded = snapshots.load();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded.map(|entries| entries.len()), Ok(1000));
+    }
+}
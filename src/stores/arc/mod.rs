@@ -0,0 +1,464 @@
+pub mod my_node;
+use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+use super::{ Entry, HeapSize, Stats, Store, Ttl, Visit };
+use super::budget::{ self, Budget, Weigher };
+use super::slab::{ List, Slab };
+use my_node::{ Ghost, Node };
+
+// Adaptive Replacement Cache (Megiddo and Modha, 2003). Resident entries are split between T1,
+// the ones used once since they came in, and T2, the ones used again since. Keys evicted from
+// either are remembered without their values in B1 and B2. A write for a key in B1 means T1 was
+// too small, so the share of the cache T1 aims for grows; one in B2 shrinks it again. That lets
+// the cache lean towards recency for scans and towards frequency for a stable hot set. Every
+// list is most recently used first.
+//
+// The lists are sized in entries, as in the paper. Under a byte budget or a Weigher, c (how many
+// entries the cache holds when full) is taken to be however many it holds at the moment.
+pub struct ArcCache<T: std::fmt::Display + std::clone::Clone> {
+    pub map: HashMap<Vec<u8>, u32>,
+    nodes: Slab<Node<T>>,
+    t1: List,
+    t2: List,
+    ghosts: HashMap<Vec<u8>, u32>,
+    ghost_nodes: Slab<Ghost>,
+    b1: List,
+    b2: List,
+    // How many entries T1 aims to hold, p in the paper.
+    target: u32,
+    budget: Budget<T>,
+    stats: Stats,
+    // Evicted entries waiting to be collected, once keep_evicted has been called.
+    evicted: Option<Vec<Entry<T>>>
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> ArcCache<T> {
+    pub fn new(queue_size: u32) -> ArcCache<T> {
+        ArcCache::with_budget(Budget::entries(queue_size))
+    }
+
+    pub fn with_weigher<W: Weigher<T> + Send + Sync + 'static>(max_weight: u64, weigher: W) -> ArcCache<T> {
+        ArcCache::with_budget(Budget::weighed(max_weight, weigher))
+    }
+
+    fn with_budget(budget: Budget<T>) -> ArcCache<T> {
+        ArcCache {
+            map: HashMap::new(),
+            nodes: Slab::new(),
+            t1: List::new(),
+            t2: List::new(),
+            ghosts: HashMap::new(),
+            ghost_nodes: Slab::new(),
+            b1: List::new(),
+            b2: List::new(),
+            target: 0,
+            budget,
+            stats: Stats::default(),
+            evicted: None
+        }
+    }
+
+    // c in the paper.
+    fn capacity(&self) -> u32 {
+        if self.budget.counts_entries() {
+            self.budget.max() as u32
+        } else {
+            (self.map.len() as u32).max(1)
+        }
+    }
+
+    fn unlink(&mut self, index: u32) {
+        let list = if self.nodes[index].meta { &mut self.t2 } else { &mut self.t1 };
+        self.nodes.unlink(list, index);
+    }
+
+    // Moves a resident entry to the front of T2, since it has been used again.
+    fn promote(&mut self, index: u32) {
+        self.unlink(index);
+        self.nodes.push_front(&mut self.t2, index);
+        self.nodes[index].meta = true;
+    }
+
+    // REPLACE in the paper: evicts the least recently used entry of T1 if it is over its target,
+    // or of T2 otherwise, into the matching ghost list. `b2_hit` is whether room is being made
+    // for a key found in B2. Returns false once there is nothing left to evict.
+    fn replace(&mut self, b2_hit: bool) -> bool {
+        let t1 = self.t1.len();
+        let from_t1 = t1 > 0 && (t1 > self.target || (b2_hit && t1 == self.target) || self.t2.is_empty());
+        let list = if from_t1 { &mut self.t1 } else { &mut self.t2 };
+        match self.nodes.pop_back(list) {
+            Some(index) => {
+                self.evict(index);
+                true
+            }
+            None => false
+        }
+    }
+
+    fn make_room(&mut self, weight: u64, b2_hit: bool) {
+        while !self.budget.fits(weight) {
+            if !self.replace(b2_hit) {
+                break;
+            }
+        }
+    }
+
+    // Drops an entry that has already been unlinked and remembers its key in a ghost list.
+    fn evict(&mut self, index: u32) {
+        let evicted = self.nodes.remove(index);
+        self.map.remove(&evicted.key);
+        self.budget.remove(self.budget.weigh(&evicted.key, &evicted.value));
+        self.stats.evictions += 1;
+        let ghost = self.ghost_nodes.insert(Ghost { key: evicted.key.clone(), frequent: evicted.meta });
+        let ghost_list = if evicted.meta { &mut self.b2 } else { &mut self.b1 };
+        self.ghost_nodes.push_front(ghost_list, ghost);
+        if let Some(kept) = self.evicted.as_mut() {
+            let frequency = if evicted.meta { 2 } else { 1 };
+            kept.push(Entry { key: evicted.key.clone(), value: evicted.value, expires_at: evicted.expires_at, frequency });
+        }
+        self.ghosts.insert(evicted.key, ghost);
+    }
+
+    fn forget_ghost(&mut self, index: u32) {
+        let frequent = self.ghost_nodes[index].frequent;
+        let ghost_list = if frequent { &mut self.b2 } else { &mut self.b1 };
+        self.ghost_nodes.unlink(ghost_list, index);
+        let ghost = self.ghost_nodes.remove(index);
+        self.ghosts.remove(&ghost.key);
+    }
+
+    fn drop_oldest_ghost(&mut self, frequent: bool) -> bool {
+        let ghost_list = if frequent { &self.b2 } else { &self.b1 };
+        match ghost_list.tail() {
+            Some(index) => {
+                self.forget_ghost(index);
+                true
+            }
+            None => false
+        }
+    }
+
+    // Keeps T1 and B1 to c keys between them and all four lists to 2c, as the paper does.
+    fn trim_ghosts(&mut self) {
+        let c = self.capacity();
+        while self.t1.len() + self.b1.len() > c && self.drop_oldest_ghost(false) {}
+        while self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() > 2 * c {
+            if !self.drop_oldest_ghost(true) && !self.drop_oldest_ghost(false) {
+                break;
+            }
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: T, expires_at: Option<Instant>) -> Result<(), String> {
+        let weight = self.budget.weigh(&key, &value);
+        self.budget.check(weight)?;
+        self.remove_if_expired(&key);
+        if let Some(index) = self.map.get(&key).copied() {
+            // Writing a key counts as using it again. It is kept out of make_room's reach while
+            // room is made for its new value.
+            self.budget.remove(self.budget.weigh(&key, &self.nodes[index].value));
+            self.unlink(index);
+            self.make_room(weight, false);
+            self.nodes.push_front(&mut self.t2, index);
+            let node = &mut self.nodes[index];
+            node.meta = true;
+            node.value = value;
+            node.expires_at = expires_at;
+            self.budget.add(weight);
+            self.stats.updates += 1;
+            return Ok(());
+        }
+        let frequent = match self.ghosts.get(&key).copied() {
+            Some(ghost) => {
+                let (b1, b2) = (self.b1.len().max(1), self.b2.len().max(1));
+                let b2_hit = self.ghost_nodes[ghost].frequent;
+                if b2_hit {
+                    self.target = self.target.saturating_sub((b1 / b2).max(1));
+                } else {
+                    self.target = (self.target + (b2 / b1).max(1)).min(self.capacity());
+                }
+                self.forget_ghost(ghost);
+                self.make_room(weight, b2_hit);
+                true
+            }
+            None => {
+                self.make_room(weight, false);
+                false
+            }
+        };
+        let mut node = Node::with_meta(key.clone(), value, frequent);
+        node.expires_at = expires_at;
+        let index = self.nodes.insert(node);
+        let list = if frequent { &mut self.t2 } else { &mut self.t1 };
+        self.nodes.push_front(list, index);
+        self.map.insert(key, index);
+        self.budget.add(weight);
+        self.stats.inserts += 1;
+        self.trim_ghosts();
+        Ok(())
+    }
+
+}
+
+impl<T: std::fmt::Display + std::clone::Clone + HeapSize> ArcCache<T> {
+    // Ghost keys are not counted in the budget.
+    pub fn with_max_bytes(max_bytes: u64) -> ArcCache<T> {
+        ArcCache::with_budget(Budget::bytes(max_bytes, budget::entry_bytes::<T, Node<T>>))
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> Store<T> for ArcCache<T> {
+    fn expiry(&mut self, key: &[u8]) -> Option<&mut Option<Instant>> {
+        let nodes = &mut self.nodes;
+        self.map.get(key).map(move |index| &mut nodes[*index].expires_at)
+    }
+
+    fn each(&self, f: &mut Visit<T>) {
+        for index in self.map.values() {
+            let node = &self.nodes[*index];
+            f(&node.key, &node.value, node.expires_at);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        if let Some(index) = self.map.remove(key) {
+            self.unlink(index);
+            let node = self.nodes.remove(index);
+            self.budget.remove(self.budget.weigh(&node.key, &node.value));
+            true
+        } else {
+            false
+        }
+    }
+
+    fn counters(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    fn evicted(&mut self) -> &mut Option<Vec<Entry<T>>> {
+        &mut self.evicted
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> super::Cacheable<T> for ArcCache<T> {
+    fn put(&mut self, key: Vec<u8>, value: T) -> Result<(), String> {
+        self.insert(key, value, None)
+    }
+
+    fn put_with_ttl(&mut self, key: Vec<u8>, value: T, ttl: Duration) -> Result<(), String> {
+        self.insert(key, value, Some(super::deadline(ttl)?))
+    }
+
+    fn get(&mut self, key: Vec<u8>) -> Option<T> {
+        self.remove_if_expired(&key);
+        if let Some(index) = self.map.get(&key).copied() {
+            self.promote(index);
+            self.stats.hits += 1;
+            Some(self.nodes[index].value.clone())
+        } else {
+            self.stats.misses += 1;
+            None
+        }
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> Result<(), String> {
+        if self.remove(&key) {
+            self.stats.deletes += 1;
+            Ok(())
+        } else {
+            Err("key not found".to_string())
+        }
+    }
+
+    fn ttl(&mut self, key: Vec<u8>) -> Ttl {
+        Store::ttl(self, &key)
+    }
+
+    fn persist(&mut self, key: Vec<u8>) -> bool {
+        Store::persist(self, &key)
+    }
+
+    fn remove_expired(&mut self) -> u32 {
+        Store::remove_expired(self)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats { size: self.map.len() as u64, ..self.stats };
+        self.budget.fill_stats(&mut stats);
+        stats
+    }
+
+    fn weight(&self) -> u64 {
+        self.budget.used()
+    }
+
+    fn max_weight(&self) -> u64 {
+        self.budget.max()
+    }
+
+    // T1 then T2, each front to back, since restored entries are appended to the back of the
+    // list their frequency puts them in. The ghost lists and T1's target are not kept.
+    fn entries(&self) -> Vec<Entry<T>> {
+        let mut entries = Vec::with_capacity(self.map.len());
+        for (list, frequency) in [(&self.t1, 1), (&self.t2, 2)] {
+            for (_, node) in self.nodes.iter(list) {
+                if !node.is_expired() {
+                    let entry = Entry {
+                        key: node.key.clone(),
+                        value: node.value.clone(),
+                        expires_at: node.expires_at,
+                        frequency
+                    };
+                    entries.push(entry);
+                }
+            }
+        }
+        entries
+    }
+
+    fn restore(&mut self, entry: Entry<T>) {
+        self.remove(&entry.key);
+        if let Some(ghost) = self.ghosts.get(&entry.key).copied() {
+            self.forget_ghost(ghost);
+        }
+        let weight = self.budget.weigh(&entry.key, &entry.value);
+        if self.budget.check(weight).is_err() {
+            return;
+        }
+        self.make_room(weight, false);
+        let frequent = entry.frequency >= 2;
+        let mut node = Node::with_meta(entry.key.clone(), entry.value, frequent);
+        node.expires_at = entry.expires_at;
+        let index = self.nodes.insert(node);
+        let list = if frequent { &mut self.t2 } else { &mut self.t1 };
+        self.nodes.push_back(list, index);
+        self.map.insert(entry.key, index);
+        self.budget.add(weight);
+        self.stats.inserts += 1;
+        self.trim_ghosts();
+    }
+
+    fn keep_evicted(&mut self) {
+        Store::keep_evicted(self)
+    }
+
+    fn take_evicted(&mut self) -> Vec<Entry<T>> {
+        Store::take_evicted(self)
+    }
+
+    fn print_map(&self) {
+        Store::print_map(self)
+    }
+
+    fn print_list(&self) {
+        println!("t1 (target {}):", self.target);
+        for (_, n) in self.nodes.iter(&self.t1) {
+            println!("{}", n.value);
+        }
+        println!("t2:");
+        for (_, n) in self.nodes.iter(&self.t2) {
+            println!("{}", n.value);
+        }
+        println!("ghosts: {} in b1, {} in b2", self.b1.len(), self.b2.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ArcCache;
+    use crate::stores::Cacheable;
+
+    fn key(k: &str) -> Vec<u8> {
+        k.as_bytes().to_vec()
+    }
+
+    // Reads each key in turn, putting it on a miss as a cache in front of a slower store would,
+    // and returns what was evicted at each step.
+    fn replay(cache: &mut ArcCache<String>, keys: &str) -> Vec<String> {
+        cache.keep_evicted();
+        keys.split(' ')
+            .map(|k| {
+                if cache.get(key(k)).is_none() {
+                    cache.put(key(k), k.to_string()).unwrap();
+                }
+                cache.take_evicted().into_iter().map(|entry| entry.value).collect::<Vec<_>>().join(" ")
+            })
+            .collect()
+    }
+
+    // Room for 4. B is evicted from T1 into B1, and coming back while it is there moves T1's
+    // target up by one and puts B straight into T2.
+    #[test]
+    fn a_hit_in_b1_grows_the_target() {
+        let mut cache = ArcCache::new(4);
+        let evicted = replay(&mut cache, "A B A C D E B");
+        assert_eq!(evicted, ["", "", "", "", "", "B", "C"]);
+        assert_eq!(cache.target, 1);
+        assert_eq!((cache.t1.len(), cache.t2.len(), cache.b1.len(), cache.b2.len()), (2, 2, 1, 0));
+    }
+
+    // Carrying on from the sequence above, T1 is held to its target of 1, so H evicts A from
+    // T2 into B2. A coming back shrinks the target by |B1| / |B2| = 2, down to 0.
+    #[test]
+    fn a_hit_in_b2_shrinks_the_target() {
+        let mut cache = ArcCache::new(4);
+        replay(&mut cache, "A B A C D E B");
+        let evicted = replay(&mut cache, "F G F H A");
+        assert_eq!(evicted, ["D", "E", "", "A", "G"]);
+        assert_eq!(cache.target, 0);
+        assert_eq!((cache.t1.len(), cache.t2.len(), cache.b1.len(), cache.b2.len()), (1, 3, 3, 0));
+    }
+
+    // T1 gives up its least recently used entry while it holds more than its target, or as
+    // many when room is being made for a key found in B2. Otherwise T2 does.
+    #[test]
+    fn replace_picks_t1_or_t2_by_the_target() {
+        let mut cache = ArcCache::new(4);
+        replay(&mut cache, "A B A B C D");
+        cache.keep_evicted();
+        let replace = |cache: &mut ArcCache<String>, target, b2_hit| {
+            cache.target = target;
+            assert!(cache.replace(b2_hit));
+            cache.take_evicted().into_iter().map(|entry| entry.value).collect::<Vec<_>>()
+        };
+        assert_eq!(replace(&mut cache, 1, false), ["C"]);
+        assert_eq!(replace(&mut cache, 1, false), ["A"]);
+        assert_eq!(replace(&mut cache, 1, true), ["D"]);
+        assert_eq!(replace(&mut cache, 0, false), ["B"]);
+        assert!(!cache.replace(false));
+    }
+
+    // T1 and B1 never hold more than c keys between them, nor all four lists more than 2c.
+    #[test]
+    fn ghost_lists_are_trimmed_to_the_capacity() {
+        let mut cache = ArcCache::new(4);
+        let mut seed = 1u32;
+        let mut most_in_b2 = 0;
+        for _ in 0..1000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let k = key(&((seed >> 16) % 12).to_string());
+            if cache.get(k.clone()).is_none() {
+                cache.put(k, String::new()).unwrap();
+            }
+            assert!(cache.t1.len() + cache.b1.len() <= 4);
+            assert!(cache.t1.len() + cache.t2.len() + cache.b1.len() + cache.b2.len() <= 8);
+            assert_eq!(cache.ghosts.len() as u32, cache.b1.len() + cache.b2.len());
+            most_in_b2 = most_in_b2.max(cache.b2.len());
+        }
+        assert!(most_in_b2 >= 2);
+    }
+
+    // Keys seen once only ever go through T1, and never come back to B1 to move the target,
+    // so a scan leaves the keys in T2 alone.
+    #[test]
+    fn a_scan_does_not_flush_t2() {
+        let mut cache = ArcCache::new(4);
+        replay(&mut cache, "A B A B");
+        let scan: Vec<String> = (0..20).map(|i| format!("s{}", i)).collect();
+        let evicted = replay(&mut cache, &scan.join(" "));
+        assert!(evicted.iter().all(|k| !k.contains('A') && !k.contains('B')));
+        assert_eq!(cache.target, 0);
+        assert_eq!(cache.get(key("A")), Some("A".to_string()));
+        assert_eq!(cache.get(key("B")), Some("B".to_string()));
+    }
+}
@@ -0,0 +1,13 @@
+use crate::stores::lru::my_node;
+
+// The meta is whether the entry is in T2, having been used again since it came in, rather
+// than T1.
+pub type Node<T> = my_node::Node<T, bool>;
+
+// A key that was evicted recently, kept without its value so that asking for it again soon
+// tells the cache which of its lists it should have kept longer.
+pub struct Ghost {
+    pub key: Vec<u8>,
+    // Whether it was evicted from T2 into B2 rather than from T1 into B1.
+    pub frequent: bool
+}
@@ -0,0 +1,111 @@
+// How full a cache is allowed to get, in whatever its entries are weighed by. A cache made with
+// a number of entries counts every entry as 1. One made with a number of bytes weighs each entry
+// by what it takes up in memory, so a 1MB value uses up as much of the budget as a million 1
+// byte ones. Anything else can be weighed by a Weigher of its own, such as what a value would
+// cost to compute again.
+use std::mem::size_of;
+use super::{ HeapSize, Stats };
+use super::slab::Slab;
+
+pub trait Weigher<T> {
+    // Has to weigh the same key and value the same every time, since an entry's weight is
+    // taken back off the budget by weighing it again when it leaves.
+    fn weigh(&self, key: &[u8], value: &T) -> u64;
+}
+
+impl<T, F: Fn(&[u8], &T) -> u64> Weigher<T> for F {
+    fn weigh(&self, key: &[u8], value: &T) -> u64 {
+        self(key, value)
+    }
+}
+
+enum Weighing<T> {
+    Count,
+    Bytes(fn(&[u8], &T) -> u64),
+    Custom(Box<dyn Weigher<T> + Send + Sync>)
+}
+
+pub struct Budget<T> {
+    weighing: Weighing<T>,
+    used: u64,
+    max: u64
+}
+
+// An entry's key and value along with its slot in the slab and the map, for a cache keeping
+// its entries as `N` in a Slab indexed from a HashMap.
+pub fn entry_bytes<T: HeapSize, N>(key: &[u8], value: &T) -> u64 {
+    // A HashMap<Vec<u8>, u32> keeps a control byte alongside each key and index.
+    let map_slot = size_of::<(Vec<u8>, u32)>() + 1;
+    (key.len() + value.heap_size() + Slab::<N>::SLOT_SIZE + map_slot) as u64
+}
+
+impl<T> Budget<T> {
+    pub fn entries(max: u32) -> Budget<T> {
+        Budget { weighing: Weighing::Count, used: 0, max: max as u64 }
+    }
+
+    // `weigh` is usually entry_bytes for the cache's own node type.
+    pub fn bytes(max: u64, weigh: fn(&[u8], &T) -> u64) -> Budget<T> {
+        Budget { weighing: Weighing::Bytes(weigh), used: 0, max }
+    }
+
+    pub fn weighed<W: Weigher<T> + Send + Sync + 'static>(max: u64, weigher: W) -> Budget<T> {
+        Budget { weighing: Weighing::Custom(Box::new(weigher)), used: 0, max }
+    }
+
+    pub fn weigh(&self, key: &[u8], value: &T) -> u64 {
+        match &self.weighing {
+            Weighing::Count => 1,
+            Weighing::Bytes(weigh) => weigh(key, value),
+            Weighing::Custom(weigher) => weigher.weigh(key, value)
+        }
+    }
+
+    // Whether an entry weighing `weight` can go in without evicting anything.
+    pub fn fits(&self, weight: u64) -> bool {
+        self.used.checked_add(weight).is_some_and(|total| total <= self.max)
+    }
+
+    // Fails for an entry weighing `weight` that wouldn't fit even in an empty cache.
+    pub fn check(&self, weight: u64) -> Result<(), String> {
+        if weight > self.max {
+            Err("value is larger than the whole cache".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn add(&mut self, weight: u64) {
+        self.used += weight;
+    }
+
+    pub fn remove(&mut self, weight: u64) {
+        self.used -= weight;
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    // Whether every entry weighs 1, so the budget is a number of entries.
+    pub fn counts_entries(&self) -> bool {
+        matches!(self.weighing, Weighing::Count)
+    }
+
+    // Fills in the capacity the budget stands for, or the bytes in use if it counts bytes.
+    // Budgets weighed some other way leave both alone.
+    pub fn fill_stats(&self, stats: &mut Stats) {
+        match self.weighing {
+            Weighing::Count => stats.capacity = self.max,
+            Weighing::Bytes(_) => {
+                stats.bytes = self.used;
+                stats.max_bytes = self.max;
+            }
+            Weighing::Custom(_) => {}
+        }
+    }
+}
@@ -0,0 +1,351 @@
+pub mod my_node;
+use std::collections::HashMap;
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::time::{ Duration, Instant };
+use super::{ Entry, HeapSize, Stats, Store, Ttl, Visit };
+use super::budget::{ self, Budget, Weigher };
+use super::slab::{ List, Slab };
+use my_node::Node;
+
+// CLOCK, an approximation of LRU that leaves its list alone on a hit. Using an entry only sets
+// its reference bit, so get_shared can serve reads through a shared reference, with the cache
+// behind a read lock. Entries are kept in the order they came in, and the hand sits at the
+// front of the list: to make room it evicts the entry there if its bit is clear, or clears the
+// bit and moves the entry to the back to give it a second chance.
+pub struct ClockCache<T: std::fmt::Display + std::clone::Clone> {
+    pub map: HashMap<Vec<u8>, u32>,
+    nodes: Slab<Node<T>>,
+    list: List,
+    budget: Budget<T>,
+    stats: Stats,
+    // Hits and misses counted by get_shared, which can't get at `stats`.
+    shared_hits: AtomicU64,
+    shared_misses: AtomicU64,
+    // Evicted entries waiting to be collected, once keep_evicted has been called.
+    evicted: Option<Vec<Entry<T>>>
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> ClockCache<T> {
+    pub fn new(queue_size: u32) -> ClockCache<T> {
+        ClockCache::with_budget(Budget::entries(queue_size))
+    }
+
+    pub fn with_weigher<W: Weigher<T> + Send + Sync + 'static>(max_weight: u64, weigher: W) -> ClockCache<T> {
+        ClockCache::with_budget(Budget::weighed(max_weight, weigher))
+    }
+
+    fn with_budget(budget: Budget<T>) -> ClockCache<T> {
+        ClockCache {
+            map: HashMap::new(),
+            nodes: Slab::new(),
+            list: List::new(),
+            budget,
+            stats: Stats::default(),
+            shared_hits: AtomicU64::new(0),
+            shared_misses: AtomicU64::new(0),
+            evicted: None
+        }
+    }
+
+    // Sweeps the hand until an entry weighing `weight` fits.
+    fn make_room(&mut self, weight: u64) {
+        while !self.budget.fits(weight) {
+            let index = match self.list.head() {
+                Some(index) => index,
+                None => break
+            };
+            if self.nodes[index].meta.swap(false, Ordering::Relaxed) {
+                self.nodes.unlink(&mut self.list, index);
+                self.nodes.push_back(&mut self.list, index);
+            } else {
+                self.nodes.unlink(&mut self.list, index);
+                self.evict(index);
+            }
+        }
+    }
+
+    // Drops an entry that has already been unlinked to make room for another.
+    fn evict(&mut self, index: u32) {
+        let evicted = self.nodes.remove(index);
+        self.map.remove(&evicted.key);
+        self.budget.remove(self.budget.weigh(&evicted.key, &evicted.value));
+        self.stats.evictions += 1;
+        if let Some(kept) = self.evicted.as_mut() {
+            kept.push(Entry { key: evicted.key, value: evicted.value, expires_at: evicted.expires_at, frequency: 0 });
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: T, expires_at: Option<Instant>) -> Result<(), String> {
+        let weight = self.budget.weigh(&key, &value);
+        self.budget.check(weight)?;
+        self.remove_if_expired(&key);
+        if let Some(index) = self.map.get(&key).copied() {
+            // Writing a key counts as using it. It is kept out of the hand's reach while room
+            // is made for its new value.
+            self.nodes.unlink(&mut self.list, index);
+            self.budget.remove(self.budget.weigh(&key, &self.nodes[index].value));
+            self.make_room(weight);
+            self.nodes.push_back(&mut self.list, index);
+            let node = &mut self.nodes[index];
+            node.value = value;
+            node.expires_at = expires_at;
+            *node.meta.get_mut() = true;
+            self.budget.add(weight);
+            self.stats.updates += 1;
+        } else {
+            let mut node = Node::with_meta(key.clone(), value, AtomicBool::new(false));
+            node.expires_at = expires_at;
+            self.make_room(weight);
+            let index = self.nodes.insert(node);
+            self.nodes.push_back(&mut self.list, index);
+            self.map.insert(key, index);
+            self.budget.add(weight);
+            self.stats.inserts += 1;
+        }
+        Ok(())
+    }
+
+}
+
+impl<T: std::fmt::Display + std::clone::Clone + HeapSize> ClockCache<T> {
+    pub fn with_max_bytes(max_bytes: u64) -> ClockCache<T> {
+        ClockCache::with_budget(Budget::bytes(max_bytes, budget::entry_bytes::<T, Node<T>>))
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> Store<T> for ClockCache<T> {
+    fn expiry(&mut self, key: &[u8]) -> Option<&mut Option<Instant>> {
+        let nodes = &mut self.nodes;
+        self.map.get(key).map(move |index| &mut nodes[*index].expires_at)
+    }
+
+    fn each(&self, f: &mut Visit<T>) {
+        for index in self.map.values() {
+            let node = &self.nodes[*index];
+            f(&node.key, &node.value, node.expires_at);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        if let Some(index) = self.map.remove(key) {
+            self.nodes.unlink(&mut self.list, index);
+            let node = self.nodes.remove(index);
+            self.budget.remove(self.budget.weigh(&node.key, &node.value));
+            true
+        } else {
+            false
+        }
+    }
+
+    fn counters(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    fn evicted(&mut self) -> &mut Option<Vec<Entry<T>>> {
+        &mut self.evicted
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> super::Cacheable<T> for ClockCache<T> {
+    fn put(&mut self, key: Vec<u8>, value: T) -> Result<(), String> {
+        self.insert(key, value, None)
+    }
+
+    fn put_with_ttl(&mut self, key: Vec<u8>, value: T, ttl: Duration) -> Result<(), String> {
+        self.insert(key, value, Some(super::deadline(ttl)?))
+    }
+
+    fn get(&mut self, key: Vec<u8>) -> Option<T> {
+        self.remove_if_expired(&key);
+        if let Some(index) = self.map.get(&key) {
+            let node = &mut self.nodes[*index];
+            *node.meta.get_mut() = true;
+            self.stats.hits += 1;
+            Some(node.value.clone())
+        } else {
+            self.stats.misses += 1;
+            None
+        }
+    }
+
+    // Expired entries are treated as missing but left for a later get or remove_expired to drop.
+    fn get_shared(&self, key: &[u8]) -> Option<Option<T>> {
+        match self.map.get(key).map(|index| &self.nodes[*index]) {
+            Some(node) if !node.is_expired() => {
+                node.meta.store(true, Ordering::Relaxed);
+                self.shared_hits.fetch_add(1, Ordering::Relaxed);
+                Some(Some(node.value.clone()))
+            }
+            _ => {
+                self.shared_misses.fetch_add(1, Ordering::Relaxed);
+                Some(None)
+            }
+        }
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> Result<(), String> {
+        if self.remove(&key) {
+            self.stats.deletes += 1;
+            Ok(())
+        } else {
+            Err("key not found".to_string())
+        }
+    }
+
+    fn ttl(&mut self, key: Vec<u8>) -> Ttl {
+        Store::ttl(self, &key)
+    }
+
+    fn persist(&mut self, key: Vec<u8>) -> bool {
+        Store::persist(self, &key)
+    }
+
+    fn remove_expired(&mut self) -> u32 {
+        Store::remove_expired(self)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            size: self.map.len() as u64,
+            hits: self.stats.hits + self.shared_hits.load(Ordering::Relaxed),
+            misses: self.stats.misses + self.shared_misses.load(Ordering::Relaxed),
+            ..self.stats
+        };
+        self.budget.fill_stats(&mut stats);
+        stats
+    }
+
+    fn weight(&self) -> u64 {
+        self.budget.used()
+    }
+
+    fn max_weight(&self) -> u64 {
+        self.budget.max()
+    }
+
+    // From the hand round to the entry that came in last, since each restored entry is
+    // appended to the back of the list. Entries whose reference bit is set have a frequency
+    // of 1.
+    fn entries(&self) -> Vec<Entry<T>> {
+        self.nodes.iter(&self.list)
+            .map(|(_, node)| node)
+            .filter(|node| !node.is_expired())
+            .map(|node| Entry {
+                key: node.key.clone(),
+                value: node.value.clone(),
+                expires_at: node.expires_at,
+                frequency: node.meta.load(Ordering::Relaxed) as u32
+            })
+            .collect()
+    }
+
+    fn restore(&mut self, entry: Entry<T>) {
+        self.remove(&entry.key);
+        let referenced = entry.frequency > 0;
+        let key = entry.key.clone();
+        if self.insert(entry.key, entry.value, entry.expires_at).is_ok() && referenced {
+            if let Some(index) = self.map.get(&key) {
+                *self.nodes[*index].meta.get_mut() = true;
+            }
+        }
+    }
+
+    fn keep_evicted(&mut self) {
+        Store::keep_evicted(self)
+    }
+
+    fn take_evicted(&mut self) -> Vec<Entry<T>> {
+        Store::take_evicted(self)
+    }
+
+    fn print_map(&self) {
+        Store::print_map(self)
+    }
+
+    fn print_list(&self) {
+        for (_, n) in self.nodes.iter(&self.list) {
+            let mark = if n.meta.load(Ordering::Relaxed) { "*" } else { "" };
+            println!("{}{}", n.value, mark);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClockCache;
+    use crate::stores::Cacheable;
+
+    fn key(k: &str) -> Vec<u8> {
+        k.as_bytes().to_vec()
+    }
+
+    // Runs ops such as "pA gA sA", putting, getting or getting through get_shared the key after
+    // the letter, and returns what was evicted at each step.
+    fn replay(cache: &mut ClockCache<String>, ops: &str) -> Vec<String> {
+        cache.keep_evicted();
+        ops.split(' ')
+            .map(|op| {
+                let (kind, k) = op.split_at(1);
+                match kind {
+                    "p" => cache.put(key(k), k.to_string()).unwrap(),
+                    "g" => {
+                        cache.get(key(k));
+                    }
+                    _ => {
+                        cache.get_shared(&key(k));
+                    }
+                }
+                cache.take_evicted().into_iter().map(|entry| entry.value).collect::<Vec<_>>().join(" ")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn evicts_in_the_order_keys_came_in_when_none_are_used() {
+        let mut cache = ClockCache::new(3);
+        let evicted = replay(&mut cache, "pA pB pC pD pE");
+        assert_eq!(evicted, ["", "", "", "A", "B"]);
+    }
+
+    // The hand clears A's bit and passes it over once, then evicts it when it comes round again.
+    #[test]
+    fn a_used_key_gets_a_second_chance() {
+        let mut cache = ClockCache::new(3);
+        let evicted = replay(&mut cache, "pA pB pC gA pD pE pF");
+        assert_eq!(evicted, ["", "", "", "", "B", "C", "A"]);
+    }
+
+    #[test]
+    fn get_shared_sets_the_reference_bit() {
+        let mut cache = ClockCache::new(3);
+        let evicted = replay(&mut cache, "pA pB pC sA pD pE pF");
+        assert_eq!(evicted, ["", "", "", "", "B", "C", "A"]);
+    }
+
+    #[test]
+    fn writing_a_key_counts_as_using_it() {
+        let mut cache = ClockCache::new(3);
+        let evicted = replay(&mut cache, "pA pB pC pA pD");
+        assert_eq!(evicted, ["", "", "", "", "B"]);
+    }
+
+    // With every bit set the hand clears them all going round, and evicts where it started.
+    #[test]
+    fn evicts_where_the_hand_started_once_every_key_was_used() {
+        let mut cache = ClockCache::new(3);
+        let evicted = replay(&mut cache, "pA pB pC gC gB gA pD pE");
+        assert_eq!(evicted, ["", "", "", "", "", "", "A", "B"]);
+    }
+
+    // get_shared can't drop expired entries, but doesn't hand them out either.
+    #[test]
+    fn get_shared_misses_expired_entries() {
+        let mut cache = ClockCache::new(3);
+        cache.put_with_ttl(key("A"), "A".to_string(), std::time::Duration::from_nanos(1)).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        assert_eq!(cache.get_shared(&key("A")), Some(None));
+        assert_eq!(cache.stats().misses, 1);
+        assert_eq!(cache.get(key("A")), None);
+        assert_eq!(cache.stats().expirations, 1);
+    }
+}
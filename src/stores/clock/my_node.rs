@@ -0,0 +1,6 @@
+use std::sync::atomic::AtomicBool;
+use crate::stores::lru::my_node;
+
+// The meta is the reference bit, set whenever the entry is used and cleared as the hand passes
+// it. Atomic so that a read only needs a shared reference to the cache.
+pub type Node<T> = my_node::Node<T, AtomicBool>;
@@ -0,0 +1,552 @@
+pub mod my_node;
+use std::collections::HashMap;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::{ Duration, Instant };
+use super::{ Entry, HeapSize, Stats, Store, Ttl, Visit };
+use super::budget::{ self, Budget, Weigher };
+use super::slab::{ List, Slab };
+use my_node::{ Kind, Page, PageState };
+
+// CLOCK-Pro (Jiang, Chen and Zhang, 2005), CLOCK with the scan resistance of LIRS. Pages are hot
+// or cold, and all of them sit on one circular list in the order they came in, along with test
+// pages: keys recently evicted from the cold pages, kept without their values. Three hands go
+// round it. The cold hand evicts unreferenced cold pages, turning them into test pages, and
+// promotes referenced ones to hot. The hot hand demotes unreferenced hot pages once they take
+// up more than their share. The test hand drops test pages. A key written again while it is a
+// test page comes back hot, and grows the share of the cache cold pages get; a test page the
+// test hand drops shrinks it again.
+//
+// As with CLOCK, a hit only sets a reference bit, so get_shared can serve reads through a
+// shared reference. The cold target is sized in whatever the cache's entries are weighed by.
+// Test pages are capped at as many as the cache holds entries, which under a byte budget or a
+// Weigher is however many it holds at the moment.
+pub struct ClockProCache<T: std::fmt::Display + std::clone::Clone> {
+    pub map: HashMap<Vec<u8>, u32>,
+    pages: Slab<Page<T>>,
+    clock: List,
+    hand_hot: Option<u32>,
+    hand_cold: Option<u32>,
+    hand_test: Option<u32>,
+    hot_weight: u64,
+    cold_count: u32,
+    test_count: u32,
+    // How much of the budget cold pages aim to take up, m_c in the paper.
+    cold_target: u64,
+    budget: Budget<T>,
+    stats: Stats,
+    // Hits and misses counted by get_shared, which can't get at `stats`.
+    shared_hits: AtomicU64,
+    shared_misses: AtomicU64,
+    // Evicted entries waiting to be collected, once keep_evicted has been called.
+    evicted: Option<Vec<Entry<T>>>
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> ClockProCache<T> {
+    pub fn new(queue_size: u32) -> ClockProCache<T> {
+        ClockProCache::with_budget(Budget::entries(queue_size))
+    }
+
+    pub fn with_weigher<W: Weigher<T> + Send + Sync + 'static>(max_weight: u64, weigher: W) -> ClockProCache<T> {
+        ClockProCache::with_budget(Budget::weighed(max_weight, weigher))
+    }
+
+    fn with_budget(budget: Budget<T>) -> ClockProCache<T> {
+        ClockProCache {
+            map: HashMap::new(),
+            pages: Slab::new(),
+            clock: List::new(),
+            hand_hot: None,
+            hand_cold: None,
+            hand_test: None,
+            hot_weight: 0,
+            cold_count: 0,
+            test_count: 0,
+            cold_target: budget.max(),
+            budget,
+            stats: Stats::default(),
+            shared_hits: AtomicU64::new(0),
+            shared_misses: AtomicU64::new(0),
+            evicted: None
+        }
+    }
+
+    fn resident(&self) -> u32 {
+        self.map.len() as u32 - self.test_count
+    }
+
+    // The most test pages there may be.
+    fn test_max(&self) -> u32 {
+        if self.budget.counts_entries() {
+            self.budget.max() as u32
+        } else {
+            self.resident().max(1)
+        }
+    }
+
+    // The page after `index` going round the clock.
+    fn next(&self, index: u32) -> Option<u32> {
+        self.pages.next(index).or(self.clock.head())
+    }
+
+    // Puts a page in the clock just behind the hot hand, where it is the last any hand reaches.
+    fn attach(&mut self, index: u32) {
+        match self.hand_hot.and_then(|hand| self.pages.prev(hand)) {
+            Some(before) => self.pages.insert_after(&mut self.clock, before, index),
+            None => self.pages.push_back(&mut self.clock, index)
+        }
+        if self.hand_hot.is_none() {
+            self.hand_hot = Some(index);
+            self.hand_cold = Some(index);
+            self.hand_test = Some(index);
+        }
+        let page = &self.pages[index];
+        match page.meta.kind {
+            Kind::Hot => self.hot_weight += page.meta.weight,
+            Kind::Cold => self.cold_count += 1,
+            Kind::Test => self.test_count += 1
+        }
+    }
+
+    // Takes a page out of the clock, moving any hand on it on to the page after.
+    fn detach(&mut self, index: u32) {
+        let next = self.next(index).filter(|next| *next != index);
+        for hand in [&mut self.hand_hot, &mut self.hand_cold, &mut self.hand_test] {
+            if *hand == Some(index) {
+                *hand = next;
+            }
+        }
+        self.pages.unlink(&mut self.clock, index);
+        let page = &self.pages[index];
+        match page.meta.kind {
+            Kind::Hot => self.hot_weight -= page.meta.weight,
+            Kind::Cold => self.cold_count -= 1,
+            Kind::Test => self.test_count -= 1
+        }
+    }
+
+    // Drops a page altogether.
+    fn forget(&mut self, index: u32) -> Page<T> {
+        self.detach(index);
+        let page = self.pages.remove(index);
+        self.map.remove(&page.key);
+        page
+    }
+
+    // Runs the cold hand until an entry weighing `weight` fits. The hot hand is run instead
+    // while there are no cold pages for the cold hand to evict.
+    fn make_room(&mut self, weight: u64) {
+        while !self.budget.fits(weight) {
+            if self.cold_count > 0 {
+                self.run_hand_cold();
+            } else if self.hot_weight > 0 {
+                self.run_hand_hot();
+            } else {
+                break;
+            }
+        }
+    }
+
+    // Moves the cold hand on one page. A cold page used since the hand last passed it turns hot,
+    // and one that wasn't is evicted. Hot pages are then demoted until cold ones have their room.
+    // Each hand moves on before it deals with its page, as dealing with it can drop the page.
+    fn run_hand_cold(&mut self) {
+        let hand = match self.hand_cold {
+            Some(hand) => hand,
+            None => return
+        };
+        self.hand_cold = self.next(hand);
+        if self.pages[hand].meta.kind == Kind::Cold {
+            if self.pages[hand].meta.referenced.swap(false, Ordering::Relaxed) {
+                let page = &mut self.pages[hand];
+                page.meta.kind = Kind::Hot;
+                self.cold_count -= 1;
+                self.hot_weight += page.meta.weight;
+            } else {
+                self.evict(hand);
+                while self.test_count > self.test_max() {
+                    self.run_hand_test();
+                }
+            }
+        }
+        while self.hot_weight > self.budget.max().saturating_sub(self.cold_target) {
+            self.run_hand_hot();
+        }
+    }
+
+    // Moves the hot hand on one page, demoting a hot page that wasn't used since the hand last
+    // passed it. A test page it passes is dropped, having been in the clock longer than any hot
+    // page.
+    fn run_hand_hot(&mut self) {
+        let hand = match self.hand_hot {
+            Some(hand) => hand,
+            None => return
+        };
+        self.hand_hot = self.next(hand);
+        let page = &mut self.pages[hand];
+        match page.meta.kind {
+            Kind::Hot => {
+                if !page.meta.referenced.swap(false, Ordering::Relaxed) {
+                    page.meta.kind = Kind::Cold;
+                    self.hot_weight -= page.meta.weight;
+                    self.cold_count += 1;
+                }
+            }
+            Kind::Cold => (),
+            Kind::Test => self.end_test(hand)
+        }
+    }
+
+    // Moves the test hand on one page, dropping it if it is a test page.
+    fn run_hand_test(&mut self) {
+        let hand = match self.hand_test {
+            Some(hand) => hand,
+            None => return
+        };
+        self.hand_test = self.next(hand);
+        if self.pages[hand].meta.kind == Kind::Test {
+            self.end_test(hand);
+        }
+    }
+
+    // Drops a test page whose test period ran out without the key coming back, so cold pages
+    // need less room.
+    fn end_test(&mut self, index: u32) {
+        let page = self.forget(index);
+        self.cold_target = self.cold_target.saturating_sub(page.meta.weight).max(1);
+    }
+
+    // Turns an unreferenced cold page into a test page, dropping its value.
+    fn evict(&mut self, index: u32) {
+        let page = &mut self.pages[index];
+        let value = page.value.take().unwrap();
+        page.meta.kind = Kind::Test;
+        self.cold_count -= 1;
+        self.test_count += 1;
+        self.budget.remove(page.meta.weight);
+        self.stats.evictions += 1;
+        if let Some(kept) = self.evicted.as_mut() {
+            kept.push(Entry { key: page.key.clone(), value, expires_at: page.expires_at, frequency: 1 });
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: T, expires_at: Option<Instant>) -> Result<(), String> {
+        let weight = self.budget.weigh(&key, &value);
+        self.budget.check(weight)?;
+        self.remove_if_expired(&key);
+        let kind = match self.map.get(&key).copied() {
+            Some(index) if self.pages[index].meta.kind != Kind::Test => {
+                // Writing a key counts as using it. It is kept out of the hands' reach while
+                // room is made for its new value.
+                self.detach(index);
+                self.budget.remove(self.pages[index].meta.weight);
+                self.make_room(weight);
+                let page = &mut self.pages[index];
+                page.value = Some(value);
+                page.expires_at = expires_at;
+                page.meta.weight = weight;
+                *page.meta.referenced.get_mut() = true;
+                self.attach(index);
+                self.budget.add(weight);
+                self.stats.updates += 1;
+                return Ok(());
+            }
+            Some(index) => {
+                // Back within its test period, so cold pages needed more room.
+                self.cold_target = (self.cold_target + weight).min(self.budget.max());
+                self.forget(index);
+                Kind::Hot
+            }
+            None => Kind::Cold
+        };
+        self.make_room(weight);
+        let mut page = Page::with_meta(key.clone(), Some(value), PageState::new(weight, kind));
+        page.expires_at = expires_at;
+        let index = self.pages.insert(page);
+        self.map.insert(key, index);
+        self.attach(index);
+        self.budget.add(weight);
+        self.stats.inserts += 1;
+        Ok(())
+    }
+
+    // The pages going round the clock from the hot hand.
+    fn pages_from_hot_hand(&self) -> Vec<u32> {
+        let mut indexes = Vec::with_capacity(self.map.len());
+        let mut at = self.hand_hot;
+        while let Some(index) = at {
+            indexes.push(index);
+            at = self.next(index).filter(|next| Some(*next) != self.hand_hot);
+        }
+        indexes
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone + HeapSize> ClockProCache<T> {
+    // Test pages are not counted in the budget.
+    pub fn with_max_bytes(max_bytes: u64) -> ClockProCache<T> {
+        ClockProCache::with_budget(Budget::bytes(max_bytes, budget::entry_bytes::<T, Page<T>>))
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> Store<T> for ClockProCache<T> {
+    fn expiry(&mut self, key: &[u8]) -> Option<&mut Option<Instant>> {
+        let pages = &mut self.pages;
+        self.map.get(key)
+            .map(move |index| &mut pages[*index])
+            .filter(|entry| entry.value.is_some())
+            .map(|entry| &mut entry.expires_at)
+    }
+
+    fn each(&self, f: &mut Visit<T>) {
+        for index in self.map.values() {
+            let entry = &self.pages[*index];
+            if let Some(value) = &entry.value {
+                f(&entry.key, value, entry.expires_at);
+            }
+        }
+    }
+
+    // Drops the key if it is in the cache. Test pages are left alone.
+    fn remove(&mut self, key: &[u8]) -> bool {
+        match self.map.get(key).copied() {
+            Some(index) if self.pages[index].meta.kind != Kind::Test => {
+                let page = self.forget(index);
+                self.budget.remove(page.meta.weight);
+                true
+            }
+            _ => false
+        }
+    }
+
+    fn counters(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    fn evicted(&mut self) -> &mut Option<Vec<Entry<T>>> {
+        &mut self.evicted
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> super::Cacheable<T> for ClockProCache<T> {
+    fn put(&mut self, key: Vec<u8>, value: T) -> Result<(), String> {
+        self.insert(key, value, None)
+    }
+
+    fn put_with_ttl(&mut self, key: Vec<u8>, value: T, ttl: Duration) -> Result<(), String> {
+        self.insert(key, value, Some(super::deadline(ttl)?))
+    }
+
+    fn get(&mut self, key: Vec<u8>) -> Option<T> {
+        self.remove_if_expired(&key);
+        let pages = &mut self.pages;
+        match self.map.get(&key).map(|index| &mut pages[*index]) {
+            Some(page) if page.value.is_some() => {
+                *page.meta.referenced.get_mut() = true;
+                self.stats.hits += 1;
+                page.value.clone()
+            }
+            _ => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    // Expired entries are treated as missing but left for a later get or remove_expired to drop.
+    fn get_shared(&self, key: &[u8]) -> Option<Option<T>> {
+        match self.map.get(key).map(|index| &self.pages[*index]) {
+            Some(page) if page.value.is_some() && !page.is_expired() => {
+                page.meta.referenced.store(true, Ordering::Relaxed);
+                self.shared_hits.fetch_add(1, Ordering::Relaxed);
+                Some(page.value.clone())
+            }
+            _ => {
+                self.shared_misses.fetch_add(1, Ordering::Relaxed);
+                Some(None)
+            }
+        }
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> Result<(), String> {
+        if self.remove(&key) {
+            self.stats.deletes += 1;
+            Ok(())
+        } else {
+            Err("key not found".to_string())
+        }
+    }
+
+    fn ttl(&mut self, key: Vec<u8>) -> Ttl {
+        Store::ttl(self, &key)
+    }
+
+    fn persist(&mut self, key: Vec<u8>) -> bool {
+        Store::persist(self, &key)
+    }
+
+    fn remove_expired(&mut self) -> u32 {
+        Store::remove_expired(self)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            size: self.resident() as u64,
+            hits: self.stats.hits + self.shared_hits.load(Ordering::Relaxed),
+            misses: self.stats.misses + self.shared_misses.load(Ordering::Relaxed),
+            ..self.stats
+        };
+        self.budget.fill_stats(&mut stats);
+        stats
+    }
+
+    fn weight(&self) -> u64 {
+        self.budget.used()
+    }
+
+    fn max_weight(&self) -> u64 {
+        self.budget.max()
+    }
+
+    // Going round from the hot hand, since each restored page goes in just behind it. Hot pages
+    // have a frequency of 2 and cold ones 1. Test pages, reference bits and the cold target are
+    // not kept.
+    fn entries(&self) -> Vec<Entry<T>> {
+        self.pages_from_hot_hand().into_iter()
+            .map(|index| &self.pages[index])
+            .filter(|page| !page.is_expired())
+            .filter_map(|page| {
+                let frequency = if page.meta.kind == Kind::Hot { 2 } else { 1 };
+                page.value.clone().map(|value| Entry { key: page.key.clone(), value, expires_at: page.expires_at, frequency })
+            })
+            .collect()
+    }
+
+    fn restore(&mut self, entry: Entry<T>) {
+        if let Some(index) = self.map.get(&entry.key).copied() {
+            let page = self.forget(index);
+            if page.value.is_some() {
+                self.budget.remove(page.meta.weight);
+            }
+        }
+        let weight = self.budget.weigh(&entry.key, &entry.value);
+        if self.budget.check(weight).is_err() {
+            return;
+        }
+        self.make_room(weight);
+        let kind = if entry.frequency >= 2 { Kind::Hot } else { Kind::Cold };
+        let mut page = Page::with_meta(entry.key.clone(), Some(entry.value), PageState::new(weight, kind));
+        page.expires_at = entry.expires_at;
+        let index = self.pages.insert(page);
+        self.map.insert(entry.key, index);
+        self.attach(index);
+        self.budget.add(weight);
+        self.stats.inserts += 1;
+    }
+
+    fn keep_evicted(&mut self) {
+        Store::keep_evicted(self)
+    }
+
+    fn take_evicted(&mut self) -> Vec<Entry<T>> {
+        Store::take_evicted(self)
+    }
+
+    fn print_map(&self) {
+        Store::print_map(self)
+    }
+
+    fn print_list(&self) {
+        println!("cold target: {}", self.cold_target);
+        for index in self.pages_from_hot_hand() {
+            let page = &self.pages[index];
+            let kind = match page.meta.kind {
+                Kind::Hot => "hot",
+                Kind::Cold => "cold",
+                Kind::Test => "test"
+            };
+            let mark = if page.meta.referenced.load(Ordering::Relaxed) { "*" } else { "" };
+            match &page.value {
+                Some(value) => println!("{} {}{}", kind, value, mark),
+                None => println!("{} {}", kind, String::from_utf8_lossy(&page.key))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClockProCache;
+    use crate::stores::Cacheable;
+
+    fn key(k: &str) -> Vec<u8> {
+        k.as_bytes().to_vec()
+    }
+
+    // Runs ops such as "pA gA sA", putting, getting or getting through get_shared the key after
+    // the letter, and returns what was evicted at each step.
+    fn replay(cache: &mut ClockProCache<String>, ops: &str) -> Vec<String> {
+        cache.keep_evicted();
+        ops.split(' ')
+            .map(|op| {
+                let (kind, k) = op.split_at(1);
+                match kind {
+                    "p" => cache.put(key(k), k.to_string()).unwrap(),
+                    "g" => {
+                        cache.get(key(k));
+                    }
+                    _ => {
+                        cache.get_shared(&key(k));
+                    }
+                }
+                cache.take_evicted().into_iter().map(|entry| entry.value).collect::<Vec<_>>().join(" ")
+            })
+            .collect()
+    }
+
+    // Snapshots mark hot pages with a frequency of 2 and cold ones with 1.
+    fn is_hot(cache: &ClockProCache<String>, k: &str) -> bool {
+        cache.entries().into_iter().any(|entry| entry.key == key(k) && entry.frequency == 2)
+    }
+
+    // Every new key is cold, and the cold hand evicts the ones that weren't used oldest first.
+    #[test]
+    fn evicts_unused_cold_pages_in_the_order_they_came_in() {
+        let mut cache = ClockProCache::new(3);
+        let evicted = replay(&mut cache, "pA pB pC pD pE pF pG pH");
+        assert_eq!(evicted, ["", "", "", "A", "B", "C", "D", "E"]);
+    }
+
+    #[test]
+    fn the_cold_hand_passes_over_a_used_cold_page() {
+        let mut cache = ClockProCache::new(3);
+        let evicted = replay(&mut cache, "pA pB pC gA pD pE");
+        assert_eq!(evicted, ["", "", "", "", "B", "C"]);
+    }
+
+    #[test]
+    fn get_shared_sets_the_reference_bit() {
+        let mut cache = ClockProCache::new(3);
+        let evicted = replay(&mut cache, "pA pB pC sA pD pE");
+        assert_eq!(evicted, ["", "", "", "", "B", "C"]);
+    }
+
+    // A comes back while it is a test page, so it is hot from then on and outlasts the keys
+    // going through the cold pages only once.
+    #[test]
+    fn a_key_back_within_its_test_period_is_hot() {
+        let mut cache = ClockProCache::new(3);
+        let evicted = replay(&mut cache, "pA pB pC pD pA pE pF pG");
+        assert_eq!(evicted, ["", "", "", "A", "B", "C", "D", "E"]);
+        assert!(is_hot(&cache, "A"));
+    }
+
+    // Test pages are capped at as many as the cache holds, so the test hand has dropped A by
+    // the time it comes back and it starts over as a cold page.
+    #[test]
+    fn a_key_back_after_its_test_period_is_cold() {
+        let mut cache = ClockProCache::new(3);
+        let evicted = replay(&mut cache, "pA pB pC pD pE pF pG pH pI pA");
+        assert_eq!(evicted, ["", "", "", "A", "B", "C", "D", "E", "F", "G"]);
+        assert!(!is_hot(&cache, "A"));
+    }
+}
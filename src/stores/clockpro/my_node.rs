@@ -0,0 +1,30 @@
+use std::sync::atomic::AtomicBool;
+use crate::stores::lru::my_node;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Hot,
+    Cold,
+    // A cold page that has been evicted but is still in its test period, kept without its value.
+    Test
+}
+
+// What CLOCK-Pro keeps about a page on top of an LRU node.
+pub struct PageState {
+    // What the entry weighed while it was in the cache. Test pages keep it so the cold target
+    // can be moved by as much.
+    pub weight: u64,
+    pub kind: Kind,
+    // Set whenever the entry is used and cleared as a hand passes it. Atomic so that a read
+    // only needs a shared reference to the cache.
+    pub referenced: AtomicBool
+}
+
+// The value is None once the page is only being tested.
+pub type Page<T> = my_node::Node<Option<T>, PageState>;
+
+impl PageState {
+    pub fn new(weight: u64, kind: Kind) -> PageState {
+        PageState { weight, kind, referenced: AtomicBool::new(false) }
+    }
+}
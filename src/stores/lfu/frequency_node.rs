@@ -1,20 +1,16 @@
-use std::sync::{ Arc, RwLock };
-use super::list::List;
+use super::super::slab::List;
 
-pub struct FrequencyNode<T: std::fmt::Display + std::clone::Clone> {
+pub struct FrequencyNode {
     pub frequency: u32,
-    pub list: List<T>,
-    pub next: Option<Arc<RwLock<FrequencyNode<T>>>>,
-    pub prev: Option<Arc<RwLock<FrequencyNode<T>>>>
+    // The entries used this many times, most recently used first.
+    pub list: List
 }
 
-impl<T: std::fmt::Display + std::clone::Clone> FrequencyNode<T> {
-    pub fn new(frequency: u32) -> FrequencyNode<T> {
+impl FrequencyNode {
+    pub fn new(frequency: u32) -> FrequencyNode {
         FrequencyNode {
             frequency,
-            list: List::new(),
-            next: None,
-            prev: None
+            list: List::new()
         }
     }
 }
@@ -1,187 +1,567 @@
 pub mod frequency_node;
-pub mod list;
 pub mod my_node;
 use std::collections::HashMap;
-use std::sync::{ Arc, RwLock };
+use std::time::{ Duration, Instant };
+use super::{ Entry, HeapSize, Stats, Store, Ttl, Visit };
+use super::budget::{ self, Budget, Weigher };
+use super::slab::{ List, Slab };
 
-pub struct LFUCache<T: std::fmt::Display + std::clone::Clone> {
-    pub map: HashMap<String, Arc<RwLock<my_node::Node<T>>>>,
-    frequency_node: Arc<RwLock<frequency_node::FrequencyNode<T>>>,
-    max_length: u32,
-    total_node_count: u32
+// How an LFUCache stops keys that were used a lot once from staying in it long after they have
+// stopped being used. Every so often all frequencies are halved, so recent uses count for more
+// than old ones.
+#[derive(Clone, Copy)]
+pub enum Decay {
+    // Frequencies only ever grow.
+    Never,
+    // Halve them after this many gets and puts.
+    Operations(u64),
+    // Halve them once for every time this much time has passed, counted from the last time
+    // they were.
+    Interval(Duration)
 }
 
-pub struct FrequencyListIterator<T: std::fmt::Display + std::clone::Clone> {
-    ptr: Option<Arc<RwLock<frequency_node::FrequencyNode<T>>>>
+pub struct LFUCache<T: std::fmt::Display + std::clone::Clone> {
+    pub map: HashMap<Vec<u8>, u32>,
+    nodes: Slab<my_node::Node<T>>,
+    frequency_nodes: Slab<frequency_node::FrequencyNode>,
+    // The frequency nodes in increasing order of frequency.
+    frequency_list: List,
+    // The frequency 1 node new entries start out in. It is never removed.
+    frequency_node: u32,
+    budget: Budget<T>,
+    decay: Decay,
+    // Gets and puts since frequencies were last halved, and when they were.
+    operations: u64,
+    decayed_at: Instant,
+    stats: Stats,
+    // Evicted entries waiting to be collected, once keep_evicted has been called.
+    evicted: Option<Vec<Entry<T>>>
 }
 
-impl<T: std::fmt::Display + std::clone::Clone> Iterator for FrequencyListIterator<T> {
-    type Item = Arc<RwLock<frequency_node::FrequencyNode<T>>>;
+impl<T: std::fmt::Display + std::clone::Clone> LFUCache<T> {
+    pub fn new(queue_size: u32) -> LFUCache<T> {
+        LFUCache::with_budget(Budget::entries(queue_size))
+    }
+
+    pub fn with_weigher<W: Weigher<T> + Send + Sync + 'static>(max_weight: u64, weigher: W) -> LFUCache<T> {
+        LFUCache::with_budget(Budget::weighed(max_weight, weigher))
+    }
+
+    fn with_budget(budget: Budget<T>) -> LFUCache<T> {
+        let mut frequency_nodes = Slab::new();
+        let mut frequency_list = List::new();
+        let frequency_node = frequency_nodes.insert(frequency_node::FrequencyNode::new(1));
+        frequency_nodes.push_back(&mut frequency_list, frequency_node);
+        LFUCache {
+            map: HashMap::new(),
+            nodes: Slab::new(),
+            frequency_nodes,
+            frequency_list,
+            frequency_node,
+            budget,
+            decay: Decay::Never,
+            operations: 0,
+            decayed_at: Instant::now(),
+            stats: Stats::default(),
+            evicted: None
+        }
+    }
+
+    pub fn set_decay(&mut self, decay: Decay) {
+        self.decay = decay;
+        self.operations = 0;
+        self.decayed_at = Instant::now();
+    }
+
+    // Counts a get or put towards the next decay, and halves frequencies if one is due.
+    fn tick(&mut self) {
+        match self.decay {
+            Decay::Never => {}
+            Decay::Operations(n) => {
+                self.operations += 1;
+                if self.operations >= n {
+                    self.operations = 0;
+                    self.age(1);
+                }
+            }
+            Decay::Interval(interval) => {
+                let elapsed = self.decayed_at.elapsed();
+                if !interval.is_zero() && elapsed >= interval {
+                    let intervals = elapsed.as_nanos() / interval.as_nanos();
+                    self.decayed_at = Instant::now();
+                    self.age(intervals.min(u32::BITS as u128) as u32);
+                }
+            }
+        }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(curr_ptr) = self.ptr.take() {
-            if let Some(right_ptr) = curr_ptr.read().unwrap().next.as_ref() {
-                self.ptr = Some(Arc::clone(right_ptr));
+    // Halves every frequency `halvings` times over, down to no less than 1. Frequency nodes that
+    // end up on the same frequency are merged, with the entries of the one that was higher in
+    // front so they are still evicted last.
+    fn age(&mut self, halvings: u32) {
+        let mut prev = self.frequency_node;
+        let mut at = self.frequency_nodes.next(prev);
+        while let Some(freq_node) = at {
+            at = self.frequency_nodes.next(freq_node);
+            let frequency = self.frequency_nodes[freq_node].frequency.checked_shr(halvings).unwrap_or(0).max(1);
+            if frequency != self.frequency_nodes[prev].frequency {
+                self.frequency_nodes[freq_node].frequency = frequency;
+                prev = freq_node;
+                continue;
+            }
+            while let Some(index) = self.nodes.pop_back(&mut self.frequency_nodes[freq_node].list) {
+                self.nodes.push_front(&mut self.frequency_nodes[prev].list, index);
+                self.nodes[index].freq_node = prev;
             }
-            Some(curr_ptr)
+            self.frequency_nodes.unlink(&mut self.frequency_list, freq_node);
+            self.frequency_nodes.remove(freq_node);
+        }
+    }
+
+    fn get_next_frequency_node(&self, freq_node: u32) -> Option<u32> {
+        let next_freq_node = self.frequency_nodes.next(freq_node)?;
+        if self.frequency_nodes[next_freq_node].frequency == self.frequency_nodes[freq_node].frequency + 1 {
+            Some(next_freq_node)
         } else {
             None
         }
     }
-}
 
-impl<T: std::fmt::Display + std::clone::Clone> LFUCache<T> {
-    pub fn frequency_list_iter(&self) -> FrequencyListIterator<T> {
-        FrequencyListIterator { ptr: Some(Arc::clone(&self.frequency_node)) }
+    fn move_to_higher_frequency(&mut self, index: u32) {
+        let curr_freq_node = self.nodes[index].freq_node;
+        let next_freq_node = match self.get_next_frequency_node(curr_freq_node) {
+            Some(next_freq_node) => next_freq_node,
+            None => {
+                let frequency = self.frequency_nodes[curr_freq_node].frequency + 1;
+                let new_freq_node = self.frequency_nodes.insert(frequency_node::FrequencyNode::new(frequency));
+                self.frequency_nodes.insert_after(&mut self.frequency_list, curr_freq_node, new_freq_node);
+                new_freq_node
+            }
+        };
+        self.nodes.unlink(&mut self.frequency_nodes[curr_freq_node].list, index);
+        self.nodes.push_front(&mut self.frequency_nodes[next_freq_node].list, index);
+        self.nodes[index].freq_node = next_freq_node;
+        self.remove_if_empty(curr_freq_node);
     }
 
-    pub fn new(queue_size: u32) -> LFUCache<T> {
-        LFUCache {
-            map: HashMap::new(),
-            frequency_node: Arc::new(RwLock::new(frequency_node::FrequencyNode::new(1))),
-            max_length: queue_size,
-            total_node_count: 0
+    // Frequency nodes other than the first one go away once their last entry has left.
+    fn remove_if_empty(&mut self, freq_node: u32) {
+        if freq_node != self.frequency_node && self.frequency_nodes[freq_node].list.is_empty() {
+            self.frequency_nodes.unlink(&mut self.frequency_list, freq_node);
+            self.frequency_nodes.remove(freq_node);
         }
     }
 
-    fn get_next_frequency_node(n: &Arc<RwLock<my_node::Node<T>>>) -> Option<Arc<RwLock<frequency_node::FrequencyNode<T>>>> {
-        let curr_node = n.read().unwrap();
-        let curr_freq_node = curr_node.freq_node.read().unwrap();
-        // println!("curr_freq_node.frequency = {}", curr_freq_node.frequency);
-        if let Some(next_freq_node) = curr_freq_node.next.as_ref() {
-            if next_freq_node.read().unwrap().frequency == curr_freq_node.frequency + 1 {
-                return Some(Arc::clone(next_freq_node));
+    fn insert(&mut self, key: Vec<u8>, value: T, expires_at: Option<Instant>) -> Result<(), String> {
+        let weight = self.budget.weigh(&key, &value);
+        self.budget.check(weight)?;
+        self.tick();
+        self.remove_if_expired(&key);
+        if !self.map.contains_key(&key) {
+            let mut new_node = my_node::Node::new(key.clone(), value, self.frequency_node);
+            new_node.expires_at = expires_at;
+            let freq_n = self.frequency_node;
+            self.make_room(weight, freq_n);
+            let index = self.nodes.insert(new_node);
+            self.nodes.push_front(&mut self.frequency_nodes[freq_n].list, index);
+            self.map.insert(key, index);
+            self.budget.add(weight);
+            self.stats.inserts += 1;
+        } else if let Some(index) = self.map.get(&key).copied() {
+            self.budget.remove(self.budget.weigh(&key, &self.nodes[index].value));
+            // Writing a key counts as using it.
+            self.move_to_higher_frequency(index);
+            if !self.budget.fits(weight) {
+                // Keep the entry out of make_room's reach while it frees up space for the new value.
+                let freq_node = self.nodes[index].freq_node;
+                self.nodes.unlink(&mut self.frequency_nodes[freq_node].list, index);
+                self.make_room(weight, freq_node);
+                self.nodes.push_front(&mut self.frequency_nodes[freq_node].list, index);
             }
+            let node = &mut self.nodes[index];
+            node.value = value;
+            node.expires_at = expires_at;
+            self.budget.add(weight);
+            self.stats.updates += 1;
         }
-        return None;
-    }
-
-    fn move_to_higher_frequency(current_node: Arc<RwLock<my_node::Node<T>>>) {
-        {
-            let mut curr_node = current_node.write().unwrap();
-            curr_node.join_neighbours();
-        }
-        if let Some(next_freq_node) = LFUCache::get_next_frequency_node(&current_node) {
-            let mut next_freq_node_mut = next_freq_node.write().unwrap();
-            // println!("next_freq_node.frequency = {}", next_freq_node_mut.frequency);
-            next_freq_node_mut.list.prepend(Arc::clone(&current_node));
-            let mut curr_node = current_node.write().unwrap();
-            curr_node.freq_node = Arc::clone(&next_freq_node);
-            return ();
-        }
-
-        let new_freq_node_ptr;
-        {
-            let curr_node = current_node.write().unwrap();
-            let mut curr_freq_node = curr_node.freq_node.write().unwrap();
-            let new_freq_node = frequency_node::FrequencyNode::new(curr_freq_node.frequency + 1);
-            new_freq_node_ptr = Arc::new(RwLock::new(new_freq_node));
-            if let Some(nxt_freq_node) = curr_freq_node.next.as_ref() {
-                let mut new_fn = new_freq_node_ptr.write().unwrap();
-                new_fn.next = Some(Arc::clone(nxt_freq_node));
-                new_fn.prev = Some(Arc::clone(&curr_node.freq_node));
-                nxt_freq_node.write().unwrap().prev = Some(Arc::clone(&new_freq_node_ptr));
-                curr_freq_node.next = Some(Arc::clone(&new_freq_node_ptr));
-            } else {
-                let mut new_fn = new_freq_node_ptr.write().unwrap();
-                new_fn.prev = Some(Arc::clone(&curr_node.freq_node));
-                curr_freq_node.next = Some(Arc::clone(&new_freq_node_ptr));
+        Ok(())
+    }
+
+    // Evicts the least recently used entries of the lowest frequency there are any of until an
+    // entry weighing `weight` fits. `keep` is a frequency node an entry is about to go into, so
+    // it stays even if evicting empties it.
+    fn make_room(&mut self, weight: u64, keep: u32) {
+        while !self.budget.fits(weight) {
+            let freq_n = match self.frequency_nodes.iter(&self.frequency_list).find(|(_, fr_n)| !fr_n.list.is_empty()) {
+                Some((freq_n, _)) => freq_n,
+                None => break
+            };
+            let e = match self.nodes.pop_back(&mut self.frequency_nodes[freq_n].list) {
+                Some(e) => e,
+                None => break
+            };
+            let evicted = self.nodes.remove(e);
+            self.map.remove(&evicted.key);
+            self.budget.remove(self.budget.weigh(&evicted.key, &evicted.value));
+            self.stats.evictions += 1;
+            if let Some(kept) = self.evicted.as_mut() {
+                let frequency = self.frequency_nodes[freq_n].frequency;
+                kept.push(Entry { key: evicted.key, value: evicted.value, expires_at: evicted.expires_at, frequency });
+            }
+            if freq_n != keep {
+                self.remove_if_empty(freq_n);
             }
         }
+    }
 
-        {
-            let curr_node = current_node.write().unwrap();
-            let mut freq_n = curr_node.freq_node.write().unwrap();
-            if freq_n.list.head.is_none() && freq_n.list.end.is_none() {
-                if let Some(next) = freq_n.next.as_ref() {
-                    if let Some(prev) = freq_n.prev.as_ref() {
-                        next.write().unwrap().prev = Some(Arc::clone(prev));
-                    }
-                }
-                if let Some(prev) = freq_n.prev.as_ref() {
-                    if let Some(next) = freq_n.next.as_ref() {
-                        prev.write().unwrap().next = Some(Arc::clone(next));
-                    }
-                }
-                freq_n.next = None;
-                freq_n.prev = None;
+    // The frequency node for `frequency`, added in order if there isn't one yet.
+    fn frequency_node_for(&mut self, frequency: u32) -> u32 {
+        let mut at = self.frequency_node;
+        while let Some(next) = self.frequency_nodes.next(at) {
+            if self.frequency_nodes[next].frequency > frequency {
+                break;
             }
+            at = next;
         }
+        if self.frequency_nodes[at].frequency == frequency {
+            return at;
+        }
+        let new_freq_node = self.frequency_nodes.insert(frequency_node::FrequencyNode::new(frequency));
+        self.frequency_nodes.insert_after(&mut self.frequency_list, at, new_freq_node);
+        new_freq_node
+    }
 
-        {
-            let mut curr_node = current_node.write().unwrap();
-            curr_node.freq_node = Arc::clone(&new_freq_node_ptr);
+}
+
+impl<T: std::fmt::Display + std::clone::Clone + HeapSize> LFUCache<T> {
+    pub fn with_max_bytes(max_bytes: u64) -> LFUCache<T> {
+        LFUCache::with_budget(Budget::bytes(max_bytes, budget::entry_bytes::<T, my_node::Node<T>>))
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> Store<T> for LFUCache<T> {
+    fn expiry(&mut self, key: &[u8]) -> Option<&mut Option<Instant>> {
+        let nodes = &mut self.nodes;
+        self.map.get(key).map(move |index| &mut nodes[*index].expires_at)
+    }
+
+    fn each(&self, f: &mut Visit<T>) {
+        for index in self.map.values() {
+            let node = &self.nodes[*index];
+            f(&node.key, &node.value, node.expires_at);
         }
+    }
 
-        {
-            let mut new_freq_node_mut = new_freq_node_ptr.write().unwrap();
-            new_freq_node_mut.list.prepend(Arc::clone(&current_node));
+    fn remove(&mut self, key: &[u8]) -> bool {
+        if let Some(index) = self.map.remove(key) {
+            let freq_node = self.nodes[index].freq_node;
+            self.nodes.unlink(&mut self.frequency_nodes[freq_node].list, index);
+            let node = self.nodes.remove(index);
+            self.budget.remove(self.budget.weigh(&node.key, &node.value));
+            self.remove_if_empty(freq_node);
+            true
+        } else {
+            false
         }
     }
+
+    fn counters(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    fn evicted(&mut self) -> &mut Option<Vec<Entry<T>>> {
+        &mut self.evicted
+    }
 }
 
 impl<T: std::fmt::Display + std::clone::Clone> super::Cacheable<T> for LFUCache<T> {
-    fn put(&mut self, key: String, value: T) {
-        if !self.map.contains_key(&key) {
-            let new_node = Arc::new(RwLock::new(my_node::Node::new(key.clone(), value, &self.frequency_node)));
-            {
-                let mut freq_n = self.frequency_node.write().unwrap();
-                if self.total_node_count >= self.max_length {
-                    if let Some(e) = freq_n.list.end.take() {
-                        self.map.remove(&e.read().unwrap().key);
-                        let mut list_end = e.write().unwrap();
-                        if let Some(new_e) = list_end.prev.take() {
-                            new_e.write().unwrap().next = None;
-                            freq_n.list.end = Some(Arc::clone(&new_e));
-                        }
-                        std::mem::drop(&list_end.freq_node);
-                    }
-                    self.total_node_count -= 1;
-                }
-                freq_n.list.prepend(Arc::clone(&new_node));
-                self.map.insert(key.clone(), Arc::clone(&new_node));
-                self.total_node_count += 1;
-            }
+    fn put(&mut self, key: Vec<u8>, value: T) -> Result<(), String> {
+        self.insert(key, value, None)
+    }
+
+    fn put_with_ttl(&mut self, key: Vec<u8>, value: T, ttl: Duration) -> Result<(), String> {
+        self.insert(key, value, Some(super::deadline(ttl)?))
+    }
+
+    fn get(&mut self, key: Vec<u8>) -> Option<T> {
+        self.tick();
+        self.remove_if_expired(&key);
+        if let Some(index) = self.map.get(&key).copied() {
+            self.move_to_higher_frequency(index);
+            self.stats.hits += 1;
+            Some(self.nodes[index].value.clone())
         } else {
-            if let Some(node) = self.map.get(&key) {
-                let mut node_w = node.write().unwrap();
-                node_w.value = value;
-            }
+            self.stats.misses += 1;
+            None
         }
     }
 
-    fn get(&mut self, key: String) -> Option<T> {
-        if let Some(node) = self.map.get(&key) {
-            LFUCache::move_to_higher_frequency(Arc::clone(&node));
-            return Some(node.read().unwrap().value.clone());
+    fn delete(&mut self, key: Vec<u8>) -> Result<(), String> {
+        if self.remove(&key) {
+            self.stats.deletes += 1;
+            Ok(())
         } else {
-            return None;
+            Err("key not found".to_string())
         }
     }
 
-    fn delete(&mut self, key: String) -> Result<(), String> {
-        if let Some(node) = self.map.get(&key) {
-            node.write().unwrap().join_neighbours();
-            self.map.remove(&key);
-            self.total_node_count -= 1;
-            return Ok(());
-        } else {
-            return Err("key not found".to_string());
+    fn ttl(&mut self, key: Vec<u8>) -> Ttl {
+        Store::ttl(self, &key)
+    }
+
+    fn persist(&mut self, key: Vec<u8>) -> bool {
+        Store::persist(self, &key)
+    }
+
+    fn remove_expired(&mut self) -> u32 {
+        Store::remove_expired(self)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats { size: self.map.len() as u64, ..self.stats };
+        self.budget.fill_stats(&mut stats);
+        stats
+    }
+
+    fn weight(&self) -> u64 {
+        self.budget.used()
+    }
+
+    fn max_weight(&self) -> u64 {
+        self.budget.max()
+    }
+
+    // Lowest frequency first and each frequency front to back, since restored entries are
+    // appended to the back of their frequency's list.
+    fn entries(&self) -> Vec<Entry<T>> {
+        let mut entries = Vec::with_capacity(self.map.len());
+        for (_, fr_n) in self.frequency_nodes.iter(&self.frequency_list) {
+            for (_, node) in self.nodes.iter(&fr_n.list) {
+                if !node.is_expired() {
+                    let entry = Entry {
+                        key: node.key.clone(),
+                        value: node.value.clone(),
+                        expires_at: node.expires_at,
+                        frequency: fr_n.frequency
+                    };
+                    entries.push(entry);
+                }
+            }
         }
+        entries
     }
 
-    fn print_map(&self) {
-        for (k, v) in &self.map {
-            println!("{}: {}", k, v.read().unwrap().value);
+    fn restore(&mut self, entry: Entry<T>) {
+        self.remove(&entry.key);
+        let weight = self.budget.weigh(&entry.key, &entry.value);
+        if self.budget.check(weight).is_err() {
+            return;
         }
+        let root = self.frequency_node;
+        self.make_room(weight, root);
+        let freq_node = self.frequency_node_for(entry.frequency.max(1));
+        let mut node = my_node::Node::new(entry.key.clone(), entry.value, freq_node);
+        node.expires_at = entry.expires_at;
+        let index = self.nodes.insert(node);
+        self.nodes.push_back(&mut self.frequency_nodes[freq_node].list, index);
+        self.map.insert(entry.key, index);
+        self.budget.add(weight);
+        self.stats.inserts += 1;
+    }
+
+    fn keep_evicted(&mut self) {
+        Store::keep_evicted(self)
+    }
+
+    fn take_evicted(&mut self) -> Vec<Entry<T>> {
+        Store::take_evicted(self)
+    }
+
+    fn print_map(&self) {
+        Store::print_map(self)
     }
 
     fn print_list(&self) {
-        for fr_n in self.frequency_list_iter() {
-            println!("frequency_node: {}", fr_n.read().unwrap().frequency);
-            for n in fr_n.write().unwrap().list.iter() {
-                println!("{}", n.read().unwrap().value);
+        for (_, fr_n) in self.frequency_nodes.iter(&self.frequency_list) {
+            println!("frequency_node: {}", fr_n.frequency);
+            for (_, n) in self.nodes.iter(&fr_n.list) {
+                println!("{}", n.value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{ Duration, Instant };
+    use super::{ Decay, LFUCache };
+    use crate::stores::Cacheable;
+
+    // xorshift64, so the random workloads are the same on every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    fn key(k: u64) -> Vec<u8> {
+        k.to_string().into_bytes()
+    }
+
+    // Gets, puts, ttl'd puts and deletes over a small key space, checking the cache never holds
+    // more than it may after each one.
+    fn random_workload(cache: &mut LFUCache<String>, seed: u64, check: &dyn Fn(&LFUCache<String>)) {
+        let mut rng = Rng(seed);
+        for i in 0..20_000 {
+            let r = rng.next();
+            let k = key(r % 64);
+            let value = "v".repeat((r >> 8) as usize % 24) + &i.to_string();
+            match (r >> 16) % 10 {
+                0..=3 => {
+                    cache.get(k);
+                }
+                4..=7 => cache.put(k, value).unwrap(),
+                8 => {
+                    let _ = cache.delete(k);
+                }
+                _ => cache.put_with_ttl(k, value, Duration::from_nanos(1)).unwrap(),
+            }
+            check(cache);
+        }
+    }
+
+    #[test]
+    fn random_workload_stays_within_capacity() {
+        for capacity in [1, 2, 5, 50] {
+            let mut cache = LFUCache::new(capacity);
+            random_workload(&mut cache, capacity as u64, &|cache| {
+                assert!(cache.stats().size <= capacity as u64);
+                assert_eq!(cache.map.len() as u64, cache.stats().size);
+            });
+        }
+    }
+
+    #[test]
+    fn random_workload_stays_within_byte_budget() {
+        for max_bytes in [300, 1_000, 10_000] {
+            let mut cache = LFUCache::with_max_bytes(max_bytes);
+            random_workload(&mut cache, max_bytes, &|cache| assert!(cache.weight() <= max_bytes));
+        }
+    }
+
+    #[test]
+    fn evicts_from_lowest_frequency() {
+        let mut cache = LFUCache::new(2);
+        cache.put(key(1), 1).unwrap();
+        cache.put(key(2), 2).unwrap();
+        cache.get(key(1));
+        cache.put(key(3), 3).unwrap();
+        assert_eq!(cache.get(key(1)), Some(1));
+        assert_eq!(cache.get(key(2)), None);
+    }
+
+    #[test]
+    fn evicts_when_no_key_is_left_at_frequency_one() {
+        let mut cache = LFUCache::new(2);
+        cache.put(key(1), 1).unwrap();
+        cache.put(key(2), 2).unwrap();
+        cache.get(key(1));
+        cache.get(key(2));
+        cache.put(key(3), 3).unwrap();
+        assert_eq!(cache.stats().size, 2);
+        assert_eq!(cache.get(key(1)), None);
+        assert_eq!(cache.get(key(3)), Some(3));
+    }
+
+    // Writing key 1 counts as a use, which takes it to the frequency key 2 is at. Evicting key 2
+    // to make room then empties the frequency node key 1 goes back into.
+    #[test]
+    fn update_under_weighed_budget_evicts_from_its_own_frequency() {
+        let mut cache = LFUCache::with_weigher(10, |_: &[u8], value: &u64| *value);
+        cache.put(key(1), 3).unwrap();
+        cache.put(key(2), 3).unwrap();
+        cache.get(key(1));
+        cache.get(key(2));
+        cache.get(key(2));
+        cache.put(key(1), 9).unwrap();
+        assert_eq!(cache.get(key(1)), Some(9));
+        assert_eq!(cache.get(key(2)), None);
+        assert_eq!(cache.weight(), 9);
+    }
+
+    // Each frequency node in turn, with the values of its entries, most recently used first.
+    fn frequencies(cache: &LFUCache<u64>) -> Vec<(u32, Vec<u64>)> {
+        cache.frequency_nodes.iter(&cache.frequency_list)
+            .map(|(_, freq_node)| (freq_node.frequency, cache.nodes.iter(&freq_node.list).map(|(_, node)| node.value).collect()))
+            .collect()
+    }
+
+    // Key 1 has been used 8 times, key 2 4 times and key 3 once.
+    fn used_unevenly() -> LFUCache<u64> {
+        let mut cache = LFUCache::new(10);
+        for k in 1..=3 {
+            cache.put(key(k), k).unwrap();
+        }
+        for _ in 0..7 {
+            cache.get(key(1));
+        }
+        for _ in 0..3 {
+            cache.get(key(2));
+        }
+        cache
+    }
+
+    // The third get after set_decay halves every frequency before it counts, which leaves key 3
+    // at 1 and then moves it up to 2 alongside key 2.
+    #[test]
+    fn operations_decay_halves_after_that_many_operations() {
+        let mut cache = used_unevenly();
+        cache.set_decay(Decay::Operations(3));
+        cache.get(key(3));
+        cache.get(key(3));
+        assert_eq!(frequencies(&cache), [(1, vec![]), (3, vec![3]), (4, vec![2]), (8, vec![1])]);
+        cache.get(key(3));
+        assert_eq!(frequencies(&cache), [(1, vec![]), (2, vec![3, 2]), (4, vec![1])]);
+    }
+
+    // Halved once for each whole interval gone by since the last time: two of them here.
+    #[test]
+    fn interval_decay_halves_once_per_interval_passed() {
+        let mut cache = used_unevenly();
+        cache.set_decay(Decay::Interval(Duration::from_secs(1)));
+        cache.get(key(3));
+        assert_eq!(frequencies(&cache), [(1, vec![]), (2, vec![3]), (4, vec![2]), (8, vec![1])]);
+        cache.decayed_at = Instant::now() - Duration::from_millis(2500);
+        cache.get(key(3));
+        assert_eq!(frequencies(&cache), [(1, vec![2]), (2, vec![3, 1])]);
+        assert!(cache.decayed_at.elapsed() < Duration::from_secs(1));
+    }
+
+    // Nodes that end up on the same frequency are merged with the entries of the one that was
+    // higher in front, so of the keys now at 1, key 5 (used once) is still evicted first and
+    // key 2 (used 3 times) last.
+    #[test]
+    fn halving_merges_frequencies_keeping_the_busier_keys_in_front() {
+        let mut cache = LFUCache::new(5);
+        for k in [5, 1, 2, 3, 4] {
+            cache.put(key(k), k).unwrap();
+        }
+        for (k, uses) in [(1, 1), (2, 2), (3, 3), (4, 4)] {
+            for _ in 0..uses {
+                cache.get(key(k));
             }
         }
+        assert_eq!(frequencies(&cache), [(1, vec![5]), (2, vec![1]), (3, vec![2]), (4, vec![3]), (5, vec![4])]);
+        cache.age(1);
+        assert_eq!(frequencies(&cache), [(1, vec![2, 1, 5]), (2, vec![4, 3])]);
+        cache.keep_evicted();
+        cache.put(key(6), 6).unwrap();
+        cache.put(key(7), 7).unwrap();
+        assert_eq!(cache.take_evicted().into_iter().map(|entry| entry.value).collect::<Vec<_>>(), [5, 1]);
     }
 }
@@ -0,0 +1,519 @@
+pub mod my_node;
+use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+use super::{ Entry, HeapSize, Stats, Store, Ttl, Visit };
+use super::budget::{ self, Budget, Weigher };
+use super::slab::{ List, Slab };
+use my_node::{ Block, Node };
+
+// The share of the budget resident HIR blocks get, Lhirs in the paper.
+const HIR_PERCENT: u64 = 1;
+
+// LIRS (Jiang and Zhang, 2002). Keys are ranked by how many other keys were used between their
+// last two uses rather than by when they were last used. Most of the cache holds LIR blocks,
+// whose keys came back quickly, and the remaining 1% holds HIR blocks, the only ones that are
+// ever evicted. The stack S orders blocks by recency, and includes keys evicted while they were
+// in it. A HIR block used again while it is in S has come back sooner than the least recent LIR
+// block, so the two trade places. S is pruned so that its bottom is always a LIR block, and
+// anything below it could not have come back sooner than that. Resident HIR blocks also wait in
+// the queue Q to be evicted, oldest first.
+//
+// A block is in S through the slab it lives in, and in Q or the list of non-resident blocks
+// through a slot in a second slab, as it can be in S and one of the others at once.
+pub struct LIRSCache<T: std::fmt::Display + std::clone::Clone> {
+    // Every block in the cache, non-resident ones included.
+    pub map: HashMap<Vec<u8>, u32>,
+    blocks: Slab<Node<T>>,
+    // S, most recently used first.
+    stack: List,
+    slots: Slab<u32>,
+    // Q and the non-resident blocks, newest first.
+    queue: List,
+    non_resident: List,
+    lir_weight: u64,
+    budget: Budget<T>,
+    stats: Stats,
+    // Evicted entries waiting to be collected, once keep_evicted has been called.
+    evicted: Option<Vec<Entry<T>>>
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> LIRSCache<T> {
+    pub fn new(queue_size: u32) -> LIRSCache<T> {
+        LIRSCache::with_budget(Budget::entries(queue_size))
+    }
+
+    pub fn with_weigher<W: Weigher<T> + Send + Sync + 'static>(max_weight: u64, weigher: W) -> LIRSCache<T> {
+        LIRSCache::with_budget(Budget::weighed(max_weight, weigher))
+    }
+
+    fn with_budget(budget: Budget<T>) -> LIRSCache<T> {
+        LIRSCache {
+            map: HashMap::new(),
+            blocks: Slab::new(),
+            stack: List::new(),
+            slots: Slab::new(),
+            queue: List::new(),
+            non_resident: List::new(),
+            lir_weight: 0,
+            budget,
+            stats: Stats::default(),
+            evicted: None
+        }
+    }
+
+    // Llirs in the paper.
+    fn lir_max(&self) -> u64 {
+        let max = self.budget.max();
+        // Divided first so large byte budgets don't overflow.
+        let hir_max = (max / 100 * HIR_PERCENT + max % 100 * HIR_PERCENT / 100).max(1);
+        max.saturating_sub(hir_max)
+    }
+
+    fn resident(&self) -> u32 {
+        self.map.len() as u32 - self.non_resident.len()
+    }
+
+    // The most non-resident blocks S may hold on to, as many as the cache holds entries. Under
+    // a byte budget or a Weigher that is however many it holds at the moment.
+    fn non_resident_max(&self) -> u32 {
+        if self.budget.counts_entries() {
+            self.budget.max() as u32
+        } else {
+            self.resident().max(1)
+        }
+    }
+
+    // Puts a block at the front of Q if it is resident, or of the non-resident list if not.
+    fn enqueue(&mut self, index: u32) {
+        let slot = self.slots.insert(index);
+        let list = if self.blocks[index].value.is_some() { &mut self.queue } else { &mut self.non_resident };
+        self.slots.push_front(list, slot);
+        self.blocks[index].meta.slot = Some(slot);
+    }
+
+    fn dequeue(&mut self, index: u32) {
+        if let Some(slot) = self.blocks[index].meta.slot.take() {
+            let list = if self.blocks[index].value.is_some() { &mut self.queue } else { &mut self.non_resident };
+            self.slots.unlink(list, slot);
+            self.slots.remove(slot);
+        }
+    }
+
+    fn unstack(&mut self, index: u32) {
+        if self.blocks[index].meta.in_stack {
+            self.blocks.unlink(&mut self.stack, index);
+            self.blocks[index].meta.in_stack = false;
+        }
+    }
+
+    // Takes a resident block out of S and Q, leaving it HIR.
+    fn detach(&mut self, index: u32) {
+        self.unstack(index);
+        self.dequeue(index);
+        let block = &mut self.blocks[index];
+        if block.meta.lir {
+            block.meta.lir = false;
+            self.lir_weight -= block.meta.weight;
+        }
+    }
+
+    // Puts a detached resident block at the top of S, as LIR or as HIR at the front of Q.
+    fn attach(&mut self, index: u32, lir: bool) {
+        self.blocks.push_front(&mut self.stack, index);
+        self.blocks[index].meta.in_stack = true;
+        if lir {
+            self.blocks[index].meta.lir = true;
+            self.lir_weight += self.blocks[index].meta.weight;
+            while self.lir_weight > self.lir_max() && self.demote() {}
+        } else {
+            self.enqueue(index);
+        }
+        self.prune();
+    }
+
+    // Drops a block altogether.
+    fn forget(&mut self, index: u32) -> Node<T> {
+        self.unstack(index);
+        self.dequeue(index);
+        let block = self.blocks.remove(index);
+        self.map.remove(&block.key);
+        block
+    }
+
+    // Stack pruning in the paper: takes HIR blocks off the bottom of S until a LIR block is
+    // there, forgetting the non-resident ones.
+    fn prune(&mut self) {
+        while let Some(bottom) = self.stack.tail() {
+            if self.blocks[bottom].meta.lir {
+                break;
+            }
+            self.unstack(bottom);
+            if self.blocks[bottom].value.is_none() {
+                self.forget(bottom);
+            }
+        }
+    }
+
+    // Turns the LIR block at the bottom of S into a HIR block at the front of Q. Returns false
+    // if there are no LIR blocks.
+    fn demote(&mut self) -> bool {
+        self.prune();
+        match self.stack.tail() {
+            Some(bottom) => {
+                self.unstack(bottom);
+                let block = &mut self.blocks[bottom];
+                block.meta.lir = false;
+                self.lir_weight -= block.meta.weight;
+                self.enqueue(bottom);
+                self.prune();
+                true
+            }
+            None => false
+        }
+    }
+
+    // Moves a resident block to the top of S, since it has been used again. A HIR block still in
+    // S becomes LIR, pushing out the least recent LIR blocks if there is no room for it.
+    fn touch(&mut self, index: u32) {
+        let lir = self.blocks[index].meta.lir || self.blocks[index].meta.in_stack;
+        self.detach(index);
+        self.attach(index, lir);
+    }
+
+    // Evicts from the back of Q until an entry weighing `weight` fits, demoting LIR blocks when
+    // Q runs out.
+    fn make_room(&mut self, weight: u64) {
+        while !self.budget.fits(weight) {
+            match self.queue.tail() {
+                Some(slot) => self.evict(self.slots[slot]),
+                None => {
+                    if !self.demote() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // Evicts a resident HIR block, keeping it in S without its value if it is there.
+    fn evict(&mut self, index: u32) {
+        self.dequeue(index);
+        let block = &mut self.blocks[index];
+        let value = block.value.take().unwrap();
+        self.budget.remove(block.meta.weight);
+        self.stats.evictions += 1;
+        if let Some(kept) = self.evicted.as_mut() {
+            kept.push(Entry { key: block.key.clone(), value, expires_at: block.expires_at, frequency: 1 });
+        }
+        if block.meta.in_stack {
+            self.enqueue(index);
+            self.trim_non_resident();
+        } else {
+            self.forget(index);
+        }
+    }
+
+    fn trim_non_resident(&mut self) {
+        while self.non_resident.len() > self.non_resident_max() {
+            match self.non_resident.tail() {
+                Some(slot) => {
+                    self.forget(self.slots[slot]);
+                }
+                None => break
+            }
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: T, expires_at: Option<Instant>) -> Result<(), String> {
+        let weight = self.budget.weigh(&key, &value);
+        self.budget.check(weight)?;
+        self.remove_if_expired(&key);
+        if let Some(index) = self.map.get(&key).copied().filter(|index| self.blocks[*index].value.is_some()) {
+            // Writing a key counts as using it. It is kept out of make_room's reach while room
+            // is made for its new value.
+            let lir = self.blocks[index].meta.lir || self.blocks[index].meta.in_stack;
+            self.detach(index);
+            self.budget.remove(self.blocks[index].meta.weight);
+            self.make_room(weight);
+            let block = &mut self.blocks[index];
+            block.value = Some(value);
+            block.expires_at = expires_at;
+            block.meta.weight = weight;
+            self.budget.add(weight);
+            self.attach(index, lir);
+            self.stats.updates += 1;
+            return Ok(());
+        }
+        // Room is made first, since evicting can prune the key's non-resident block.
+        self.make_room(weight);
+        match self.map.get(&key).copied() {
+            Some(index) => {
+                // Back while its block was still in S, so it came back sooner than the least
+                // recent LIR block.
+                self.dequeue(index);
+                self.unstack(index);
+                let block = &mut self.blocks[index];
+                block.value = Some(value);
+                block.expires_at = expires_at;
+                block.meta.weight = weight;
+                self.budget.add(weight);
+                self.attach(index, true);
+            }
+            None => {
+                let mut block = Node::with_meta(key.clone(), Some(value), Block::new(weight));
+                block.expires_at = expires_at;
+                let index = self.blocks.insert(block);
+                self.map.insert(key, index);
+                self.budget.add(weight);
+                // Until the LIR blocks fill their share, every new key is one.
+                let lir = self.lir_weight + weight <= self.lir_max();
+                self.attach(index, lir);
+            }
+        }
+        self.stats.inserts += 1;
+        self.trim_non_resident();
+        Ok(())
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone + HeapSize> LIRSCache<T> {
+    // Non-resident blocks are not counted in the budget.
+    pub fn with_max_bytes(max_bytes: u64) -> LIRSCache<T> {
+        LIRSCache::with_budget(Budget::bytes(max_bytes, budget::entry_bytes::<T, Node<T>>))
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> Store<T> for LIRSCache<T> {
+    fn expiry(&mut self, key: &[u8]) -> Option<&mut Option<Instant>> {
+        let blocks = &mut self.blocks;
+        self.map.get(key)
+            .map(move |index| &mut blocks[*index])
+            .filter(|entry| entry.value.is_some())
+            .map(|entry| &mut entry.expires_at)
+    }
+
+    fn each(&self, f: &mut Visit<T>) {
+        for index in self.map.values() {
+            let entry = &self.blocks[*index];
+            if let Some(value) = &entry.value {
+                f(&entry.key, value, entry.expires_at);
+            }
+        }
+    }
+
+    // Drops the key if it is resident. Non-resident blocks are left alone.
+    fn remove(&mut self, key: &[u8]) -> bool {
+        match self.map.get(key).copied() {
+            Some(index) if self.blocks[index].value.is_some() => {
+                self.detach(index);
+                let block = self.forget(index);
+                self.budget.remove(block.meta.weight);
+                self.prune();
+                true
+            }
+            _ => false
+        }
+    }
+
+    fn counters(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    fn evicted(&mut self) -> &mut Option<Vec<Entry<T>>> {
+        &mut self.evicted
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> super::Cacheable<T> for LIRSCache<T> {
+    fn put(&mut self, key: Vec<u8>, value: T) -> Result<(), String> {
+        self.insert(key, value, None)
+    }
+
+    fn put_with_ttl(&mut self, key: Vec<u8>, value: T, ttl: Duration) -> Result<(), String> {
+        self.insert(key, value, Some(super::deadline(ttl)?))
+    }
+
+    fn get(&mut self, key: Vec<u8>) -> Option<T> {
+        self.remove_if_expired(&key);
+        match self.map.get(&key).copied().filter(|index| self.blocks[*index].value.is_some()) {
+            Some(index) => {
+                self.touch(index);
+                self.stats.hits += 1;
+                self.blocks[index].value.clone()
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> Result<(), String> {
+        if self.remove(&key) {
+            self.stats.deletes += 1;
+            Ok(())
+        } else {
+            Err("key not found".to_string())
+        }
+    }
+
+    fn ttl(&mut self, key: Vec<u8>) -> Ttl {
+        Store::ttl(self, &key)
+    }
+
+    fn persist(&mut self, key: Vec<u8>) -> bool {
+        Store::persist(self, &key)
+    }
+
+    fn remove_expired(&mut self) -> u32 {
+        Store::remove_expired(self)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats { size: self.resident() as u64, ..self.stats };
+        self.budget.fill_stats(&mut stats);
+        stats
+    }
+
+    fn weight(&self) -> u64 {
+        self.budget.used()
+    }
+
+    fn max_weight(&self) -> u64 {
+        self.budget.max()
+    }
+
+    // The HIR blocks that have left S, oldest first, then the resident blocks in S from the
+    // bottom up, with a frequency of 2 for LIR blocks and 1 for HIR ones. Each restored block
+    // goes on top of S, and pruning takes the first lot back off it. Non-resident blocks are not
+    // kept, and Q comes back in that order rather than its own.
+    fn entries(&self) -> Vec<Entry<T>> {
+        let outside_stack = self.slots.iter(&self.queue).collect::<Vec<_>>().into_iter().rev()
+            .map(|(_, index)| &self.blocks[*index])
+            .filter(|block| !block.meta.in_stack);
+        let in_stack = self.blocks.iter(&self.stack).collect::<Vec<_>>().into_iter().rev()
+            .map(|(_, block)| block);
+        outside_stack.chain(in_stack)
+            .filter(|block| !block.is_expired())
+            .filter_map(|block| {
+                let frequency = if block.meta.lir { 2 } else { 1 };
+                block.value.clone().map(|value| Entry { key: block.key.clone(), value, expires_at: block.expires_at, frequency })
+            })
+            .collect()
+    }
+
+    // An entry with no frequency is placed as a new key would be.
+    fn restore(&mut self, entry: Entry<T>) {
+        if let Some(index) = self.map.get(&entry.key).copied() {
+            let block = self.forget(index);
+            if block.value.is_some() {
+                if block.meta.lir {
+                    self.lir_weight -= block.meta.weight;
+                }
+                self.budget.remove(block.meta.weight);
+                self.prune();
+            }
+        }
+        let weight = self.budget.weigh(&entry.key, &entry.value);
+        if self.budget.check(weight).is_err() {
+            return;
+        }
+        self.make_room(weight);
+        let lir = match entry.frequency {
+            0 => self.lir_weight + weight <= self.lir_max(),
+            frequency => frequency >= 2
+        };
+        let mut block = Node::with_meta(entry.key.clone(), Some(entry.value), Block::new(weight));
+        block.expires_at = entry.expires_at;
+        let index = self.blocks.insert(block);
+        self.map.insert(entry.key, index);
+        self.budget.add(weight);
+        self.attach(index, lir);
+        self.stats.inserts += 1;
+        self.trim_non_resident();
+    }
+
+    fn keep_evicted(&mut self) {
+        Store::keep_evicted(self)
+    }
+
+    fn take_evicted(&mut self) -> Vec<Entry<T>> {
+        Store::take_evicted(self)
+    }
+
+    fn print_map(&self) {
+        Store::print_map(self)
+    }
+
+    fn print_list(&self) {
+        println!("stack:");
+        for (_, block) in self.blocks.iter(&self.stack) {
+            match &block.value {
+                Some(value) if block.meta.lir => println!("lir {}", value),
+                Some(value) => println!("hir {}", value),
+                None => println!("non-resident {}", String::from_utf8_lossy(&block.key))
+            }
+        }
+        println!("queue:");
+        for (_, index) in self.slots.iter(&self.queue) {
+            if let Some(value) = &self.blocks[*index].value {
+                println!("{}", value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LIRSCache;
+    use crate::stores::Cacheable;
+
+    fn key(k: &str) -> Vec<u8> {
+        k.as_bytes().to_vec()
+    }
+
+    // Reads each key in turn, putting it on a miss as a cache in front of a slower store would,
+    // and returns what was evicted at each step.
+    fn replay(cache: &mut LIRSCache<String>, keys: &str) -> Vec<String> {
+        cache.keep_evicted();
+        keys.split(' ')
+            .map(|k| {
+                if cache.get(key(k)).is_none() {
+                    cache.put(key(k), k.to_string()).unwrap();
+                }
+                cache.take_evicted().into_iter().map(|entry| entry.value).collect::<Vec<_>>().join(" ")
+            })
+            .collect()
+    }
+
+    // Snapshots mark LIR blocks with a frequency of 2 and HIR ones with 1.
+    fn frequency_of(cache: &LIRSCache<String>, k: &str) -> Option<u32> {
+        cache.entries().into_iter().find(|entry| entry.key == key(k)).map(|entry| entry.frequency)
+    }
+
+    // With room for 3 entries, Llirs is 2 and Lhirs is 1 as in the paper's example. C comes back
+    // while it is non-resident in S and takes A's place as a LIR block, then E does the same to
+    // C, while B stays LIR throughout as it keeps coming back before the others.
+    #[test]
+    fn replays_the_lirs_eviction_sequence() {
+        let mut cache = LIRSCache::new(3);
+        let evicted = replay(&mut cache, "A B C D C E B E F");
+        assert_eq!(evicted, ["", "", "", "C", "D", "A", "", "", "C"]);
+        assert_eq!(frequency_of(&cache, "B"), Some(2));
+        assert_eq!(frequency_of(&cache, "E"), Some(2));
+        assert_eq!(frequency_of(&cache, "F"), Some(1));
+        assert_eq!(cache.entries().len(), 3);
+    }
+
+    // Using B, the bottom of S, prunes the non-resident C off it. When C comes back its last use
+    // is forgotten, so it is a HIR block again rather than a LIR one.
+    #[test]
+    fn a_key_pruned_from_the_stack_comes_back_as_hir() {
+        let mut cache = LIRSCache::new(3);
+        let evicted = replay(&mut cache, "A B C D A B C");
+        assert_eq!(evicted, ["", "", "", "C", "", "", "D"]);
+        assert_eq!(frequency_of(&cache, "C"), Some(1));
+        assert_eq!(frequency_of(&cache, "A"), Some(2));
+        assert_eq!(frequency_of(&cache, "B"), Some(2));
+    }
+}
@@ -0,0 +1,22 @@
+use crate::stores::lru::my_node;
+
+// What LIRS keeps about a key on top of an LRU node.
+pub struct Block {
+    // What the entry weighs, kept so LIR blocks can be totalled as they come and go.
+    pub weight: u64,
+    // Whether the block has low inter-reference recency. Only resident blocks can.
+    pub lir: bool,
+    pub in_stack: bool,
+    // The block's slot in the queue of resident HIR blocks, or in the list of non-resident ones.
+    pub slot: Option<u32>
+}
+
+// The value is None for a non-resident HIR block, whose value was evicted while it was still in
+// the stack.
+pub type Node<T> = my_node::Node<Option<T>, Block>;
+
+impl Block {
+    pub fn new(weight: u64) -> Block {
+        Block { weight, lir: false, in_stack: false, slot: None }
+    }
+}
@@ -1,62 +1,341 @@
 use std::collections::HashMap;
-use std::sync::{ Arc, RwLock };
+use std::time::{ Duration, Instant };
+use super::{ Entry, HeapSize, Stats, Store, Ttl, Visit };
+use super::budget::{ self, Budget, Weigher };
+use super::slab::{ List, Slab };
 pub mod my_node;
-pub mod my_list;
 
 pub struct LRUCache<T: std::fmt::Display + std::clone::Clone> {
-  pub map: HashMap<String, Arc<RwLock<my_node::Node<T>>>>,
-  list: my_list::List<T>,
-  max_length: u32
+  pub map: HashMap<Vec<u8>, u32>,
+  nodes: Slab<my_node::Node<T>>,
+  list: List,
+  budget: Budget<T>,
+  stats: Stats,
+  // Evicted entries waiting to be collected, once keep_evicted has been called.
+  evicted: Option<Vec<Entry<T>>>
 }
 
 impl<T: std::fmt::Display + std::clone::Clone> LRUCache<T> {
   pub fn new(queue_size: u32) -> LRUCache<T> {
-    LRUCache { map: HashMap::new(), list: my_list::List::new(), max_length: queue_size }
+    LRUCache::with_budget(Budget::entries(queue_size))
   }
+
+  pub fn with_weigher<W: Weigher<T> + Send + Sync + 'static>(max_weight: u64, weigher: W) -> LRUCache<T> {
+    LRUCache::with_budget(Budget::weighed(max_weight, weigher))
+  }
+
+  fn with_budget(budget: Budget<T>) -> LRUCache<T> {
+    LRUCache { map: HashMap::new(), nodes: Slab::new(), list: List::new(), budget, stats: Stats::default(), evicted: None }
+  }
+
+  fn insert(&mut self, key: Vec<u8>, value: T, expires_at: Option<Instant>) -> Result<(), String> {
+      let weight = self.budget.weigh(&key, &value);
+      self.budget.check(weight)?;
+      self.remove_if_expired(&key);
+      if let Some(index) = self.map.get(&key).copied() {
+          // Writing a key counts as using it. It is kept out of make_room's reach while room is
+          // made for its new value.
+          self.nodes.unlink(&mut self.list, index);
+          self.budget.remove(self.budget.weigh(&key, &self.nodes[index].value));
+          self.make_room(weight);
+          self.nodes.push_front(&mut self.list, index);
+          let node = &mut self.nodes[index];
+          node.value = value;
+          node.expires_at = expires_at;
+          self.budget.add(weight);
+          self.stats.updates += 1;
+      } else {
+          let mut new_node = my_node::Node::new(key.clone(), value);
+          new_node.expires_at = expires_at;
+          self.make_room(weight);
+          let index = self.nodes.insert(new_node);
+          self.nodes.push_front(&mut self.list, index);
+          self.map.insert(key, index);
+          self.budget.add(weight);
+          self.stats.inserts += 1;
+      }
+      Ok(())
+  }
+
+  fn make_room(&mut self, weight: u64) {
+      while !self.budget.fits(weight) {
+          match self.nodes.pop_back(&mut self.list) {
+              Some(last) => self.evict(last),
+              None => break
+          }
+      }
+  }
+
+  // Drops an entry that has already been unlinked to make room for another.
+  fn evict(&mut self, index: u32) {
+      let evicted = self.nodes.remove(index);
+      self.map.remove(&evicted.key);
+      self.budget.remove(self.budget.weigh(&evicted.key, &evicted.value));
+      self.stats.evictions += 1;
+      if let Some(kept) = self.evicted.as_mut() {
+          kept.push(Entry { key: evicted.key, value: evicted.value, expires_at: evicted.expires_at, frequency: 0 });
+      }
+  }
+
+}
+
+impl<T: std::fmt::Display + std::clone::Clone + HeapSize> LRUCache<T> {
+  pub fn with_max_bytes(max_bytes: u64) -> LRUCache<T> {
+    LRUCache::with_budget(Budget::bytes(max_bytes, budget::entry_bytes::<T, my_node::Node<T>>))
+  }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> Store<T> for LRUCache<T> {
+    fn expiry(&mut self, key: &[u8]) -> Option<&mut Option<Instant>> {
+        let nodes = &mut self.nodes;
+        self.map.get(key).map(move |index| &mut nodes[*index].expires_at)
+    }
+
+    fn each(&self, f: &mut Visit<T>) {
+        for index in self.map.values() {
+            let node = &self.nodes[*index];
+            f(&node.key, &node.value, node.expires_at);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        if let Some(index) = self.map.remove(key) {
+            self.nodes.unlink(&mut self.list, index);
+            let node = self.nodes.remove(index);
+            self.budget.remove(self.budget.weigh(&node.key, &node.value));
+            true
+        } else {
+            false
+        }
+    }
+
+    fn counters(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    fn evicted(&mut self) -> &mut Option<Vec<Entry<T>>> {
+        &mut self.evicted
+    }
 }
 
 impl<T: std::fmt::Display + std::clone::Clone> super::Cacheable<T> for LRUCache<T> {
-    fn put(&mut self, key: String, value: T) {
-        if !self.map.contains_key(&key) {
-            let new_node = Arc::new(RwLock::new(my_node::Node::new(key.clone(), value)));
-            if self.list.size >= self.max_length {
-                if let Some(rn) = self.list.remove_last_node() {
-                    self.map.remove(&rn.read().unwrap().key);
-                }
-            }
-            self.list.append(Arc::clone(&new_node));
-            self.map.insert(key.clone(), Arc::clone(&new_node));
-        }
-    }
-
-    fn get(&mut self, key: String) -> Option<T> {
-      if let Some(node) = self.map.get(&key) {
-          self.list.move_to_front(Arc::clone(node));
-          return Some(node.read().unwrap().value.clone());
+    fn put(&mut self, key: Vec<u8>, value: T) -> Result<(), String> {
+        self.insert(key, value, None)
+    }
+
+    fn put_with_ttl(&mut self, key: Vec<u8>, value: T, ttl: Duration) -> Result<(), String> {
+        self.insert(key, value, Some(super::deadline(ttl)?))
+    }
+
+    fn get(&mut self, key: Vec<u8>) -> Option<T> {
+      self.remove_if_expired(&key);
+      if let Some(index) = self.map.get(&key) {
+          self.nodes.move_to_front(&mut self.list, *index);
+          self.stats.hits += 1;
+          Some(self.nodes[*index].value.clone())
       } else {
-          return None;
+          self.stats.misses += 1;
+          None
       }
     }
 
-    fn delete(&mut self, key: String) -> Result<(), String> {
-        if let Some(node) = self.map.get(&key) {
-            node.write().unwrap().join_neighbours(&mut self.list);
-            self.map.remove(&key);
-            return Ok(());
+    fn delete(&mut self, key: Vec<u8>) -> Result<(), String> {
+        if self.remove(&key) {
+            self.stats.deletes += 1;
+            Ok(())
         } else {
-            return Err("key not found".to_string());
+            Err("key not found".to_string())
         }
     }
 
+    fn ttl(&mut self, key: Vec<u8>) -> Ttl {
+        Store::ttl(self, &key)
+    }
+
+    fn persist(&mut self, key: Vec<u8>) -> bool {
+        Store::persist(self, &key)
+    }
+
+    fn remove_expired(&mut self) -> u32 {
+        Store::remove_expired(self)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats { size: self.map.len() as u64, ..self.stats };
+        self.budget.fill_stats(&mut stats);
+        stats
+    }
+
+    fn weight(&self) -> u64 {
+        self.budget.used()
+    }
+
+    fn max_weight(&self) -> u64 {
+        self.budget.max()
+    }
+
+    // Least recently used first, since each restored entry goes in at the front of the list.
+    fn entries(&self) -> Vec<Entry<T>> {
+        self.nodes.iter(&self.list).collect::<Vec<_>>().into_iter().rev()
+            .map(|(_, node)| node)
+            .filter(|node| !node.is_expired())
+            .map(|node| Entry { key: node.key.clone(), value: node.value.clone(), expires_at: node.expires_at, frequency: 0 })
+            .collect()
+    }
+
+    fn restore(&mut self, entry: Entry<T>) {
+        self.remove(&entry.key);
+        let _ = self.insert(entry.key, entry.value, entry.expires_at);
+    }
+
+    fn keep_evicted(&mut self) {
+        Store::keep_evicted(self)
+    }
+
+    fn take_evicted(&mut self) -> Vec<Entry<T>> {
+        Store::take_evicted(self)
+    }
+
     fn print_map(&self) {
-        for (k, v) in &self.map {
-            println!("{}: {}", k, v.read().unwrap().value);
-        }
+        Store::print_map(self)
     }
 
     fn print_list(&self) {
-        for n in self.list.iter() {
-            println!("{}", n.read().unwrap().value);
+        for (_, n) in self.nodes.iter(&self.list) {
+            println!("{}", n.value);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use super::LRUCache;
+    use crate::stores::Cacheable;
+
+    fn key(k: &str) -> Vec<u8> {
+        k.as_bytes().to_vec()
+    }
+
+    fn keys(cache: &LRUCache<String>) -> Vec<String> {
+        cache.entries().into_iter().map(|entry| entry.value).collect()
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_key() {
+        let mut cache = LRUCache::new(3);
+        cache.keep_evicted();
+        for k in ["A", "B", "C"] {
+            cache.put(key(k), k.to_string()).unwrap();
+        }
+        cache.get(key("A"));
+        cache.put(key("D"), "D".to_string()).unwrap();
+        cache.put(key("E"), "E".to_string()).unwrap();
+        let evicted: Vec<String> = cache.take_evicted().into_iter().map(|entry| entry.value).collect();
+        assert_eq!(evicted, ["B", "C"]);
+        assert_eq!(keys(&cache), ["A", "D", "E"]);
+    }
+
+    // Restoring the entries in the order they come in rebuilds the same recency order.
+    #[test]
+    fn restored_entries_keep_their_order() {
+        let mut cache = LRUCache::new(4);
+        for k in ["A", "B", "C", "D"] {
+            cache.put(key(k), k.to_string()).unwrap();
+        }
+        cache.get(key("B"));
+        let mut restored = LRUCache::new(4);
+        for entry in cache.entries() {
+            restored.restore(entry);
+        }
+        assert_eq!(keys(&restored), ["A", "C", "D", "B"]);
+        restored.put(key("E"), "E".to_string()).unwrap();
+        assert_eq!(keys(&restored), ["C", "D", "B", "E"]);
+    }
+
+    // Capped by memory, a write evicts as many of the least recently used entries as it takes
+    // for the new one to fit, and a value heavier than the whole cache is turned away.
+    #[test]
+    fn evicts_by_bytes_until_the_new_entry_fits() {
+        let mut probe = LRUCache::with_max_bytes(u64::MAX);
+        probe.put(key("A"), "a".repeat(100)).unwrap();
+        let weight = probe.weight();
+
+        let mut cache = LRUCache::with_max_bytes(3 * weight);
+        cache.keep_evicted();
+        for k in ["A", "B", "C"] {
+            cache.put(key(k), k.repeat(100)).unwrap();
+        }
+        assert_eq!(cache.weight(), 3 * weight);
+        cache.get(key("A"));
+        cache.put(key("D"), "D".repeat(100)).unwrap();
+        assert_eq!(cache.take_evicted().into_iter().map(|entry| entry.key).collect::<Vec<_>>(), [key("B")]);
+
+        // An entry weighing twice as much takes the room of two of the others.
+        cache.put(key("E"), "E".repeat(weight as usize + 100)).unwrap();
+        assert_eq!(cache.take_evicted().into_iter().map(|entry| entry.key).collect::<Vec<_>>(), [key("C"), key("A")]);
+        assert_eq!(cache.entries().into_iter().map(|entry| entry.key).collect::<Vec<_>>(), [key("D"), key("E")]);
+
+        assert_eq!(cache.put(key("F"), "F".repeat(3 * weight as usize)), Err("value is larger than the whole cache".to_string()));
+        assert_eq!(cache.weight(), 3 * weight);
+    }
+
+    // With a budget near u64::MAX, what is used plus a new entry's weight can be too big for a
+    // u64. It still has to come out as not fitting.
+    #[test]
+    fn weights_near_the_limit_do_not_overflow() {
+        let mut cache = LRUCache::with_weigher(u64::MAX, |_: &[u8], weight: &u64| *weight);
+        cache.keep_evicted();
+        cache.put(key("A"), u64::MAX - 1).unwrap();
+        cache.put(key("B"), 2).unwrap();
+        assert_eq!(cache.take_evicted().into_iter().map(|entry| entry.key).collect::<Vec<_>>(), [key("A")]);
+        assert_eq!(cache.weight(), 2);
+    }
+
+    // Every kind of operation shows up in its own counter, and a key that runs out counts as an
+    // expiration whether a read finds it or remove_expired does.
+    #[test]
+    fn stats_count_every_operation() {
+        let mut cache = LRUCache::new(2);
+        cache.put(key("A"), "a".to_string()).unwrap();
+        cache.put(key("B"), "b".to_string()).unwrap();
+        cache.put(key("A"), "a2".to_string()).unwrap();
+        assert_eq!(cache.get(key("A")), Some("a2".to_string()));
+        assert_eq!(cache.get(key("Z")), None);
+        cache.put(key("C"), "c".to_string()).unwrap();
+        cache.delete(key("A")).unwrap();
+        assert!(cache.delete(key("A")).is_err());
+
+        cache.put_with_ttl(key("D"), "d".to_string(), Duration::from_nanos(1)).unwrap();
+        thread::sleep(Duration::from_millis(1));
+        assert_eq!(cache.get(key("D")), None);
+        cache.put_with_ttl(key("E"), "e".to_string(), Duration::from_nanos(1)).unwrap();
+        thread::sleep(Duration::from_millis(1));
+        assert_eq!(cache.remove_expired(), 1);
+
+        let stats = cache.stats();
+        assert_eq!(
+            stats.fields(),
+            [
+                ("hits", 1),
+                ("misses", 2),
+                ("inserts", 5),
+                ("updates", 1),
+                ("evictions", 1),
+                ("expirations", 2),
+                ("deletes", 1),
+                ("size", 1),
+                ("capacity", 2),
+                ("bytes", 0),
+                ("max_bytes", 0),
+            ]
+        );
+
+        // Capped by bytes, the cache reports what it holds in bytes and no entry capacity.
+        let mut cache = LRUCache::with_max_bytes(1024);
+        cache.put(key("A"), "a".repeat(100)).unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.size, stats.capacity, stats.bytes, stats.max_bytes), (1, 0, cache.weight(), 1024));
+    }
+}
@@ -1,10 +1,242 @@
+use std::time::{ Duration, Instant };
+
 pub mod lru;
 pub mod lfu;
+pub mod arc;
+pub mod tinylfu;
+pub mod slru;
+pub mod clock;
+pub mod clockpro;
+pub mod twoq;
+pub mod lirs;
+pub mod sharded;
+pub mod slab;
+pub mod budget;
+
+// Every store is made in one of three ways. `new(n)` holds up to n entries. `with_weigher(max,
+// weigher)` holds entries until their total weight, as measured by the Weigher, would go over
+// max. `with_max_bytes(max)` holds as many entries as fit in max bytes, counting keys, values
+// and the store's own bookkeeping for each entry.
+
+// When an entry written now with `ttl` runs out, unless that is further off than an Instant can
+// represent.
+pub fn deadline(ttl: Duration) -> Result<Instant, String> {
+    Instant::now().checked_add(ttl).ok_or_else(|| "invalid expire time".to_string())
+}
+
+pub enum Ttl {
+    Missing,
+    Persistent,
+    Expiring(Duration),
+}
+
+// The memory a value holds on to outside of itself, for caches capped by bytes.
+pub trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+impl HeapSize for Vec<u8> {
+    fn heap_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.len()
+    }
+}
+
+// Whether a write goes ahead depending on the key being in the cache already, as with the NX
+// and XX options of Redis' SET.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Always,
+    IfAbsent,
+    IfPresent,
+}
+
+// A copy of one cached key, as handed out for snapshots.
+pub struct Entry<T> {
+    pub key: Vec<u8>,
+    pub value: T,
+    pub expires_at: Option<Instant>,
+    // How many times the key has been used, for policies that count it. 0 when unknown.
+    pub frequency: u32,
+}
+
+// Running totals a cache keeps about itself. `size`, `capacity` and the byte counts are filled
+// in when asked. A cache capped by bytes has no fixed `capacity` and reports 0 for it, while one
+// capped by entries reports 0 for both byte counts.
+#[derive(Clone, Copy, Default)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub inserts: u64,
+    pub updates: u64,
+    pub evictions: u64,
+    pub expirations: u64,
+    pub deletes: u64,
+    pub size: u64,
+    pub capacity: u64,
+    pub bytes: u64,
+    pub max_bytes: u64,
+}
+
+impl Stats {
+    pub fn fields(&self) -> [(&'static str, u64); 11] {
+        [
+            ("hits", self.hits),
+            ("misses", self.misses),
+            ("inserts", self.inserts),
+            ("updates", self.updates),
+            ("evictions", self.evictions),
+            ("expirations", self.expirations),
+            ("deletes", self.deletes),
+            ("size", self.size),
+            ("capacity", self.capacity),
+            ("bytes", self.bytes),
+            ("max_bytes", self.max_bytes),
+        ]
+    }
+
+    pub fn merge(&mut self, other: &Stats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.inserts += other.inserts;
+        self.updates += other.updates;
+        self.evictions += other.evictions;
+        self.expirations += other.expirations;
+        self.deletes += other.deletes;
+        self.size += other.size;
+        self.capacity += other.capacity;
+        self.bytes += other.bytes;
+        self.max_bytes += other.max_bytes;
+    }
+}
+
+// What every store keeps track of in the same way: the live entries under their keys, the
+// counters and the evicted entries waiting to be collected. Stores hand their Cacheable ttl,
+// persist, remove_expired, keep_evicted, take_evicted and print_map on to the methods here.
+type Visit<'a, T> = dyn FnMut(&[u8], &T, Option<Instant>) + 'a;
+
+trait Store<T: std::fmt::Display + std::clone::Clone> {
+    // The expiry of the entry under `key`, if the key is in the cache, whether or not it has
+    // run out.
+    fn expiry(&mut self, key: &[u8]) -> Option<&mut Option<Instant>>;
+    // Calls `f` with every key in the cache, its value and its expiry, expired ones included.
+    fn each(&self, f: &mut Visit<T>);
+    // Drops the key if it is in the cache, returning whether it was.
+    fn remove(&mut self, key: &[u8]) -> bool;
+    fn counters(&mut self) -> &mut Stats;
+    fn evicted(&mut self) -> &mut Option<Vec<Entry<T>>>;
+
+    // Lazily drops the key if its ttl has run out, so callers only ever see live entries.
+    fn remove_if_expired(&mut self, key: &[u8]) {
+        let now = Instant::now();
+        if self.expiry(key).is_some_and(|t| t.is_some_and(|t| t <= now)) {
+            self.remove(key);
+            self.counters().expirations += 1;
+        }
+    }
+
+    fn ttl(&mut self, key: &[u8]) -> Ttl {
+        self.remove_if_expired(key);
+        match self.expiry(key) {
+            Some(Some(t)) => Ttl::Expiring(t.saturating_duration_since(Instant::now())),
+            Some(None) => Ttl::Persistent,
+            None => Ttl::Missing,
+        }
+    }
+
+    fn persist(&mut self, key: &[u8]) -> bool {
+        self.remove_if_expired(key);
+        self.expiry(key).is_some_and(|t| t.take().is_some())
+    }
+
+    fn remove_expired(&mut self) -> u32 {
+        let now = Instant::now();
+        let mut expired_keys = Vec::new();
+        self.each(&mut |key, _, expires_at| {
+            if expires_at.is_some_and(|t| t <= now) {
+                expired_keys.push(key.to_vec());
+            }
+        });
+        for k in &expired_keys {
+            self.remove(k);
+        }
+        self.counters().expirations += expired_keys.len() as u64;
+        expired_keys.len() as u32
+    }
+
+    fn keep_evicted(&mut self) {
+        self.evicted().get_or_insert_with(Vec::new);
+    }
+
+    fn take_evicted(&mut self) -> Vec<Entry<T>> {
+        self.evicted().as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn print_map(&self) {
+        self.each(&mut |key, value, _| println!("{}: {}", String::from_utf8_lossy(key), value));
+    }
+}
 
 pub trait Cacheable<T: std::fmt::Display + std::clone::Clone> {
-    fn put(&mut self, key: String, value: T);
-    fn get(&mut self, key: String) -> Option<T>;
-    fn delete(&mut self, key: String) -> Result<(), String>;
+    // Both replace the value of a key that is already there, counting the write as a use of
+    // it. Both fail if the entry alone is more than the cache can hold.
+    fn put(&mut self, key: Vec<u8>, value: T) -> Result<(), String>;
+    fn put_with_ttl(&mut self, key: Vec<u8>, value: T, ttl: Duration) -> Result<(), String>;
+    // Writes the entry only if `condition` holds for the key. Returns whether it did.
+    fn put_if(&mut self, key: Vec<u8>, value: T, ttl: Option<Duration>, condition: Condition) -> Result<bool, String> {
+        let present = !matches!(self.ttl(key.clone()), Ttl::Missing);
+        let allowed = match condition {
+            Condition::Always => true,
+            Condition::IfAbsent => !present,
+            Condition::IfPresent => present,
+        };
+        if !allowed {
+            return Ok(false);
+        }
+        match ttl {
+            Some(ttl) => self.put_with_ttl(key, value, ttl)?,
+            None => self.put(key, value)?,
+        }
+        Ok(true)
+    }
+    fn get(&mut self, key: Vec<u8>) -> Option<T>;
+    // A get through a shared reference, so readers holding a shard's read lock don't wait on
+    // each other. Only stores that leave their eviction order alone on a hit can serve one. The
+    // rest return None, and get has to be used instead.
+    fn get_shared(&self, _key: &[u8]) -> Option<Option<T>> {
+        None
+    }
+    fn delete(&mut self, key: Vec<u8>) -> Result<(), String>;
+    fn ttl(&mut self, key: Vec<u8>) -> Ttl;
+    fn persist(&mut self, key: Vec<u8>) -> bool;
+    fn remove_expired(&mut self) -> u32;
+    fn stats(&self) -> Stats;
+    // How much of the cache is taken up and how much it may hold, in whatever its entries are
+    // weighed by: 1 each, their size in bytes or a Weigher's measure.
+    fn weight(&self) -> u64;
+    fn max_weight(&self) -> u64;
+    // Every live entry, for writing the cache out to disk. They come in the order that
+    // restoring them one after another rebuilds the eviction order exactly.
+    fn entries(&self) -> Vec<Entry<T>>;
+    // Puts an entry from `entries` back where it was, replacing the key if it is present. An
+    // entry that no longer fits is dropped.
+    fn restore(&mut self, entry: Entry<T>) {
+        let _ = self.delete(entry.key.clone());
+        let _ = match entry.expires_at {
+            Some(t) => self.put_with_ttl(entry.key, entry.value, t.saturating_duration_since(Instant::now())),
+            None => self.put(entry.key, entry.value),
+        };
+    }
+    // Holds on to entries evicted from now on instead of dropping them, until take_evicted.
+    fn keep_evicted(&mut self);
+    // The entries evicted since the last call, oldest first. Always empty unless keep_evicted
+    // was called. Keys that expired are not included.
+    fn take_evicted(&mut self) -> Vec<Entry<T>>;
     fn print_map(&self);
     fn print_list(&self);
 }
@@ -0,0 +1,182 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::sync::RwLock;
+use std::time::Duration;
+use super::{ Cacheable, Condition, Entry, Stats, Ttl };
+
+pub type Shard<T> = RwLock<Box<dyn Cacheable<T> + Send + Sync>>;
+
+// Spreads keys over a number of independent caches, each behind its own lock, so commands
+// on different keys don't wait on each other. Every shard gets an equal share of the
+// capacity and evicts on its own.
+pub struct ShardedCache<T: std::fmt::Display + std::clone::Clone> {
+    shards: Vec<Shard<T>>,
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> ShardedCache<T> {
+    // Builds `shard_count` caches with `new_shard`, handing it each shard's share of
+    // `capacity`, whether that is counted in entries or bytes. There are never more shards than
+    // there is capacity to share out.
+    pub fn new<F>(capacity: u64, shard_count: u32, new_shard: F) -> ShardedCache<T>
+    where
+        F: Fn(u64) -> Box<dyn Cacheable<T> + Send + Sync>,
+    {
+        let shard_count = (shard_count as u64).min(capacity).max(1);
+        let shards = (0..shard_count)
+            .map(|i| {
+                let share = capacity / shard_count + if i < capacity % shard_count { 1 } else { 0 };
+                RwLock::new(new_shard(share))
+            })
+            .collect();
+        ShardedCache { shards }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_index(&self, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    // The shard that owns `key`. Lock it for as long as a command needs the key to itself.
+    pub fn shard(&self, key: &[u8]) -> &Shard<T> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    pub fn shards(&self) -> &[Shard<T>] {
+        &self.shards
+    }
+
+    fn shard_mut(&mut self, key: &[u8]) -> &mut dyn Cacheable<T> {
+        let index = self.shard_index(key);
+        &mut **self.shards[index].get_mut().unwrap()
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> Cacheable<T> for ShardedCache<T> {
+    fn put(&mut self, key: Vec<u8>, value: T) -> Result<(), String> {
+        self.shard_mut(&key).put(key, value)
+    }
+
+    fn put_with_ttl(&mut self, key: Vec<u8>, value: T, ttl: Duration) -> Result<(), String> {
+        self.shard_mut(&key).put_with_ttl(key, value, ttl)
+    }
+
+    fn put_if(&mut self, key: Vec<u8>, value: T, ttl: Option<Duration>, condition: Condition) -> Result<bool, String> {
+        self.shard_mut(&key).put_if(key, value, ttl, condition)
+    }
+
+    fn get(&mut self, key: Vec<u8>) -> Option<T> {
+        self.shard_mut(&key).get(key)
+    }
+
+    fn get_shared(&self, key: &[u8]) -> Option<Option<T>> {
+        self.shard(key).read().unwrap().get_shared(key)
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> Result<(), String> {
+        self.shard_mut(&key).delete(key)
+    }
+
+    fn ttl(&mut self, key: Vec<u8>) -> Ttl {
+        self.shard_mut(&key).ttl(key)
+    }
+
+    fn persist(&mut self, key: Vec<u8>) -> bool {
+        self.shard_mut(&key).persist(key)
+    }
+
+    fn remove_expired(&mut self) -> u32 {
+        self.shards.iter_mut().map(|shard| shard.get_mut().unwrap().remove_expired()).sum()
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+        for shard in &self.shards {
+            stats.merge(&shard.read().unwrap().stats());
+        }
+        stats
+    }
+
+    fn weight(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.read().unwrap().weight()).sum()
+    }
+
+    fn max_weight(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.read().unwrap().max_weight()).sum()
+    }
+
+    fn entries(&self) -> Vec<Entry<T>> {
+        self.shards.iter().flat_map(|shard| shard.read().unwrap().entries()).collect()
+    }
+
+    fn restore(&mut self, entry: Entry<T>) {
+        self.shard_mut(&entry.key).restore(entry);
+    }
+
+    fn keep_evicted(&mut self) {
+        for shard in &mut self.shards {
+            shard.get_mut().unwrap().keep_evicted();
+        }
+    }
+
+    fn take_evicted(&mut self) -> Vec<Entry<T>> {
+        self.shards.iter_mut().flat_map(|shard| shard.get_mut().unwrap().take_evicted()).collect()
+    }
+
+    fn print_map(&self) {
+        for shard in &self.shards {
+            shard.read().unwrap().print_map();
+        }
+    }
+
+    fn print_list(&self) {
+        for shard in &self.shards {
+            shard.read().unwrap().print_list();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShardedCache;
+    use crate::stores::lru::LRUCache;
+    use crate::stores::{ Cacheable, Stats };
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{}", i).into_bytes()
+    }
+
+    // The cache's stats are those of all its shards added up, whichever shard each key went to.
+    #[test]
+    fn stats_add_up_across_shards() {
+        let mut cache: ShardedCache<String> = ShardedCache::new(8, 4, |share| Box::new(LRUCache::new(share as u32)));
+        for i in 0..20 {
+            cache.put(key(i), i.to_string()).unwrap();
+        }
+        for i in 0..20 {
+            cache.get(key(i));
+        }
+        let kept = cache.entries().remove(0).key;
+        cache.delete(kept).unwrap();
+
+        let mut summed = Stats::default();
+        for shard in cache.shards() {
+            summed.merge(&shard.read().unwrap().stats());
+        }
+        // The keys have to have been spread out for the sum to mean anything.
+        assert!(cache.shards().iter().filter(|shard| shard.read().unwrap().stats().inserts > 0).count() > 1);
+        let stats = cache.stats();
+        assert_eq!(stats.fields(), summed.fields());
+        assert_eq!(stats.inserts, 20);
+        assert_eq!(stats.hits + stats.misses, 20);
+        assert_eq!(stats.evictions, 20 - stats.hits);
+        assert_eq!(stats.deletes, 1);
+        assert_eq!(stats.size, stats.hits - 1);
+        assert_eq!(stats.size, cache.entries().len() as u64);
+        assert_eq!(stats.capacity, 8);
+    }
+}
@@ -0,0 +1,232 @@
+// An arena of entries linked into doubly linked lists by index. Entries live in one Vec and
+// refer to their neighbours by slot number, so there are no reference cycles to leak, no
+// locks to take when walking a list and no allocation per entry once a slot is reused.
+// Several lists can share one slab; each list is only its head, tail and length.
+use std::ops::{ Index, IndexMut };
+
+const NIL: u32 = u32::MAX;
+
+struct Slot<E> {
+    // None while the slot is on the free list.
+    entry: Option<E>,
+    prev: u32,
+    // The next free slot while the slot is on the free list.
+    next: u32
+}
+
+#[derive(Clone, Copy)]
+pub struct List {
+    head: u32,
+    tail: u32,
+    len: u32
+}
+
+impl Default for List {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl List {
+    pub fn new() -> List {
+        List { head: NIL, tail: NIL, len: 0 }
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn head(&self) -> Option<u32> {
+        if self.head == NIL { None } else { Some(self.head) }
+    }
+
+    pub fn tail(&self) -> Option<u32> {
+        if self.tail == NIL { None } else { Some(self.tail) }
+    }
+}
+
+pub struct Slab<E> {
+    slots: Vec<Slot<E>>,
+    free: u32,
+    len: u32
+}
+
+impl<E> Slab<E> {
+    // What each entry costs the slab, links included.
+    pub const SLOT_SIZE: usize = std::mem::size_of::<Slot<E>>();
+}
+
+impl<E> Default for Slab<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E> Slab<E> {
+    pub fn new() -> Slab<E> {
+        Slab { slots: Vec::new(), free: NIL, len: 0 }
+    }
+
+    pub fn with_capacity(capacity: u32) -> Slab<E> {
+        Slab { slots: Vec::with_capacity(capacity as usize), free: NIL, len: 0 }
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Stores the entry in a free slot, not yet linked into any list, and returns the slot.
+    pub fn insert(&mut self, entry: E) -> u32 {
+        self.len += 1;
+        if self.free != NIL {
+            let index = self.free;
+            let slot = &mut self.slots[index as usize];
+            self.free = slot.next;
+            *slot = Slot { entry: Some(entry), prev: NIL, next: NIL };
+            index
+        } else {
+            self.slots.push(Slot { entry: Some(entry), prev: NIL, next: NIL });
+            (self.slots.len() - 1) as u32
+        }
+    }
+
+    // Frees the slot and hands back its entry. The slot must already be unlinked.
+    pub fn remove(&mut self, index: u32) -> E {
+        let free = self.free;
+        let slot = &mut self.slots[index as usize];
+        let entry = slot.entry.take().expect("slab slot is already free");
+        slot.prev = NIL;
+        slot.next = free;
+        self.free = index;
+        self.len -= 1;
+        entry
+    }
+
+    pub fn next(&self, index: u32) -> Option<u32> {
+        let next = self.slots[index as usize].next;
+        if next == NIL { None } else { Some(next) }
+    }
+
+    pub fn prev(&self, index: u32) -> Option<u32> {
+        let prev = self.slots[index as usize].prev;
+        if prev == NIL { None } else { Some(prev) }
+    }
+
+    pub fn push_front(&mut self, list: &mut List, index: u32) {
+        self.slots[index as usize].prev = NIL;
+        self.slots[index as usize].next = list.head;
+        if list.head == NIL {
+            list.tail = index;
+        } else {
+            self.slots[list.head as usize].prev = index;
+        }
+        list.head = index;
+        list.len += 1;
+    }
+
+    pub fn push_back(&mut self, list: &mut List, index: u32) {
+        self.slots[index as usize].prev = list.tail;
+        self.slots[index as usize].next = NIL;
+        if list.tail == NIL {
+            list.head = index;
+        } else {
+            self.slots[list.tail as usize].next = index;
+        }
+        list.tail = index;
+        list.len += 1;
+    }
+
+    // Links `index` in directly behind `at`, which must already be on the list.
+    pub fn insert_after(&mut self, list: &mut List, at: u32, index: u32) {
+        let next = self.slots[at as usize].next;
+        self.slots[index as usize].prev = at;
+        self.slots[index as usize].next = next;
+        self.slots[at as usize].next = index;
+        if next == NIL {
+            list.tail = index;
+        } else {
+            self.slots[next as usize].prev = index;
+        }
+        list.len += 1;
+    }
+
+    pub fn unlink(&mut self, list: &mut List, index: u32) {
+        let (prev, next) = {
+            let slot = &self.slots[index as usize];
+            (slot.prev, slot.next)
+        };
+        if prev == NIL {
+            list.head = next;
+        } else {
+            self.slots[prev as usize].next = next;
+        }
+        if next == NIL {
+            list.tail = prev;
+        } else {
+            self.slots[next as usize].prev = prev;
+        }
+        self.slots[index as usize].prev = NIL;
+        self.slots[index as usize].next = NIL;
+        list.len -= 1;
+    }
+
+    pub fn move_to_front(&mut self, list: &mut List, index: u32) {
+        if list.head != index {
+            self.unlink(list, index);
+            self.push_front(list, index);
+        }
+    }
+
+    // Unlinks the last entry of the list and returns its slot, which is still occupied.
+    pub fn pop_back(&mut self, list: &mut List) -> Option<u32> {
+        let tail = list.tail()?;
+        self.unlink(list, tail);
+        Some(tail)
+    }
+
+    // Walks the list from head to tail, yielding each slot along with its entry.
+    pub fn iter<'a>(&'a self, list: &List) -> Iter<'a, E> {
+        Iter { slab: self, next: list.head }
+    }
+}
+
+impl<E> Index<u32> for Slab<E> {
+    type Output = E;
+
+    fn index(&self, index: u32) -> &E {
+        self.slots[index as usize].entry.as_ref().expect("slab slot is free")
+    }
+}
+
+impl<E> IndexMut<u32> for Slab<E> {
+    fn index_mut(&mut self, index: u32) -> &mut E {
+        self.slots[index as usize].entry.as_mut().expect("slab slot is free")
+    }
+}
+
+pub struct Iter<'a, E> {
+    slab: &'a Slab<E>,
+    next: u32
+}
+
+impl<'a, E> Iterator for Iter<'a, E> {
+    type Item = (u32, &'a E);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == NIL {
+            return None;
+        }
+        let index = self.next;
+        let slot = &self.slab.slots[index as usize];
+        self.next = slot.next;
+        Some((index, slot.entry.as_ref().expect("slab slot is free")))
+    }
+}
@@ -0,0 +1,404 @@
+pub mod my_node;
+use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+use super::{ Entry, HeapSize, Stats, Store, Ttl, Visit };
+use super::budget::{ self, Budget, Weigher };
+use super::slab::{ List, Slab };
+use my_node::Node;
+
+// The share of the budget the protected segment gets unless set_protected_percent says otherwise.
+pub const DEFAULT_PROTECTED_PERCENT: u64 = 80;
+
+// Segmented LRU. New entries go on probation and only move to the protected segment once they
+// are used again, so a scan through keys that are never asked for twice only pushes out other
+// entries on probation. Entries are evicted from the back of probation, and the protected
+// segment hands its least recently used entries back to probation once it is over its share of
+// the budget. Both lists are most recently used first.
+pub struct SLRUCache<T: std::fmt::Display + std::clone::Clone> {
+    pub map: HashMap<Vec<u8>, u32>,
+    nodes: Slab<Node<T>>,
+    probation: List,
+    protected: List,
+    // The weight of the entries in the protected segment, and the most it may hold as a
+    // percentage of the budget.
+    protected_weight: u64,
+    protected_percent: u64,
+    budget: Budget<T>,
+    stats: Stats,
+    // Evicted entries waiting to be collected, once keep_evicted has been called.
+    evicted: Option<Vec<Entry<T>>>
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> SLRUCache<T> {
+    pub fn new(queue_size: u32) -> SLRUCache<T> {
+        SLRUCache::with_budget(Budget::entries(queue_size))
+    }
+
+    pub fn with_weigher<W: Weigher<T> + Send + Sync + 'static>(max_weight: u64, weigher: W) -> SLRUCache<T> {
+        SLRUCache::with_budget(Budget::weighed(max_weight, weigher))
+    }
+
+    fn with_budget(budget: Budget<T>) -> SLRUCache<T> {
+        SLRUCache {
+            map: HashMap::new(),
+            nodes: Slab::new(),
+            probation: List::new(),
+            protected: List::new(),
+            protected_weight: 0,
+            protected_percent: DEFAULT_PROTECTED_PERCENT,
+            budget,
+            stats: Stats::default(),
+            evicted: None
+        }
+    }
+
+    // How much of the budget the protected segment may take up, from 0 to 100 percent. The
+    // bigger it is the longer keys used more than once stay, and the sooner new keys go.
+    pub fn set_protected_percent(&mut self, percent: u64) {
+        self.protected_percent = percent.min(100);
+        self.demote();
+    }
+
+    fn protected_max(&self) -> u64 {
+        // Divided first so large byte budgets don't overflow.
+        self.budget.max() / 100 * self.protected_percent + self.budget.max() % 100 * self.protected_percent / 100
+    }
+
+    fn weight_of(&self, index: u32) -> u64 {
+        let node = &self.nodes[index];
+        self.budget.weigh(&node.key, &node.value)
+    }
+
+    // Takes an entry off the list it is in.
+    fn unlink(&mut self, index: u32) {
+        if self.nodes[index].meta {
+            self.protected_weight -= self.weight_of(index);
+            self.nodes.unlink(&mut self.protected, index);
+        } else {
+            self.nodes.unlink(&mut self.probation, index);
+        }
+    }
+
+    // Puts an entry that is in neither list at the front of the protected segment.
+    fn protect(&mut self, index: u32) {
+        self.protected_weight += self.weight_of(index);
+        self.nodes.push_front(&mut self.protected, index);
+        self.nodes[index].meta = true;
+    }
+
+    // Moves an entry to the front of the protected segment, since it has been used again.
+    fn touch(&mut self, index: u32) {
+        if self.nodes[index].meta {
+            self.nodes.move_to_front(&mut self.protected, index);
+        } else {
+            self.unlink(index);
+            self.protect(index);
+            self.demote();
+        }
+    }
+
+    // Puts the least recently used protected entries back on probation while the segment is
+    // over its share.
+    fn demote(&mut self) {
+        while self.protected_weight > self.protected_max() {
+            match self.protected.tail() {
+                Some(index) => {
+                    self.unlink(index);
+                    self.nodes.push_front(&mut self.probation, index);
+                    self.nodes[index].meta = false;
+                }
+                None => break
+            }
+        }
+    }
+
+    // Evicts from the back of probation, or of the protected segment once probation is empty,
+    // until an entry weighing `weight` fits.
+    fn make_room(&mut self, weight: u64) {
+        while !self.budget.fits(weight) {
+            match self.probation.tail().or(self.protected.tail()) {
+                Some(victim) => self.evict(victim),
+                None => break
+            }
+        }
+    }
+
+    fn evict(&mut self, index: u32) {
+        self.unlink(index);
+        let evicted = self.nodes.remove(index);
+        self.map.remove(&evicted.key);
+        self.budget.remove(self.budget.weigh(&evicted.key, &evicted.value));
+        self.stats.evictions += 1;
+        if let Some(kept) = self.evicted.as_mut() {
+            let frequency = if evicted.meta { 2 } else { 1 };
+            kept.push(Entry { key: evicted.key, value: evicted.value, expires_at: evicted.expires_at, frequency });
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: T, expires_at: Option<Instant>) -> Result<(), String> {
+        let weight = self.budget.weigh(&key, &value);
+        self.budget.check(weight)?;
+        self.remove_if_expired(&key);
+        if let Some(index) = self.map.get(&key).copied() {
+            // Writing a key counts as using it. It is kept out of make_room's reach while room
+            // is made for its new value.
+            self.unlink(index);
+            self.budget.remove(self.weight_of(index));
+            self.make_room(weight);
+            let node = &mut self.nodes[index];
+            node.value = value;
+            node.expires_at = expires_at;
+            self.budget.add(weight);
+            self.protect(index);
+            self.demote();
+            self.stats.updates += 1;
+        } else {
+            let mut node = Node::with_meta(key.clone(), value, false);
+            node.expires_at = expires_at;
+            self.make_room(weight);
+            let index = self.nodes.insert(node);
+            self.nodes.push_front(&mut self.probation, index);
+            self.map.insert(key, index);
+            self.budget.add(weight);
+            self.stats.inserts += 1;
+        }
+        Ok(())
+    }
+
+}
+
+impl<T: std::fmt::Display + std::clone::Clone + HeapSize> SLRUCache<T> {
+    pub fn with_max_bytes(max_bytes: u64) -> SLRUCache<T> {
+        SLRUCache::with_budget(Budget::bytes(max_bytes, budget::entry_bytes::<T, Node<T>>))
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> Store<T> for SLRUCache<T> {
+    fn expiry(&mut self, key: &[u8]) -> Option<&mut Option<Instant>> {
+        let nodes = &mut self.nodes;
+        self.map.get(key).map(move |index| &mut nodes[*index].expires_at)
+    }
+
+    fn each(&self, f: &mut Visit<T>) {
+        for index in self.map.values() {
+            let node = &self.nodes[*index];
+            f(&node.key, &node.value, node.expires_at);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        if let Some(index) = self.map.remove(key) {
+            self.unlink(index);
+            let node = self.nodes.remove(index);
+            self.budget.remove(self.budget.weigh(&node.key, &node.value));
+            true
+        } else {
+            false
+        }
+    }
+
+    fn counters(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    fn evicted(&mut self) -> &mut Option<Vec<Entry<T>>> {
+        &mut self.evicted
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> super::Cacheable<T> for SLRUCache<T> {
+    fn put(&mut self, key: Vec<u8>, value: T) -> Result<(), String> {
+        self.insert(key, value, None)
+    }
+
+    fn put_with_ttl(&mut self, key: Vec<u8>, value: T, ttl: Duration) -> Result<(), String> {
+        self.insert(key, value, Some(super::deadline(ttl)?))
+    }
+
+    fn get(&mut self, key: Vec<u8>) -> Option<T> {
+        self.remove_if_expired(&key);
+        if let Some(index) = self.map.get(&key).copied() {
+            self.touch(index);
+            self.stats.hits += 1;
+            Some(self.nodes[index].value.clone())
+        } else {
+            self.stats.misses += 1;
+            None
+        }
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> Result<(), String> {
+        if self.remove(&key) {
+            self.stats.deletes += 1;
+            Ok(())
+        } else {
+            Err("key not found".to_string())
+        }
+    }
+
+    fn ttl(&mut self, key: Vec<u8>) -> Ttl {
+        Store::ttl(self, &key)
+    }
+
+    fn persist(&mut self, key: Vec<u8>) -> bool {
+        Store::persist(self, &key)
+    }
+
+    fn remove_expired(&mut self) -> u32 {
+        Store::remove_expired(self)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats { size: self.map.len() as u64, ..self.stats };
+        self.budget.fill_stats(&mut stats);
+        stats
+    }
+
+    fn weight(&self) -> u64 {
+        self.budget.used()
+    }
+
+    fn max_weight(&self) -> u64 {
+        self.budget.max()
+    }
+
+    // Probation then the protected segment, each front to back, since restored entries are
+    // appended to the back of the segment their frequency puts them in.
+    fn entries(&self) -> Vec<Entry<T>> {
+        let mut entries = Vec::with_capacity(self.map.len());
+        for (list, frequency) in [(&self.probation, 1), (&self.protected, 2)] {
+            for (_, node) in self.nodes.iter(list) {
+                if !node.is_expired() {
+                    let entry = Entry {
+                        key: node.key.clone(),
+                        value: node.value.clone(),
+                        expires_at: node.expires_at,
+                        frequency
+                    };
+                    entries.push(entry);
+                }
+            }
+        }
+        entries
+    }
+
+    fn restore(&mut self, entry: Entry<T>) {
+        self.remove(&entry.key);
+        let weight = self.budget.weigh(&entry.key, &entry.value);
+        if self.budget.check(weight).is_err() {
+            return;
+        }
+        self.make_room(weight);
+        let protected = entry.frequency >= 2;
+        let mut node = Node::with_meta(entry.key.clone(), entry.value, protected);
+        node.expires_at = entry.expires_at;
+        let index = self.nodes.insert(node);
+        if protected {
+            self.protected_weight += self.weight_of(index);
+            self.nodes.push_back(&mut self.protected, index);
+        } else {
+            self.nodes.push_back(&mut self.probation, index);
+        }
+        self.map.insert(entry.key, index);
+        self.budget.add(weight);
+        self.stats.inserts += 1;
+        self.demote();
+    }
+
+    fn keep_evicted(&mut self) {
+        Store::keep_evicted(self)
+    }
+
+    fn take_evicted(&mut self) -> Vec<Entry<T>> {
+        Store::take_evicted(self)
+    }
+
+    fn print_map(&self) {
+        Store::print_map(self)
+    }
+
+    fn print_list(&self) {
+        for (name, list) in [("probation", &self.probation), ("protected", &self.protected)] {
+            println!("{}:", name);
+            for (_, n) in self.nodes.iter(list) {
+                println!("{}", n.value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SLRUCache;
+    use crate::stores::Cacheable;
+
+    fn key(k: &str) -> Vec<u8> {
+        k.as_bytes().to_vec()
+    }
+
+    // Reads each key in turn, putting it on a miss as a cache in front of a slower store would,
+    // and returns what was evicted at each step.
+    fn replay(cache: &mut SLRUCache<String>, keys: &str) -> Vec<String> {
+        cache.keep_evicted();
+        keys.split(' ')
+            .map(|k| {
+                if cache.get(key(k)).is_none() {
+                    cache.put(key(k), k.to_string()).unwrap();
+                }
+                cache.take_evicted().into_iter().map(|entry| entry.value).collect::<Vec<_>>().join(" ")
+            })
+            .collect()
+    }
+
+    fn segments(cache: &SLRUCache<String>) -> (Vec<String>, Vec<String>) {
+        let list = |list| cache.nodes.iter(list).map(|(_, node)| node.value.clone()).collect();
+        (list(&cache.probation), list(&cache.protected))
+    }
+
+    #[test]
+    fn a_second_use_moves_a_key_off_probation() {
+        let mut cache = SLRUCache::new(5);
+        replay(&mut cache, "A B C A");
+        assert_eq!(segments(&cache), (vec!["C".to_string(), "B".to_string()], vec!["A".to_string()]));
+    }
+
+    // Room for 5, so the protected segment holds 4. A fifth key used twice sends the least
+    // recently used protected key back to the front of probation.
+    #[test]
+    fn the_protected_segment_demotes_once_over_its_share() {
+        let mut cache = SLRUCache::new(5);
+        replay(&mut cache, "A B C D E A B C D E");
+        let (probation, protected) = segments(&cache);
+        assert_eq!(probation, ["A"]);
+        assert_eq!(protected, ["E", "D", "C", "B"]);
+    }
+
+    // Shrinking the protected segment demotes at once, least recently used first, and the
+    // demoted keys are the next to go.
+    #[test]
+    fn setting_the_protected_percent_resizes_the_segment() {
+        let mut cache = SLRUCache::new(5);
+        replay(&mut cache, "A B C D E A B C D E");
+        cache.set_protected_percent(40);
+        let (probation, protected) = segments(&cache);
+        assert_eq!(probation, ["C", "B", "A"]);
+        assert_eq!(protected, ["E", "D"]);
+        assert_eq!(replay(&mut cache, "F G"), ["A", "B"]);
+        cache.set_protected_percent(0);
+        assert_eq!(segments(&cache).1, Vec::<String>::new());
+        cache.set_protected_percent(100);
+        assert_eq!(replay(&mut cache, "E D C F G"), ["", "", "", "", ""]);
+        assert_eq!(segments(&cache).1, ["G", "F", "C", "D", "E"]);
+    }
+
+    // Keys seen once only ever push out each other, so a scan leaves the protected keys alone.
+    #[test]
+    fn a_scan_only_evicts_from_probation() {
+        let mut cache = SLRUCache::new(5);
+        replay(&mut cache, "A B C D A B C D");
+        let scan: Vec<String> = (0..20).map(|i| format!("s{}", i)).collect();
+        let evicted = replay(&mut cache, &scan.join(" "));
+        assert!(evicted.iter().all(|k| k.is_empty() || k.starts_with('s')));
+        assert_eq!(segments(&cache).1, ["D", "C", "B", "A"]);
+    }
+}
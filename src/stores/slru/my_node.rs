@@ -0,0 +1,5 @@
+use crate::stores::lru::my_node;
+
+// The meta is whether the entry is in the protected segment, having been used again since it
+// came in, rather than on probation.
+pub type Node<T> = my_node::Node<T, bool>;
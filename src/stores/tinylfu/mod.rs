@@ -0,0 +1,445 @@
+pub mod my_node;
+pub mod sketch;
+use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+use super::{ Entry, HeapSize, Stats, Store, Ttl, Visit };
+use super::budget::{ self, Budget, Weigher };
+use super::slab::{ List, Slab };
+use my_node::{ Node, Region };
+use sketch::Sketch;
+
+// Window TinyLFU, the policy Caffeine uses (Einziger, Friedman and Manes, 2017). New entries go
+// into a small window kept in LRU order. Whatever falls out of the window is a candidate for the
+// main region, and only gets in if it has been used more often lately than the entry the main
+// region would evict for it, going by a count-min sketch. The main region is a segmented LRU:
+// entries start out on probation and move to the protected segment once used again, so a burst
+// of new keys only competes with entries that have not proven themselves yet. Every list is
+// most recently used first.
+pub struct TinyLfuCache<T: std::fmt::Display + std::clone::Clone> {
+    pub map: HashMap<Vec<u8>, u32>,
+    nodes: Slab<Node<T>>,
+    window: List,
+    probation: List,
+    protected: List,
+    // The weight of the entries in the window and in the protected segment.
+    window_weight: u64,
+    protected_weight: u64,
+    sketch: Sketch,
+    budget: Budget<T>,
+    stats: Stats,
+    // Evicted entries waiting to be collected, once keep_evicted has been called.
+    evicted: Option<Vec<Entry<T>>>
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> TinyLfuCache<T> {
+    pub fn new(queue_size: u32) -> TinyLfuCache<T> {
+        TinyLfuCache::with_budget(Budget::entries(queue_size))
+    }
+
+    pub fn with_weigher<W: Weigher<T> + Send + Sync + 'static>(max_weight: u64, weigher: W) -> TinyLfuCache<T> {
+        TinyLfuCache::with_budget(Budget::weighed(max_weight, weigher))
+    }
+
+    fn with_budget(budget: Budget<T>) -> TinyLfuCache<T> {
+        // Without a number of entries to go by, the sketch grows along with the cache.
+        let keys = if budget.counts_entries() { budget.max() as usize } else { 0 };
+        TinyLfuCache {
+            map: HashMap::new(),
+            nodes: Slab::new(),
+            window: List::new(),
+            probation: List::new(),
+            protected: List::new(),
+            window_weight: 0,
+            protected_weight: 0,
+            sketch: Sketch::new(keys),
+            budget,
+            stats: Stats::default(),
+            evicted: None
+        }
+    }
+
+    // The window gets 1% of the budget and the protected segment 80% of the rest, as in Caffeine.
+    fn window_max(&self) -> u64 {
+        (self.budget.max() / 100).max(1)
+    }
+
+    fn protected_max(&self) -> u64 {
+        let main = self.budget.max().saturating_sub(self.window_max());
+        main - main / 5
+    }
+
+    fn weight_of(&self, index: u32) -> u64 {
+        let node = &self.nodes[index];
+        self.budget.weigh(&node.key, &node.value)
+    }
+
+    // Takes an entry off the list it is in.
+    fn leave(&mut self, index: u32) {
+        let weight = self.weight_of(index);
+        let list = match self.nodes[index].region {
+            Region::Window => {
+                self.window_weight -= weight;
+                &mut self.window
+            }
+            Region::Probation => &mut self.probation,
+            Region::Protected => {
+                self.protected_weight -= weight;
+                &mut self.protected
+            }
+        };
+        self.nodes.unlink(list, index);
+    }
+
+    // Puts an entry that is in no list at the front of `region`'s.
+    fn enter(&mut self, index: u32, region: Region) {
+        let weight = self.weight_of(index);
+        let list = match region {
+            Region::Window => {
+                self.window_weight += weight;
+                &mut self.window
+            }
+            Region::Probation => &mut self.probation,
+            Region::Protected => {
+                self.protected_weight += weight;
+                &mut self.protected
+            }
+        };
+        self.nodes.push_front(list, index);
+        self.nodes[index].region = region;
+    }
+
+    // Where an entry goes when it is used: to the front of its list, or from probation into
+    // the protected segment.
+    fn touch(&mut self, index: u32) {
+        match self.nodes[index].region {
+            Region::Window => self.nodes.move_to_front(&mut self.window, index),
+            Region::Probation => {
+                self.leave(index);
+                self.enter(index, Region::Protected);
+                self.demote();
+            }
+            Region::Protected => self.nodes.move_to_front(&mut self.protected, index)
+        }
+    }
+
+    // Puts the least recently used protected entries back on probation while the segment is
+    // over its share.
+    fn demote(&mut self) {
+        while self.protected_weight > self.protected_max() {
+            match self.protected.tail() {
+                Some(index) => {
+                    self.leave(index);
+                    self.enter(index, Region::Probation);
+                }
+                None => break
+            }
+        }
+    }
+
+    // Brings the cache back within its budget after an entry has come in or grown. Entries
+    // past the window's share move on to the main region, where each has to win its place.
+    fn make_room(&mut self) {
+        while self.window_weight > self.window_max() {
+            let candidate = match self.window.tail() {
+                Some(candidate) => candidate,
+                None => break
+            };
+            self.leave(candidate);
+            self.enter(candidate, Region::Probation);
+            self.admit(candidate);
+        }
+        self.evict_until_fits(0);
+    }
+
+    // Evicts entries from the main region for `candidate`, which has just been put on
+    // probation, for as long as it has been used more often lately than each of them. The
+    // first one that has been used as often or more is kept and the candidate is evicted.
+    fn admit(&mut self, candidate: u32) {
+        while !self.budget.fits(0) {
+            let victim = match self.probation.tail().filter(|victim| *victim != candidate).or(self.protected.tail()) {
+                Some(victim) => victim,
+                None => {
+                    self.evict(candidate);
+                    return;
+                }
+            };
+            if self.sketch.frequency(&self.nodes[candidate].key) > self.sketch.frequency(&self.nodes[victim].key) {
+                self.evict(victim);
+            } else {
+                self.evict(candidate);
+                return;
+            }
+        }
+    }
+
+    // Evicts without asking the sketch, least recently used first, probation before the
+    // protected segment before the window.
+    fn evict_until_fits(&mut self, weight: u64) {
+        while !self.budget.fits(weight) {
+            match self.probation.tail().or(self.protected.tail()).or(self.window.tail()) {
+                Some(victim) => self.evict(victim),
+                None => break
+            }
+        }
+    }
+
+    fn evict(&mut self, index: u32) {
+        self.leave(index);
+        let evicted = self.nodes.remove(index);
+        self.map.remove(&evicted.key);
+        self.budget.remove(self.budget.weigh(&evicted.key, &evicted.value));
+        self.stats.evictions += 1;
+        if let Some(kept) = self.evicted.as_mut() {
+            let frequency = self.sketch.frequency(&evicted.key).max(1) as u32;
+            kept.push(Entry { key: evicted.key, value: evicted.value, expires_at: evicted.expires_at, frequency });
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: T, expires_at: Option<Instant>) -> Result<(), String> {
+        let weight = self.budget.weigh(&key, &value);
+        self.budget.check(weight)?;
+        self.remove_if_expired(&key);
+        self.sketch.ensure_capacity(self.map.len() + 1);
+        self.sketch.increment(&key);
+        if let Some(index) = self.map.get(&key).copied() {
+            // Writing a key counts as using it.
+            let region = match self.nodes[index].region {
+                Region::Probation => Region::Protected,
+                region => region
+            };
+            self.leave(index);
+            self.budget.remove(self.weight_of(index));
+            let node = &mut self.nodes[index];
+            node.value = value;
+            node.expires_at = expires_at;
+            self.budget.add(weight);
+            self.enter(index, region);
+            self.demote();
+            self.stats.updates += 1;
+        } else {
+            let mut node = Node::new(key.clone(), value, Region::Window);
+            node.expires_at = expires_at;
+            let index = self.nodes.insert(node);
+            self.map.insert(key, index);
+            self.budget.add(weight);
+            self.enter(index, Region::Window);
+            self.stats.inserts += 1;
+        }
+        self.make_room();
+        Ok(())
+    }
+
+}
+
+impl<T: std::fmt::Display + std::clone::Clone + HeapSize> TinyLfuCache<T> {
+    // The sketch is not counted in the budget.
+    pub fn with_max_bytes(max_bytes: u64) -> TinyLfuCache<T> {
+        TinyLfuCache::with_budget(Budget::bytes(max_bytes, budget::entry_bytes::<T, Node<T>>))
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> Store<T> for TinyLfuCache<T> {
+    fn expiry(&mut self, key: &[u8]) -> Option<&mut Option<Instant>> {
+        let nodes = &mut self.nodes;
+        self.map.get(key).map(move |index| &mut nodes[*index].expires_at)
+    }
+
+    fn each(&self, f: &mut Visit<T>) {
+        for index in self.map.values() {
+            let node = &self.nodes[*index];
+            f(&node.key, &node.value, node.expires_at);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        if let Some(index) = self.map.remove(key) {
+            self.leave(index);
+            let node = self.nodes.remove(index);
+            self.budget.remove(self.budget.weigh(&node.key, &node.value));
+            true
+        } else {
+            false
+        }
+    }
+
+    fn counters(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    fn evicted(&mut self) -> &mut Option<Vec<Entry<T>>> {
+        &mut self.evicted
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> super::Cacheable<T> for TinyLfuCache<T> {
+    fn put(&mut self, key: Vec<u8>, value: T) -> Result<(), String> {
+        self.insert(key, value, None)
+    }
+
+    fn put_with_ttl(&mut self, key: Vec<u8>, value: T, ttl: Duration) -> Result<(), String> {
+        self.insert(key, value, Some(super::deadline(ttl)?))
+    }
+
+    // Misses are not counted by the sketch, since a miss is usually followed by a write for the
+    // same key, which is.
+    fn get(&mut self, key: Vec<u8>) -> Option<T> {
+        self.remove_if_expired(&key);
+        if let Some(index) = self.map.get(&key).copied() {
+            self.sketch.increment(&key);
+            self.touch(index);
+            self.stats.hits += 1;
+            Some(self.nodes[index].value.clone())
+        } else {
+            self.stats.misses += 1;
+            None
+        }
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> Result<(), String> {
+        if self.remove(&key) {
+            self.stats.deletes += 1;
+            Ok(())
+        } else {
+            Err("key not found".to_string())
+        }
+    }
+
+    fn ttl(&mut self, key: Vec<u8>) -> Ttl {
+        Store::ttl(self, &key)
+    }
+
+    fn persist(&mut self, key: Vec<u8>) -> bool {
+        Store::persist(self, &key)
+    }
+
+    fn remove_expired(&mut self) -> u32 {
+        Store::remove_expired(self)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats { size: self.map.len() as u64, ..self.stats };
+        self.budget.fill_stats(&mut stats);
+        stats
+    }
+
+    fn weight(&self) -> u64 {
+        self.budget.used()
+    }
+
+    fn max_weight(&self) -> u64 {
+        self.budget.max()
+    }
+
+    // The window, the protected segment and then probation, each front to back. Restored
+    // entries are all appended to the back of probation, so the ones evicted first stay the
+    // same, and the window and protected segment fill up again as keys are used. Frequencies
+    // are the sketch's estimates.
+    fn entries(&self) -> Vec<Entry<T>> {
+        let mut entries = Vec::with_capacity(self.map.len());
+        for list in [&self.window, &self.protected, &self.probation] {
+            for (_, node) in self.nodes.iter(list) {
+                if !node.is_expired() {
+                    let entry = Entry {
+                        key: node.key.clone(),
+                        value: node.value.clone(),
+                        expires_at: node.expires_at,
+                        frequency: self.sketch.frequency(&node.key).max(1) as u32
+                    };
+                    entries.push(entry);
+                }
+            }
+        }
+        entries
+    }
+
+    fn restore(&mut self, entry: Entry<T>) {
+        self.remove(&entry.key);
+        let weight = self.budget.weigh(&entry.key, &entry.value);
+        if self.budget.check(weight).is_err() {
+            return;
+        }
+        self.sketch.ensure_capacity(self.map.len() + 1);
+        self.sketch.raise_to(&entry.key, entry.frequency);
+        self.evict_until_fits(weight);
+        let mut node = Node::new(entry.key.clone(), entry.value, Region::Probation);
+        node.expires_at = entry.expires_at;
+        let index = self.nodes.insert(node);
+        self.nodes.push_back(&mut self.probation, index);
+        self.map.insert(entry.key, index);
+        self.budget.add(weight);
+        self.stats.inserts += 1;
+    }
+
+    fn keep_evicted(&mut self) {
+        Store::keep_evicted(self)
+    }
+
+    fn take_evicted(&mut self) -> Vec<Entry<T>> {
+        Store::take_evicted(self)
+    }
+
+    fn print_map(&self) {
+        Store::print_map(self)
+    }
+
+    fn print_list(&self) {
+        for (name, list) in [("window", &self.window), ("probation", &self.probation), ("protected", &self.protected)] {
+            println!("{}:", name);
+            for (_, n) in self.nodes.iter(list) {
+                println!("{}", n.value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TinyLfuCache;
+    use crate::stores::Cacheable;
+
+    fn key(k: &str) -> Vec<u8> {
+        k.as_bytes().to_vec()
+    }
+
+    // Puts each key in turn and returns what was evicted at each step.
+    fn put_all(cache: &mut TinyLfuCache<String>, keys: &str) -> Vec<String> {
+        cache.keep_evicted();
+        keys.split(' ')
+            .map(|k| {
+                cache.put(key(k), k.to_string()).unwrap();
+                cache.take_evicted().into_iter().map(|entry| entry.value).collect::<Vec<_>>().join(" ")
+            })
+            .collect()
+    }
+
+    // Room for 10, so the window holds 1. Each key seen once is turned away as it leaves the
+    // window, rather than any of the keys read three times.
+    #[test]
+    fn a_key_seen_once_does_not_displace_a_hot_key() {
+        let mut cache = TinyLfuCache::new(10);
+        put_all(&mut cache, "A B C D E F G H I");
+        for k in ["A", "B", "C", "D", "E", "F", "G", "H", "I"].iter().cycle().take(27) {
+            assert_eq!(cache.get(key(k)), Some(k.to_string()));
+        }
+        let evicted = put_all(&mut cache, "W X Y Z");
+        assert_eq!(evicted, ["", "W", "X", "Y"]);
+        for k in ["A", "B", "C", "D", "E", "F", "G", "H", "I", "Z"] {
+            assert_eq!(cache.get(key(k)), Some(k.to_string()));
+        }
+    }
+
+    // Without a number of entries to size it by, the sketch grows as the cache fills. It used
+    // to start its counts over when it did, after which A looked no more used than a key seen
+    // once and was evicted for one.
+    #[test]
+    fn counts_survive_the_sketch_growing() {
+        let mut cache = TinyLfuCache::with_weigher(20, |_: &[u8], _: &String| 1);
+        cache.put(key("A"), "A".to_string()).unwrap();
+        for _ in 0..3 {
+            cache.get(key("A"));
+        }
+        let evicted = put_all(&mut cache, "B C D E F G H I J K L M N O P Q R S T U");
+        assert_eq!(evicted.last().map(String::as_str), Some("T"));
+        assert_eq!(cache.get(key("A")), Some("A".to_string()));
+    }
+}
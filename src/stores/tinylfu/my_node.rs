@@ -0,0 +1,29 @@
+use std::time::Instant;
+
+// Which of the cache's lists an entry is in.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Window,
+    Probation,
+    Protected
+}
+
+pub struct Node<T: std::fmt::Display + std::clone::Clone> {
+    pub key: Vec<u8>,
+    pub value: T,
+    pub expires_at: Option<Instant>,
+    pub region: Region
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> Node<T> {
+    pub fn new(k: Vec<u8>, v: T, region: Region) -> Node<T> {
+        Node { key: k, value: v, expires_at: None, region }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(t) => t <= Instant::now(),
+            None => false
+        }
+    }
+}
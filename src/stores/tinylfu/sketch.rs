@@ -0,0 +1,122 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+
+const ROWS: usize = 4;
+// Counters stop at 15, as in 4 bit counters. Telling apart keys used more often than that
+// doesn't help decide which of two to keep.
+const MAX_COUNT: u8 = 15;
+// Each row has this many counters for every key the sketch is for, so few keys share one.
+const COUNTERS_PER_KEY: usize = 4;
+const MIN_WIDTH: usize = 16;
+const MAX_WIDTH: usize = 1 << 24;
+// Counts are halved once this many uses per counter in a row have been recorded.
+const SAMPLE_FACTOR: usize = 10;
+
+fn width_for(keys: usize) -> usize {
+    keys.saturating_mul(COUNTERS_PER_KEY).clamp(MIN_WIDTH, MAX_WIDTH).next_power_of_two()
+}
+
+// A count-min sketch estimating how often each key has been used lately. Every key has one
+// counter in each row, picked by hashing it, and its estimate is the smallest of them, so
+// other keys sharing a counter can only make it too high. Halving every counter after a set
+// number of uses lets keys that were popular once but aren't any more be forgotten.
+pub struct Sketch {
+    table: Vec<u8>,
+    width: usize,
+    additions: usize
+}
+
+impl Sketch {
+    // A sketch for keeping track of about `keys` keys at a time.
+    pub fn new(keys: usize) -> Sketch {
+        let width = width_for(keys);
+        Sketch { table: vec![0; ROWS * width], width, additions: 0 }
+    }
+
+    // Makes the sketch wide enough for `keys` keys. Widths are powers of two, so growing
+    // splits each counter into several whose slots only differ in the new high bits of the
+    // hash. They all start at the old count, which leaves every key's estimate as it was.
+    pub fn ensure_capacity(&mut self, keys: usize) {
+        let width = width_for(keys);
+        if width <= self.width {
+            return;
+        }
+        let mut table = Vec::with_capacity(ROWS * width);
+        for row in self.table.chunks(self.width) {
+            for _ in 0..width / self.width {
+                table.extend_from_slice(row);
+            }
+        }
+        self.table = table;
+        self.width = width;
+    }
+
+    fn slots(&self, key: &[u8]) -> [usize; ROWS] {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash as usize, (hash >> 32) as usize | 1);
+        let mut slots = [0; ROWS];
+        for (row, slot) in slots.iter_mut().enumerate() {
+            *slot = row * self.width + (h1.wrapping_add(row.wrapping_mul(h2)) & (self.width - 1));
+        }
+        slots
+    }
+
+    pub fn frequency(&self, key: &[u8]) -> u8 {
+        self.slots(key).iter().map(|slot| self.table[*slot]).min().unwrap_or(0)
+    }
+
+    pub fn increment(&mut self, key: &[u8]) {
+        let mut added = false;
+        for slot in self.slots(key) {
+            if self.table[slot] < MAX_COUNT {
+                self.table[slot] += 1;
+                added = true;
+            }
+        }
+        if added {
+            self.additions += 1;
+            if self.additions >= SAMPLE_FACTOR * self.width {
+                self.age();
+            }
+        }
+    }
+
+    // Counts uses of a key until its estimate is at least `frequency`. The sketch may still
+    // remember some of them, from before the key left the cache.
+    pub fn raise_to(&mut self, key: &[u8], frequency: u32) {
+        let frequency = frequency.min(MAX_COUNT as u32) as u8;
+        while self.frequency(key) < frequency {
+            self.increment(key);
+        }
+    }
+
+    fn age(&mut self) {
+        for count in &mut self.table {
+            *count >>= 1;
+        }
+        self.additions /= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sketch;
+
+    #[test]
+    fn growing_keeps_every_estimate() {
+        let mut sketch = Sketch::new(0);
+        let keys: Vec<Vec<u8>> = (0..8).map(|i| format!("key:{}", i).into_bytes()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            for _ in 0..i {
+                sketch.increment(key);
+            }
+        }
+        let before: Vec<u8> = keys.iter().map(|key| sketch.frequency(key)).collect();
+        sketch.ensure_capacity(1000);
+        let after: Vec<u8> = keys.iter().map(|key| sketch.frequency(key)).collect();
+        assert_eq!(after, before);
+        assert_eq!(after[7], 7);
+    }
+}
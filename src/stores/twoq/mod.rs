@@ -0,0 +1,405 @@
+pub mod my_node;
+use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+use super::{ Entry, HeapSize, Stats, Store, Ttl, Visit };
+use super::budget::{ self, Budget, Weigher };
+use super::slab::{ List, Slab };
+use my_node::{ Node, Queue };
+
+// The share of the budget A1in gets, and the number of evicted keys A1out remembers as a share
+// of the entries the cache holds. Kin and Kout in the paper, set to the values it recommends.
+const IN_PERCENT: u64 = 25;
+const OUT_PERCENT: u64 = 50;
+
+// 2Q (Johnson and Shasha, 1994). New entries go into A1in, a FIFO queue that a hit leaves alone,
+// so a key used a few times in quick succession isn't taken for a popular one. Keys evicted from
+// A1in are remembered without their values in A1out, and only a key that comes back while it is
+// still there goes into Am, the LRU list holding the rest of the cache. Entries are evicted from
+// the back of A1in while it is over its share of the budget, and from the back of Am otherwise,
+// so a scan never pushes out anything in Am. Both lists are newest first.
+pub struct TwoQueueCache<T: std::fmt::Display + std::clone::Clone> {
+    pub map: HashMap<Vec<u8>, u32>,
+    nodes: Slab<Node<T>>,
+    a1in: List,
+    am: List,
+    // The weight of the entries in A1in.
+    a1in_weight: u64,
+    // A1out: the keys evicted from A1in that are still remembered, and the list they are in.
+    ghosts: HashMap<Vec<u8>, u32>,
+    ghost_keys: Slab<Vec<u8>>,
+    a1out: List,
+    budget: Budget<T>,
+    stats: Stats,
+    // Evicted entries waiting to be collected, once keep_evicted has been called.
+    evicted: Option<Vec<Entry<T>>>
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> TwoQueueCache<T> {
+    pub fn new(queue_size: u32) -> TwoQueueCache<T> {
+        TwoQueueCache::with_budget(Budget::entries(queue_size))
+    }
+
+    pub fn with_weigher<W: Weigher<T> + Send + Sync + 'static>(max_weight: u64, weigher: W) -> TwoQueueCache<T> {
+        TwoQueueCache::with_budget(Budget::weighed(max_weight, weigher))
+    }
+
+    fn with_budget(budget: Budget<T>) -> TwoQueueCache<T> {
+        TwoQueueCache {
+            map: HashMap::new(),
+            nodes: Slab::new(),
+            a1in: List::new(),
+            am: List::new(),
+            a1in_weight: 0,
+            ghosts: HashMap::new(),
+            ghost_keys: Slab::new(),
+            a1out: List::new(),
+            budget,
+            stats: Stats::default(),
+            evicted: None
+        }
+    }
+
+    fn a1in_max(&self) -> u64 {
+        // Divided first so large byte budgets don't overflow.
+        let max = self.budget.max();
+        (max / 100 * IN_PERCENT + max % 100 * IN_PERCENT / 100).max(1)
+    }
+
+    // How many keys A1out remembers. Under a byte budget or a Weigher the cache holds no fixed
+    // number of entries, so it goes by how many it holds at the moment.
+    fn a1out_max(&self) -> u32 {
+        let capacity = if self.budget.counts_entries() { self.budget.max() } else { self.map.len() as u64 };
+        (capacity * OUT_PERCENT / 100).max(1) as u32
+    }
+
+    fn weight_of(&self, index: u32) -> u64 {
+        let node = &self.nodes[index];
+        self.budget.weigh(&node.key, &node.value)
+    }
+
+    fn unlink(&mut self, index: u32) {
+        if self.nodes[index].meta == Queue::Am {
+            self.nodes.unlink(&mut self.am, index);
+        } else {
+            self.a1in_weight -= self.weight_of(index);
+            self.nodes.unlink(&mut self.a1in, index);
+        }
+    }
+
+    // Puts an entry that is in neither list at the front of the one it belongs in.
+    fn link(&mut self, index: u32) {
+        if self.nodes[index].meta == Queue::Am {
+            self.nodes.push_front(&mut self.am, index);
+        } else {
+            self.a1in_weight += self.weight_of(index);
+            self.nodes.push_front(&mut self.a1in, index);
+        }
+    }
+
+    // reclaimfor in the paper: evicts from the back of A1in while it is over its share, or of Am
+    // otherwise, until an entry weighing `weight` fits.
+    fn make_room(&mut self, weight: u64) {
+        while !self.budget.fits(weight) {
+            let from_a1in = self.a1in_weight > self.a1in_max() || self.am.is_empty();
+            let victim = if from_a1in { self.a1in.tail() } else { self.am.tail() };
+            match victim.or(self.am.tail()) {
+                Some(index) => self.evict(index),
+                None => break
+            }
+        }
+    }
+
+    // Drops an entry, remembering its key in A1out if it came from A1in.
+    fn evict(&mut self, index: u32) {
+        self.unlink(index);
+        let evicted = self.nodes.remove(index);
+        self.map.remove(&evicted.key);
+        self.budget.remove(self.budget.weigh(&evicted.key, &evicted.value));
+        self.stats.evictions += 1;
+        if evicted.meta == Queue::A1in {
+            let ghost = self.ghost_keys.insert(evicted.key.clone());
+            self.ghost_keys.push_front(&mut self.a1out, ghost);
+            self.ghosts.insert(evicted.key.clone(), ghost);
+            while self.a1out.len() > self.a1out_max() {
+                match self.a1out.tail() {
+                    Some(oldest) => self.forget_ghost(oldest),
+                    None => break
+                }
+            }
+        }
+        if let Some(kept) = self.evicted.as_mut() {
+            let frequency = if evicted.meta == Queue::Am { 2 } else { 1 };
+            kept.push(Entry { key: evicted.key, value: evicted.value, expires_at: evicted.expires_at, frequency });
+        }
+    }
+
+    fn forget_ghost(&mut self, index: u32) {
+        self.ghost_keys.unlink(&mut self.a1out, index);
+        let key = self.ghost_keys.remove(index);
+        self.ghosts.remove(&key);
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: T, expires_at: Option<Instant>) -> Result<(), String> {
+        let weight = self.budget.weigh(&key, &value);
+        self.budget.check(weight)?;
+        self.remove_if_expired(&key);
+        if let Some(index) = self.map.get(&key).copied() {
+            // An entry in Am moves to the front as on a hit. One in A1in starts over at the
+            // front of A1in, as it can't be put back where it was once room has been made.
+            self.unlink(index);
+            self.budget.remove(self.weight_of(index));
+            self.make_room(weight);
+            let node = &mut self.nodes[index];
+            node.value = value;
+            node.expires_at = expires_at;
+            self.link(index);
+            self.budget.add(weight);
+            self.stats.updates += 1;
+            return Ok(());
+        }
+        // A key still in A1out is taken out before room is made, so evicting can't push it out.
+        let queue = match self.ghosts.get(&key).copied() {
+            Some(ghost) => {
+                self.forget_ghost(ghost);
+                Queue::Am
+            }
+            None => Queue::A1in
+        };
+        self.make_room(weight);
+        let mut node = Node::with_meta(key.clone(), value, queue);
+        node.expires_at = expires_at;
+        let index = self.nodes.insert(node);
+        self.link(index);
+        self.map.insert(key, index);
+        self.budget.add(weight);
+        self.stats.inserts += 1;
+        Ok(())
+    }
+
+}
+
+impl<T: std::fmt::Display + std::clone::Clone + HeapSize> TwoQueueCache<T> {
+    // The keys in A1out are not counted in the budget.
+    pub fn with_max_bytes(max_bytes: u64) -> TwoQueueCache<T> {
+        TwoQueueCache::with_budget(Budget::bytes(max_bytes, budget::entry_bytes::<T, Node<T>>))
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> Store<T> for TwoQueueCache<T> {
+    fn expiry(&mut self, key: &[u8]) -> Option<&mut Option<Instant>> {
+        let nodes = &mut self.nodes;
+        self.map.get(key).map(move |index| &mut nodes[*index].expires_at)
+    }
+
+    fn each(&self, f: &mut Visit<T>) {
+        for index in self.map.values() {
+            let node = &self.nodes[*index];
+            f(&node.key, &node.value, node.expires_at);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        if let Some(index) = self.map.remove(key) {
+            self.unlink(index);
+            let node = self.nodes.remove(index);
+            self.budget.remove(self.budget.weigh(&node.key, &node.value));
+            true
+        } else {
+            false
+        }
+    }
+
+    fn counters(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    fn evicted(&mut self) -> &mut Option<Vec<Entry<T>>> {
+        &mut self.evicted
+    }
+}
+
+impl<T: std::fmt::Display + std::clone::Clone> super::Cacheable<T> for TwoQueueCache<T> {
+    fn put(&mut self, key: Vec<u8>, value: T) -> Result<(), String> {
+        self.insert(key, value, None)
+    }
+
+    fn put_with_ttl(&mut self, key: Vec<u8>, value: T, ttl: Duration) -> Result<(), String> {
+        self.insert(key, value, Some(super::deadline(ttl)?))
+    }
+
+    fn get(&mut self, key: Vec<u8>) -> Option<T> {
+        self.remove_if_expired(&key);
+        if let Some(index) = self.map.get(&key).copied() {
+            if self.nodes[index].meta == Queue::Am {
+                self.nodes.move_to_front(&mut self.am, index);
+            }
+            self.stats.hits += 1;
+            Some(self.nodes[index].value.clone())
+        } else {
+            self.stats.misses += 1;
+            None
+        }
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> Result<(), String> {
+        if self.remove(&key) {
+            self.stats.deletes += 1;
+            Ok(())
+        } else {
+            Err("key not found".to_string())
+        }
+    }
+
+    fn ttl(&mut self, key: Vec<u8>) -> Ttl {
+        Store::ttl(self, &key)
+    }
+
+    fn persist(&mut self, key: Vec<u8>) -> bool {
+        Store::persist(self, &key)
+    }
+
+    fn remove_expired(&mut self) -> u32 {
+        Store::remove_expired(self)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats { size: self.map.len() as u64, ..self.stats };
+        self.budget.fill_stats(&mut stats);
+        stats
+    }
+
+    fn weight(&self) -> u64 {
+        self.budget.used()
+    }
+
+    fn max_weight(&self) -> u64 {
+        self.budget.max()
+    }
+
+    // A1in then Am, each front to back, since restored entries are appended to the back of the
+    // list their frequency puts them in. A1out is not kept.
+    fn entries(&self) -> Vec<Entry<T>> {
+        let mut entries = Vec::with_capacity(self.map.len());
+        for (list, frequency) in [(&self.a1in, 1), (&self.am, 2)] {
+            for (_, node) in self.nodes.iter(list) {
+                if !node.is_expired() {
+                    let entry = Entry {
+                        key: node.key.clone(),
+                        value: node.value.clone(),
+                        expires_at: node.expires_at,
+                        frequency
+                    };
+                    entries.push(entry);
+                }
+            }
+        }
+        entries
+    }
+
+    fn restore(&mut self, entry: Entry<T>) {
+        self.remove(&entry.key);
+        let weight = self.budget.weigh(&entry.key, &entry.value);
+        if self.budget.check(weight).is_err() {
+            return;
+        }
+        // A key still in A1out was seen again, just as on a put.
+        let ghost = self.ghosts.get(&entry.key).copied();
+        if let Some(ghost) = ghost {
+            self.forget_ghost(ghost);
+        }
+        self.make_room(weight);
+        let queue = if entry.frequency >= 2 || ghost.is_some() { Queue::Am } else { Queue::A1in };
+        let mut node = Node::with_meta(entry.key.clone(), entry.value, queue);
+        node.expires_at = entry.expires_at;
+        let index = self.nodes.insert(node);
+        if ghost.is_some() {
+            self.link(index);
+        } else if queue == Queue::Am {
+            self.nodes.push_back(&mut self.am, index);
+        } else {
+            self.a1in_weight += weight;
+            self.nodes.push_back(&mut self.a1in, index);
+        }
+        self.map.insert(entry.key, index);
+        self.budget.add(weight);
+        self.stats.inserts += 1;
+    }
+
+    fn keep_evicted(&mut self) {
+        Store::keep_evicted(self)
+    }
+
+    fn take_evicted(&mut self) -> Vec<Entry<T>> {
+        Store::take_evicted(self)
+    }
+
+    fn print_map(&self) {
+        Store::print_map(self)
+    }
+
+    fn print_list(&self) {
+        for (name, list) in [("a1in", &self.a1in), ("am", &self.am)] {
+            println!("{}:", name);
+            for (_, n) in self.nodes.iter(list) {
+                println!("{}", n.value);
+            }
+        }
+        println!("a1out: {} keys", self.a1out.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TwoQueueCache;
+    use crate::stores::{ Cacheable, Entry };
+
+    fn key(k: &str) -> Vec<u8> {
+        k.as_bytes().to_vec()
+    }
+
+    // Reads each key in turn, putting it on a miss as a cache in front of a slower store would,
+    // and returns what was evicted at each step.
+    fn replay(cache: &mut TwoQueueCache<String>, keys: &str) -> Vec<String> {
+        cache.keep_evicted();
+        keys.split(' ')
+            .map(|k| {
+                if cache.get(key(k)).is_none() {
+                    cache.put(key(k), k.to_string()).unwrap();
+                }
+                cache.take_evicted().into_iter().map(|entry| entry.value).collect::<Vec<_>>().join(" ")
+            })
+            .collect()
+    }
+
+    // Snapshots mark entries in Am with a frequency of 2 and those in A1in with 1.
+    fn frequency_of(cache: &TwoQueueCache<String>, k: &str) -> Option<u32> {
+        cache.entries().into_iter().find(|entry| entry.key == key(k)).map(|entry| entry.frequency)
+    }
+
+    // Full 2Q from the paper with room for 4 entries, so Kin is 1 and Kout is 2. Every key that
+    // misses goes through A1in and on to A1out. A comes back while it is still in A1out and is
+    // kept in Am after that, while the keys seen once go on being evicted around it.
+    #[test]
+    fn replays_the_2q_eviction_sequence() {
+        let mut cache = TwoQueueCache::new(4);
+        let evicted = replay(&mut cache, "A B C D E F A G A H I");
+        assert_eq!(evicted, ["", "", "", "", "A", "B", "C", "D", "", "E", "F"]);
+        assert_eq!(frequency_of(&cache, "A"), Some(2));
+        for k in ["G", "H", "I"] {
+            assert_eq!(frequency_of(&cache, k), Some(1));
+        }
+        assert_eq!(cache.entries().len(), 4);
+    }
+
+    #[test]
+    fn restoring_a_key_in_a1out_promotes_it_to_am() {
+        let mut cache = TwoQueueCache::new(4);
+        for k in ["A", "B", "C", "D", "E"] {
+            cache.put(key(k), k.to_string()).unwrap();
+        }
+        assert_eq!(cache.get(key("A")), None);
+        let entry = Entry { key: key("A"), value: "A".to_string(), expires_at: None, frequency: 1 };
+        cache.restore(entry);
+        assert_eq!(frequency_of(&cache, "A"), Some(2));
+    }
+}
@@ -0,0 +1,12 @@
+use crate::stores::lru::my_node;
+
+// Which of the two lists of resident entries a node is in.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Queue {
+    // Seen once, or not again since it came in.
+    A1in,
+    // Came back after it was evicted from A1in.
+    Am
+}
+
+pub type Node<T> = my_node::Node<T, Queue>;